@@ -0,0 +1,28 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+fn main() {
+    // Node.js addons are linked against symbols of the node binary, resolved when loaded
+    #[cfg(feature = "node")]
+    napi_build::setup();
+
+    // The gRPC service, its messages being the ones of src/proto.rs
+    #[cfg(feature = "grpc")]
+    tonic_build::configure()
+        .build_client(false)
+        .extern_path(".geoip.Location", "crate::proto::Location")
+        .extern_path(".geoip.Locations", "crate::proto::Locations")
+        .compile(&["proto/geoip.proto"], &["proto"])
+        .unwrap();
+}
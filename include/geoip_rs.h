@@ -0,0 +1,38 @@
+#ifndef GEOIP_RS_H
+#define GEOIP_RS_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Opens the database at `path`, replacing the one opened before, if any. Returns 0, or -1 when
+// the database can't be opened.
+//
+// # Safety
+//
+// `path` must be a NUL terminated string.
+int geoip_open(const char *path);
+
+// Resolves `ip` with the names in `lang`, returning the JSON body `/{ip}` would return: IP
+// addresses without a record have their `ip_address` and the `error` telling why. Returns NULL
+// when no database is open. The string must be released with `geoip_free_string`.
+//
+// # Safety
+//
+// `ip` and `lang` must be NUL terminated strings.
+char *geoip_lookup(const char *ip, const char *lang);
+
+// Releases a string returned by `geoip_lookup`.
+//
+// # Safety
+//
+// `json` must be NULL or returned by `geoip_lookup`, and not released yet.
+void geoip_free_string(char *json);
+
+// Closes the database opened by `geoip_open`. Lookups return NULL afterwards.
+void geoip_close(void);
+
+#endif /* GEOIP_RS_H */
@@ -0,0 +1,247 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Access log of the HTTP API, in the combined log format, so `geoip-rs enrich-log` can read it.
+//! It's written to the file set in GEOIP_RS_ACCESS_LOG, or to stdout when set to `-`. The file is
+//! rotated once it's past GEOIP_RS_ACCESS_LOG_MAX_SIZE bytes, or when the hour or the day changes
+//! with GEOIP_RS_ACCESS_LOG_ROTATE set to `hourly` or `daily`, keeping the last
+//! GEOIP_RS_ACCESS_LOG_KEEP rotated files as `<file>.1`, `<file>.2`, ... Without rotation,
+//! logrotate can move the file away and send SIGUSR1 to have it reopened.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::HttpDate;
+
+const DEFAULT_KEEP: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Period {
+    Hourly,
+    Daily,
+}
+
+impl Period {
+    /// Which hour or day since the epoch `time` falls in.
+    fn of(self, time: SystemTime) -> u64 {
+        let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        match self {
+            Period::Hourly => secs / 3600,
+            Period::Daily => secs / 86400,
+        }
+    }
+}
+
+pub struct AccessLog {
+    output: Mutex<Output>,
+}
+
+enum Output {
+    Stdout,
+    File(LogFile),
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: Option<u64>,
+    period: Option<Period>,
+    /// The hour or day the file was opened in, with `period`.
+    opened_in: u64,
+    keep: usize,
+}
+
+impl AccessLog {
+    /// The access log set in the env, if any.
+    pub fn from_env() -> Result<Option<AccessLog>, String> {
+        let path = match env::var("GEOIP_RS_ACCESS_LOG") {
+            Ok(path) => path,
+            Err(_) => return Ok(None),
+        };
+        if path == "-" {
+            return Ok(Some(AccessLog {
+                output: Mutex::new(Output::Stdout),
+            }));
+        }
+
+        let max_size = match env::var("GEOIP_RS_ACCESS_LOG_MAX_SIZE") {
+            Ok(max_size) => Some(
+                max_size
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|max_size| *max_size > 0)
+                    .ok_or_else(|| format!("GEOIP_RS_ACCESS_LOG_MAX_SIZE must be a positive number, got {}", max_size))?,
+            ),
+            Err(_) => None,
+        };
+        let period = match env::var("GEOIP_RS_ACCESS_LOG_ROTATE").as_deref() {
+            Ok("hourly") => Some(Period::Hourly),
+            Ok("daily") => Some(Period::Daily),
+            Ok(period) => {
+                return Err(format!("GEOIP_RS_ACCESS_LOG_ROTATE must be hourly or daily, got {}", period))
+            }
+            Err(_) => None,
+        };
+        let keep = match env::var("GEOIP_RS_ACCESS_LOG_KEEP") {
+            Ok(keep) => keep
+                .parse::<usize>()
+                .map_err(|_| format!("GEOIP_RS_ACCESS_LOG_KEEP must be a number, got {}", keep))?,
+            Err(_) => DEFAULT_KEEP,
+        };
+
+        let file = LogFile::open(PathBuf::from(path), max_size, period, keep)?;
+        Ok(Some(AccessLog {
+            output: Mutex::new(Output::File(file)),
+        }))
+    }
+
+    /// Logs a request, e.g. `216.58.205.132 - - [21/Oct/2020:07:28:00 +0000] "GET /?ip=46.51.179.90
+    /// HTTP/1.1" 200 341 "-" "curl/7.68.0"`.
+    pub fn log(&self, entry: &Entry) {
+        let line = entry.to_string();
+        let written = match &mut *self.output.lock().unwrap() {
+            Output::Stdout => io::stdout().write_all(line.as_bytes()),
+            Output::File(file) => file.write(line.as_bytes()),
+        };
+        if let Err(err) = written {
+            eprintln!("Can not write the access log: {}", err);
+        }
+    }
+
+    /// Reopens the file, after logrotate moved it away.
+    pub fn reopen(&self) {
+        if let Output::File(file) = &mut *self.output.lock().unwrap() {
+            if let Err(err) = file.reopen() {
+                eprintln!("{}", err);
+            }
+        }
+    }
+}
+
+/// What's logged of a request.
+pub struct Entry {
+    pub client: String,
+    pub time: SystemTime,
+    /// The request line, e.g. `GET /?ip=46.51.179.90 HTTP/1.1`.
+    pub request: String,
+    pub status: u16,
+    /// Body size, unknown for streamed responses.
+    pub bytes: Option<u64>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"",
+            self.client,
+            clf_time(self.time),
+            escape(&self.request),
+            self.status,
+            self.bytes.map_or_else(|| String::from("-"), |bytes| bytes.to_string()),
+            escape(self.referer.as_deref().unwrap_or("-")),
+            escape(self.user_agent.as_deref().unwrap_or("-")),
+        )
+    }
+}
+
+impl LogFile {
+    fn open(path: PathBuf, max_size: Option<u64>, period: Option<Period>, keep: usize) -> Result<LogFile, String> {
+        let (file, size) = append(&path)?;
+        Ok(LogFile {
+            path,
+            file,
+            size,
+            max_size,
+            period,
+            opened_in: period.map_or(0, |period| period.of(SystemTime::now())),
+            keep,
+        })
+    }
+
+    fn write(&mut self, line: &[u8]) -> io::Result<()> {
+        let size = self.size + line.len() as u64;
+        let past_size = self.max_size.map_or(false, |max_size| self.size > 0 && size > max_size);
+        let past_period = self.period.map_or(false, |period| period.of(SystemTime::now()) != self.opened_in);
+        if past_size || past_period {
+            if let Err(err) = self.rotate() {
+                eprintln!("{}", err);
+            }
+        }
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shifts `<file>.1` to `<file>.2` and so on, dropping the ones past `keep`, then moves the
+    /// file to `<file>.1` and starts a new one.
+    fn rotate(&mut self) -> Result<(), String> {
+        let rotated = |n: usize| PathBuf::from(format!("{}.{}", self.path.display(), n));
+
+        fs::remove_file(rotated(self.keep.max(1))).ok();
+        for n in (1..self.keep).rev() {
+            fs::rename(rotated(n), rotated(n + 1)).ok();
+        }
+        if self.keep > 0 {
+            fs::rename(&self.path, rotated(1))
+                .map_err(|err| format!("Can not rotate {}: {}", self.path.display(), err))?;
+        } else {
+            fs::remove_file(&self.path).map_err(|err| format!("Can not rotate {}: {}", self.path.display(), err))?;
+        }
+
+        self.reopen()
+    }
+
+    fn reopen(&mut self) -> Result<(), String> {
+        let (file, size) = append(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened_in = self.period.map_or(0, |period| period.of(SystemTime::now()));
+        Ok(())
+    }
+}
+
+fn append(path: &Path) -> Result<(File, u64), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|err| format!("Can not open {}: {}", path.display(), err))?;
+    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or(0);
+    Ok((file, size))
+}
+
+/// `time` as in the common log format, e.g. `21/Oct/2020:07:28:00 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    // HTTP dates are e.g. `Wed, 21 Oct 2020 07:28:00 GMT`
+    let date = HttpDate::from(time).to_string();
+    let parts: Vec<&str> = date.split_whitespace().collect();
+    match parts.as_slice() {
+        [_, day, month, year, time, _] => format!("{}/{}/{}:{} +0000", day, month, year, time),
+        _ => date,
+    }
+}
+
+/// Escapes the quotes and the control characters of a quoted field.
+fn escape(field: &str) -> String {
+    field.escape_default().to_string()
+}
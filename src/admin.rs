@@ -0,0 +1,153 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Admin endpoints, for operators: they're served only when an admin token is set, either
+//! GEOIP_RS_ADMIN_TOKEN or the named tokens of the JSON file set in GEOIP_RS_ADMIN_TOKENS, e.g.
+//! `{"5e2d8c41": "alice", "9a07f3b6": "bob"}`, and authenticated with it as
+//! `Authorization: Bearer <token>`. Every call is recorded in the append-only audit log set in
+//! GEOIP_RS_AUDIT_LOG, one JSON object per line, with who made it, when, and its outcome.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+
+#[derive(Clone, Default)]
+pub struct Admin {
+    /// Names of the admin tokens.
+    tokens: HashMap<String, String>,
+    audit_log: Option<Arc<Mutex<File>>>,
+}
+
+impl Admin {
+    pub fn from_env() -> Result<Admin, String> {
+        let mut tokens = HashMap::new();
+        if let Ok(path) = env::var("GEOIP_RS_ADMIN_TOKENS") {
+            let content = fs::read_to_string(&path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+            tokens = serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", path, err))?;
+        }
+        if let Some(token) = env::var("GEOIP_RS_ADMIN_TOKEN").ok().filter(|token| !token.is_empty()) {
+            tokens.insert(token, String::from("admin"));
+        }
+
+        let audit_log = match env::var("GEOIP_RS_AUDIT_LOG") {
+            Ok(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .map_err(|err| format!("Can not open {}: {}", path, err))?;
+                Some(Arc::new(Mutex::new(file)))
+            }
+            Err(_) => None,
+        };
+
+        Ok(Admin { tokens, audit_log })
+    }
+
+    /// Whether an admin token is set, without which the admin endpoints aren't served.
+    pub fn enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Runs the admin `operation` if the request is authenticated, and records the call in the
+    /// audit log. Answers 404 when there's no admin token, as if the admin endpoints didn't
+    /// exist, and 401 when the request doesn't carry one.
+    pub fn run<F: FnOnce() -> HttpResponse>(&self, req: &HttpRequest, operation: &str, run: F) -> HttpResponse {
+        if !self.enabled() {
+            return HttpResponse::NotFound().finish();
+        }
+
+        let principal = self.principal(req);
+        let response = match principal {
+            Some(_) => run(),
+            None => unauthorized(),
+        };
+
+        self.audit(req, principal, operation, &response);
+        response
+    }
+
+    /// Like [`run`](Admin::run), for the operations that wait on something, e.g. on a file read off
+    /// the workers.
+    pub async fn run_async<F, R>(&self, req: &HttpRequest, operation: &str, run: F) -> HttpResponse
+    where
+        F: FnOnce() -> R,
+        R: Future<Output = HttpResponse>,
+    {
+        if !self.enabled() {
+            return HttpResponse::NotFound().finish();
+        }
+
+        let principal = self.principal(req);
+        let response = match principal {
+            Some(_) => run().await,
+            None => unauthorized(),
+        };
+
+        self.audit(req, principal, operation, &response);
+        response
+    }
+
+    /// The name of the admin token the request carries, if it's a known one.
+    fn principal(&self, req: &HttpRequest) -> Option<&str> {
+        req.headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .and_then(|token| self.tokens.get(token.trim()))
+            .map(String::as_str)
+    }
+
+    fn audit(&self, req: &HttpRequest, principal: Option<&str>, operation: &str, response: &HttpResponse) {
+        let audit_log = match &self.audit_log {
+            Some(audit_log) => audit_log,
+            None => return,
+        };
+
+        let entry = serde_json::json!({
+            "timestamp": SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+            "principal": principal,
+            "client": req.peer_addr().map(|peer| peer.ip().to_string()),
+            "operation": operation,
+            "request": format!("{} {}", req.method(), req.uri()),
+            "status": response.status().as_u16(),
+            "outcome": if principal.is_none() {
+                "denied"
+            } else if response.status().is_success() {
+                "succeeded"
+            } else {
+                "failed"
+            },
+        });
+
+        let mut file = audit_log.lock().unwrap();
+        let written = writeln!(file, "{}", entry).and_then(|_| file.sync_data());
+        if let Err(err) = written {
+            eprintln!("Can not write the audit log: {}", err);
+        }
+    }
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .json(serde_json::json!({ "error": "invalid admin token" }))
+}
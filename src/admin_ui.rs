@@ -0,0 +1,89 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /admin/ui`, a single page for operators without a metrics stack, enabled with
+//! GEOIP_RS_ADMIN_UI and an admin token. The page itself holds no data: it asks for the admin
+//! token, then polls `GET /admin/stats`, an admin endpoint, for the requests served, the loaded
+//! database and its age, the cache and the downloads, and has a form looking IP addresses up
+//! through the API itself.
+
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::{json, Map, Value};
+
+use crate::admin::Admin;
+use crate::cache::Cache;
+use crate::diagnostics::Diagnostics;
+use crate::metrics::{self, Requests};
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::reload::Reloads;
+use crate::updater::Status;
+
+const PAGE: &str = include_str!("admin_ui.html");
+
+/// What the stats are gathered from.
+pub struct AdminUi {
+    pub provider: Arc<MaxMindProvider>,
+    pub cache: Option<Arc<Cache>>,
+    pub requests: Arc<Requests>,
+    pub reloads: Arc<Reloads>,
+    pub downloads: Arc<Status>,
+    pub diagnostics: Arc<Diagnostics>,
+}
+
+impl AdminUi {
+    fn stats(&self) -> Value {
+        let metadata = self.provider.metadata();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+        let mut requests = Map::new();
+        for (endpoint, (count, seconds)) in self.requests.by_endpoint() {
+            let mean_ms = if count > 0 { seconds * 1000.0 / count as f64 } else { 0.0 };
+            requests.insert(endpoint.to_string(), json!({ "count": count, "meanMs": mean_ms }));
+        }
+
+        json!({
+            "database": {
+                "loaded": self.provider.is_loaded(),
+                "databaseType": metadata.database_type,
+                "buildEpoch": metadata.build_epoch,
+                "ageSecs": now.saturating_sub(metadata.build_epoch),
+                "ipVersion": metadata.ip_version,
+                "nodeCount": metadata.node_count,
+                "languages": metadata.languages,
+            },
+            "requests": requests,
+            "lookupErrors": metrics::LOOKUP_ERRORS.load(Ordering::Relaxed),
+            "cache": self.cache.as_ref().map(|cache| cache.stats()),
+            "downloads": self.downloads.state(),
+            "lastReload": self.reloads.last(),
+            "process": self.diagnostics.report(),
+        })
+    }
+}
+
+/// The page, served when there's an admin token to use it with.
+pub async fn page(admin: web::Data<Admin>) -> HttpResponse {
+    if !admin.enabled() {
+        return HttpResponse::NotFound().finish();
+    }
+    HttpResponse::Ok().content_type("text/html; charset=utf-8").body(PAGE)
+}
+
+pub async fn stats(req: HttpRequest, admin: web::Data<Admin>, ui: web::Data<Arc<AdminUi>>) -> HttpResponse {
+    admin.run(&req, "stats", || HttpResponse::Ok().json(ui.stats()))
+}
@@ -0,0 +1,76 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /allowed?ip=..&allow=IT,FR,DE`: whether an IP address is allowed by a geo-blocking policy,
+//! so that apps can leave the evaluation to geoip-rs. Policies are lists of the ISO 3166-1 codes
+//! of the allowed or denied countries, from the `allow` and `deny` query params or from the API
+//! key of the request, which takes precedence.
+
+use std::collections::HashSet;
+
+use serde_json::{json, Value};
+
+#[derive(Debug)]
+pub struct Policy {
+    /// Countries allowed, all the ones not denied when missing.
+    allow: Option<HashSet<String>>,
+    deny: HashSet<String>,
+}
+
+impl Policy {
+    /// A policy from comma separated lists of countries, none when both are missing.
+    pub fn parse(allow: Option<&str>, deny: Option<&str>) -> Option<Policy> {
+        let countries = |list: &str| {
+            list.split(',')
+                .map(|country| country.trim().to_uppercase())
+                .filter(|country| !country.is_empty())
+                .collect::<HashSet<_>>()
+        };
+        if allow.is_none() && deny.is_none() {
+            return None;
+        }
+
+        Some(Policy {
+            allow: allow.map(countries),
+            deny: deny.map(countries).unwrap_or_default(),
+        })
+    }
+
+    pub fn from_lists(allow: Option<&[String]>, deny: Option<&[String]>) -> Option<Policy> {
+        let allow = allow.map(|allow| allow.join(","));
+        let deny = deny.map(|deny| deny.join(","));
+        Policy::parse(allow.as_deref(), deny.as_deref())
+    }
+
+    /// The verdict on an IP address located in `country_code`, if it's located at all. Addresses
+    /// of unknown countries are denied by allowlists, and allowed by denylists.
+    pub fn verdict(&self, ip_address: &str, country_code: Option<&str>) -> Value {
+        let (allowed, reason) = match (country_code, &self.allow) {
+            (Some(country), _) if self.deny.contains(country) => (false, format!("{} is denied", country)),
+            (Some(country), Some(allow)) if !allow.contains(country) => {
+                (false, format!("{} is not in the allowed countries", country))
+            }
+            (Some(country), _) => (true, format!("{} is allowed", country)),
+            (None, Some(_)) => (false, String::from("the country is unknown, and not in the allowed countries")),
+            (None, None) => (true, String::from("the country is unknown, and not denied")),
+        };
+
+        json!({
+            "ipAddress": ip_address,
+            "countryCode": country_code,
+            "allowed": allowed,
+            "reason": reason,
+        })
+    }
+}
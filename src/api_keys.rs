@@ -0,0 +1,415 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! API keys, sent with the `key` query param or as `Authorization: Bearer <key>`, listed in the
+//! JSON file set in GEOIP_RS_API_KEYS, or in the value of GEOIP_RS_API_KEYS itself, e.g.
+//! `{"0f3a...": {"name": "acme", "database": "/var/lib/geoip-rs/GeoIP2-City.mmdb"}}`.
+//! Keys can have a database of their own and be restricted to some fields, so that tenants of a
+//! shared instance get different databases and feature sets. They can have a rate limit of their
+//! own too, in place of the one of the calling IP addresses. With GEOIP_RS_REQUIRE_API_KEY,
+//! requests without a key are refused with 401.
+//!
+//! Keys can also be bound to the origins of the websites they're registered for, e.g.
+//! `"origins": ["https://example.com", "https://*.example.com"]`: requests with those keys are
+//! refused with 403 unless their Origin, or the origin of their Referer for JSONP script tags, is
+//! one of those. `"jsonp": false` refuses their JSONP requests.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use actix_web::http::header;
+use actix_web::{HttpRequest, HttpResponse};
+use serde_json::Value;
+
+use crate::allowed::Policy;
+use crate::provider::MaxMindProvider;
+use crate::rate_limit::{self, RateLimit, TokenBucket};
+use crate::settings::env_flag;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct KeyPolicy {
+    /// Who the key belongs to.
+    #[serde(default)]
+    pub name: String,
+    /// Path of the MaxMind database resolved against in place of the loaded one.
+    pub database: Option<String>,
+    /// Names of the only fields of the responses, all when missing.
+    pub fields: Option<Vec<String>>,
+    /// Countries allowed by `/allowed`, in place of the ones of the `allow` query param.
+    pub allow: Option<Vec<String>>,
+    /// Countries denied by `/allowed`, in place of the ones of the `deny` query param.
+    pub deny: Option<Vec<String>>,
+    /// Origins the key can be used from, any when missing.
+    pub origins: Option<Vec<String>>,
+    /// Whether JSONP callbacks are allowed, true when missing.
+    pub jsonp: Option<bool>,
+    /// Requests per minute, in place of the rate limit of the calling IP addresses.
+    pub rate_limit: Option<f64>,
+    /// Requests allowed in a burst, `rate_limit` by default.
+    pub rate_limit_burst: Option<f64>,
+}
+
+impl KeyPolicy {
+    /// Whether the request comes from one of the origins of the key.
+    fn from_origins(&self, req: &HttpRequest) -> bool {
+        let origins = match &self.origins {
+            Some(origins) => origins,
+            None => return true,
+        };
+        let value = |name: header::HeaderName| req.headers().get(name).and_then(|value| value.to_str().ok());
+        let origin = value(header::ORIGIN).or_else(|| value(header::REFERER).map(origin_of));
+
+        origin.map_or(false, |origin| origins.iter().any(|allowed| matches_origin(allowed, origin)))
+    }
+}
+
+#[derive(Default)]
+pub struct KeyStore {
+    keys: HashMap<String, KeyPolicy>,
+    /// Databases of the keys having one, shared by the keys with the same path.
+    databases: HashMap<String, Arc<MaxMindProvider>>,
+    /// Token buckets of the keys having a rate limit.
+    buckets: HashMap<String, Mutex<TokenBucket>>,
+    /// Whether requests without a key are refused.
+    required: bool,
+}
+
+impl KeyStore {
+    pub fn from_env() -> Result<KeyStore, String> {
+        let required = env_flag("GEOIP_RS_REQUIRE_API_KEY");
+        let path = match env::var("GEOIP_RS_API_KEYS") {
+            Ok(path) => path,
+            Err(_) if required => {
+                return Err(String::from("GEOIP_RS_REQUIRE_API_KEY needs the GEOIP_RS_API_KEYS env var"))
+            }
+            Err(_) => return Ok(KeyStore::default()),
+        };
+        // The keys themselves rather than the path of their file
+        let (content, source) = if path.trim_start().starts_with('{') {
+            (path.clone(), "GEOIP_RS_API_KEYS")
+        } else {
+            let content = fs::read_to_string(&path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+            (content, path.as_str())
+        };
+        let keys: HashMap<String, KeyPolicy> =
+            serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", source, err))?;
+
+        KeyStore::new(keys, required)
+    }
+
+    /// The store of `keys`, opening their databases and filling their token buckets.
+    fn new(keys: HashMap<String, KeyPolicy>, required: bool) -> Result<KeyStore, String> {
+        let mut databases = HashMap::new();
+        let mut opened: HashMap<&str, Arc<MaxMindProvider>> = HashMap::new();
+        for (key, policy) in &keys {
+            if let Some(database) = &policy.database {
+                let provider = match opened.get(database.as_str()) {
+                    Some(provider) => provider.clone(),
+                    None => {
+                        let provider = MaxMindProvider::open(database).map_err(|err| {
+                            format!("Can not open {}, the database of API key {}: {}", database, policy.name, err)
+                        })?;
+                        let provider = Arc::new(provider);
+                        opened.insert(database, provider.clone());
+                        provider
+                    }
+                };
+                databases.insert(key.clone(), provider);
+            }
+        }
+
+        let mut buckets = HashMap::new();
+        for (key, policy) in &keys {
+            if let Some(rate) = policy.rate_limit {
+                if rate <= 0.0 {
+                    return Err(format!("The rateLimit of API key {} must be positive, got {}", policy.name, rate));
+                }
+                let burst = policy.rate_limit_burst.unwrap_or_else(|| rate.max(1.0));
+                if burst < 1.0 {
+                    return Err(format!("The rateLimitBurst of API key {} must be from 1, got {}", policy.name, burst));
+                }
+                buckets.insert(key.clone(), Mutex::new(TokenBucket::new(burst, rate / 60.0)));
+            }
+        }
+
+        Ok(KeyStore {
+            keys,
+            databases,
+            buckets,
+            required,
+        })
+    }
+
+    /// The API key of the request, if any, answering 401 when the key is unknown.
+    pub fn check(&self, req: &HttpRequest) -> Result<Option<String>, HttpResponse> {
+        match api_key(req) {
+            Some(key) => match self.keys.get(&key) {
+                Some(policy) if !policy.from_origins(req) => {
+                    Err(forbidden("the API key can not be used from this origin"))
+                }
+                Some(_) => Ok(Some(key)),
+                None => Err(unauthorized("invalid API key")),
+            },
+            None if self.required => Err(unauthorized("an API key is required")),
+            None => Ok(None),
+        }
+    }
+
+    /// The API key of a request of another protocol than HTTP, which has no origin, or the reason
+    /// it's refused.
+    pub fn check_key(&self, key: Option<&str>) -> Result<Option<String>, &'static str> {
+        match key {
+            Some(key) if self.keys.contains_key(key) => Ok(Some(key.to_string())),
+            Some(_) => Err("invalid API key"),
+            None if self.required => Err("an API key is required"),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether requests without a key are refused.
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    /// Takes a token from the bucket of `key`, if it has a rate limit of its own, returning where
+    /// it stands, or the `429 Too Many Requests` response when it exceeded its rate.
+    pub fn rate_limit(&self, key: &str) -> Option<Result<RateLimit, HttpResponse>> {
+        let mut bucket = self.buckets.get(key)?.lock().unwrap();
+        if !bucket.take() {
+            let retry_after = bucket.retry_after().as_secs() + 1;
+            return Some(Err(rate_limit::too_many_requests(retry_after, RateLimit::of(&bucket))));
+        }
+        Some(Ok(RateLimit::of(&bucket)))
+    }
+
+    /// Whether `key` is listed.
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// The database of `key`, if it has one of its own.
+    pub fn database(&self, key: &str) -> Option<Arc<MaxMindProvider>> {
+        self.databases.get(key).cloned()
+    }
+
+    /// Whether `key` can be used for JSONP requests.
+    pub fn jsonp(&self, key: &str) -> bool {
+        self.keys.get(key).and_then(|policy| policy.jsonp).unwrap_or(true)
+    }
+
+    /// The geo-blocking policy of `key`, if it has one of its own.
+    pub fn geo_policy(&self, key: &str) -> Option<Policy> {
+        let policy = self.keys.get(key)?;
+        Policy::from_lists(policy.allow.as_deref(), policy.deny.as_deref())
+    }
+
+    /// Removes the fields `key` is not allowed, from a response body or each body of an array of
+    /// responses. The `error` and `range` of responses for IP addresses not found are always kept.
+    pub fn restrict(&self, key: &str, response: &mut Value) {
+        let fields = match self.keys.get(key).and_then(|policy| policy.fields.as_ref()) {
+            Some(fields) => fields.iter().map(String::as_str).collect::<HashSet<&str>>(),
+            None => return,
+        };
+
+        match response {
+            Value::Array(responses) => responses.iter_mut().for_each(|response| self.restrict(key, response)),
+            Value::Object(body) => {
+                let kept = std::mem::take(body)
+                    .into_iter()
+                    .filter(|(name, _)| name == "error" || name == "range" || fields.contains(name.as_str()));
+                *body = kept.collect();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The API key of the request, from the `key` query param or the Authorization header.
+pub fn api_key(req: &HttpRequest) -> Option<String> {
+    serde_urlencoded::from_str::<Vec<(String, String)>>(req.query_string())
+        .unwrap_or_default()
+        .into_iter()
+        .find(|(name, _)| name == "key")
+        .map(|(_, key)| key)
+        .or_else(|| {
+            req.headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .map(|key| key.trim().to_string())
+        })
+}
+
+/// The `scheme://host[:port]` origin of a URL.
+fn origin_of(url: &str) -> &str {
+    let start = url.find("://").map_or(0, |scheme| scheme + 3);
+    match url[start..].find(|c| c == '/' || c == '?' || c == '#') {
+        Some(end) => &url[..start + end],
+        None => url,
+    }
+}
+
+/// Whether `origin` is `allowed`, which can have a `*.` wildcard for the subdomains of a host,
+/// e.g. `https://*.example.com`.
+fn matches_origin(allowed: &str, origin: &str) -> bool {
+    let allowed = allowed.trim_end_matches('/');
+    match allowed.find("://*.") {
+        Some(wildcard) => {
+            let (scheme, domain) = (&allowed[..wildcard + 3], &allowed[wildcard + 4..]);
+            origin.len() > allowed.len() - 1
+                && origin.starts_with(scheme)
+                && origin.ends_with(domain)
+                && origin[scheme.len()..origin.len() - domain.len()].find(|c| c == '/' || c == ':').is_none()
+        }
+        None => allowed.eq_ignore_ascii_case(origin),
+    }
+}
+
+pub fn forbidden(message: &str) -> HttpResponse {
+    HttpResponse::Forbidden().json(serde_json::json!({ "error": message }))
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .header(header::WWW_AUTHENTICATE, "Bearer")
+        .json(serde_json::json!({ "error": message }))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use serde_json::json;
+
+    use super::*;
+
+    fn store(keys: Value, required: bool) -> KeyStore {
+        KeyStore::new(serde_json::from_value(keys).unwrap(), required).unwrap()
+    }
+
+    #[test]
+    fn origins_match_exactly_or_by_subdomain() {
+        assert!(matches_origin("https://example.com", "https://example.com"));
+        assert!(matches_origin("https://example.com/", "https://example.com"));
+        assert!(matches_origin("https://Example.com", "https://example.com"));
+        assert!(!matches_origin("https://example.com", "http://example.com"));
+        assert!(!matches_origin("https://example.com", "https://example.com:8443"));
+
+        let wildcard = "https://*.example.com";
+        assert!(matches_origin(wildcard, "https://www.example.com"));
+        assert!(matches_origin(wildcard, "https://a.b.example.com"));
+        assert!(!matches_origin(wildcard, "https://example.com"));
+        assert!(!matches_origin(wildcard, "https://.example.com"));
+        assert!(!matches_origin(wildcard, "https://evilexample.com"));
+        assert!(!matches_origin(wildcard, "http://www.example.com"));
+        assert!(!matches_origin(wildcard, "https://www.example.com:8443"));
+        assert!(!matches_origin(wildcard, "https://evil.com/.example.com"));
+        assert!(!matches_origin(wildcard, "https://evil.com:1.example.com"));
+    }
+
+    #[test]
+    fn referers_give_their_origin() {
+        assert_eq!(origin_of("https://www.example.com/page?q=1"), "https://www.example.com");
+        assert_eq!(origin_of("https://www.example.com:8443#top"), "https://www.example.com:8443");
+        assert_eq!(origin_of("https://www.example.com"), "https://www.example.com");
+    }
+
+    #[test]
+    fn keys_of_other_protocols_are_checked() {
+        let optional = store(json!({ "7b1d44a0": { "name": "acme" } }), false);
+        assert_eq!(optional.check_key(Some("7b1d44a0")), Ok(Some(String::from("7b1d44a0"))));
+        assert_eq!(optional.check_key(Some("0f3a9c2e")), Err("invalid API key"));
+        assert_eq!(optional.check_key(None), Ok(None));
+
+        let required = store(json!({ "7b1d44a0": { "name": "acme" } }), true);
+        assert!(required.required());
+        assert_eq!(required.check_key(Some("7b1d44a0")), Ok(Some(String::from("7b1d44a0"))));
+        assert_eq!(required.check_key(None), Err("an API key is required"));
+    }
+
+    #[test]
+    fn keys_of_http_requests_are_checked_with_their_origin() {
+        let keys = store(
+            json!({
+                "7b1d44a0": { "name": "acme" },
+                "0f3a9c2e": { "name": "shop", "origins": ["https://*.example.com"] },
+            }),
+            true,
+        );
+        let status = |req: TestRequest| match keys.check(&req.to_http_request()) {
+            Ok(_) => StatusCode::OK,
+            Err(response) => response.status(),
+        };
+
+        assert_eq!(status(TestRequest::with_uri("/?key=7b1d44a0")), StatusCode::OK);
+        assert_eq!(
+            status(TestRequest::with_uri("/").header(header::AUTHORIZATION, "Bearer 7b1d44a0")),
+            StatusCode::OK
+        );
+        assert_eq!(status(TestRequest::with_uri("/?key=unknown")), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(TestRequest::with_uri("/")), StatusCode::UNAUTHORIZED);
+
+        let shop = || TestRequest::with_uri("/?key=0f3a9c2e");
+        assert_eq!(status(shop().header(header::ORIGIN, "https://www.example.com")), StatusCode::OK);
+        assert_eq!(status(shop().header(header::REFERER, "https://www.example.com/store")), StatusCode::OK);
+        assert_eq!(status(shop().header(header::ORIGIN, "https://evil.com")), StatusCode::FORBIDDEN);
+        assert_eq!(status(shop()), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn keys_with_a_rate_limit_have_a_bucket_of_their_own() {
+        let keys = store(
+            json!({
+                "7b1d44a0": { "name": "acme", "rateLimit": 60, "rateLimitBurst": 2 },
+                "0f3a9c2e": { "name": "shop", "rateLimit": 60, "rateLimitBurst": 1 },
+                "5e2d8c41": { "name": "free" },
+            }),
+            false,
+        );
+
+        assert!(keys.rate_limit("7b1d44a0").unwrap().is_ok());
+        assert!(keys.rate_limit("7b1d44a0").unwrap().is_ok());
+        let rejection = keys.rate_limit("7b1d44a0").unwrap().err().unwrap();
+        assert_eq!(rejection.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        assert!(keys.rate_limit("0f3a9c2e").unwrap().is_ok());
+        assert!(keys.rate_limit("5e2d8c41").is_none());
+    }
+
+    #[test]
+    fn invalid_rate_limits_are_refused() {
+        let keys = json!({ "7b1d44a0": { "name": "acme", "rateLimit": 0 } });
+        assert!(KeyStore::new(serde_json::from_value(keys).unwrap(), false).is_err());
+        let keys = json!({ "7b1d44a0": { "name": "acme", "rateLimit": 60, "rateLimitBurst": 0.5 } });
+        assert!(KeyStore::new(serde_json::from_value(keys).unwrap(), false).is_err());
+    }
+
+    #[test]
+    fn restricted_responses_keep_their_errors() {
+        let keys = store(json!({ "7b1d44a0": { "name": "acme", "fields": ["countryCode"] } }), false);
+
+        let mut responses = json!([
+            { "ipAddress": "46.51.179.90", "countryCode": "IE", "cityName": "Dublin" },
+            { "ip_address": "10.0.0.1", "error": "not_found", "range": "10.0.0.0/8" },
+        ]);
+        keys.restrict("7b1d44a0", &mut responses);
+        assert_eq!(
+            responses,
+            json!([{ "countryCode": "IE" }, { "error": "not_found", "range": "10.0.0.0/8" }])
+        );
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Streaming of the batches: their bodies are parsed on a thread as they're received, and their
+//! responses sent as they're resolved, so that memory stays flat whatever the size of a batch.
+//! The threads are the [STREAM_WORKERS] of a pool shared with the other streamed responses, e.g.
+//! `/networks`: when they're all busy, the response is a 503.
+
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::{SinkExt, StreamExt};
+
+/// Bytes sent to the client at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Responses streamed at once, each holding a thread for as long as its client takes.
+pub const STREAM_WORKERS: usize = 32;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The idle workers wait for a job on a channel without a buffer, so that handing over a job
+/// fails right away when none of them is idle.
+static WORKERS: OnceLock<SyncSender<Job>> = OnceLock::new();
+
+fn workers() -> &'static SyncSender<Job> {
+    WORKERS.get_or_init(|| {
+        let (sender, receiver) = sync_channel::<Job>(0);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..STREAM_WORKERS {
+            let receiver = receiver.clone();
+            thread::spawn(move || work(&receiver));
+        }
+        sender
+    })
+}
+
+fn work(receiver: &Mutex<Receiver<Job>>) {
+    loop {
+        // The guard is dropped before running the job, letting another worker wait meanwhile
+        let job = receiver.lock().unwrap().recv();
+        match job {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+/// Runs `job` on an idle worker, failing with the 503 to answer when there's none.
+pub fn spawn<F: FnOnce() + Send + 'static>(job: F) -> Result<(), HttpResponse> {
+    workers().try_send(Box::new(job)).map_err(|_| {
+        HttpResponse::ServiceUnavailable()
+            .header(header::RETRY_AFTER, "1")
+            .content_type("text/plain; charset=utf-8")
+            .body(format!("{} responses are streaming already, retry later", STREAM_WORKERS))
+    })
+}
+
+/// The body of a request as a blocking reader, fed by the payload as it's received. Bodies
+/// larger than `limit` bytes end with an error.
+pub struct PayloadReader {
+    receiver: mpsc::Receiver<io::Result<web::Bytes>>,
+    chunk: web::Bytes,
+}
+
+impl PayloadReader {
+    pub fn new(mut payload: web::Payload, limit: usize) -> PayloadReader {
+        let (mut sender, receiver) = mpsc::channel(16);
+        actix_rt::spawn(async move {
+            let mut size = 0;
+            while let Some(chunk) = payload.next().await {
+                let chunk = match chunk {
+                    Ok(chunk) if size + chunk.len() > limit => Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the body is larger than {} bytes", limit),
+                    )),
+                    Ok(chunk) => Ok(chunk),
+                    Err(err) => Err(io::Error::new(io::ErrorKind::Other, err.to_string())),
+                };
+                size += chunk.as_ref().map_or(0, |chunk| chunk.len());
+                let failed = chunk.is_err();
+                if sender.send(chunk).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        PayloadReader {
+            receiver,
+            chunk: web::Bytes::new(),
+        }
+    }
+}
+
+impl Read for PayloadReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match block_on(self.receiver.next()) {
+                Some(chunk) => self.chunk = chunk?,
+                None => return Ok(0),
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len());
+        buf[..len].copy_from_slice(&self.chunk.split_to(len));
+        Ok(len)
+    }
+}
+
+/// The body of a response, sent in chunks as it's written.
+pub struct Chunks {
+    sender: mpsc::Sender<Result<web::Bytes, actix_web::Error>>,
+    buf: Vec<u8>,
+}
+
+impl Chunks {
+    fn send(&mut self) -> io::Result<()> {
+        let chunk = web::Bytes::from(std::mem::take(&mut self.buf));
+        block_on(self.sender.send(Ok(chunk))).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client gone"))
+    }
+}
+
+impl Write for Chunks {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        if self.buf.len() >= CHUNK_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        self.send()
+    }
+}
+
+/// Runs `write` on a worker, streaming what it writes as the body of the response. Errors
+/// abort the response, which has already started by then.
+pub fn stream<F>(content_type: &str, write: F) -> HttpResponse
+where
+    F: FnOnce(&mut Chunks) -> Result<(), String> + Send + 'static,
+{
+    let (sender, receiver) = mpsc::channel(16);
+    let spawned = spawn(move || {
+        let mut chunks = Chunks { sender, buf: vec![] };
+        let written = write(&mut chunks).and_then(|_| chunks.flush().map_err(|err| err.to_string()));
+        if let Err(err) = written {
+            eprintln!("Can not stream the batch: {}", err);
+            let _ = block_on(chunks.sender.send(Err(actix_web::error::ErrorBadRequest(err))));
+        }
+    });
+
+    match spawned {
+        Ok(()) => HttpResponse::Ok().content_type(content_type).streaming(receiver),
+        Err(busy) => busy,
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cache of the resolved responses, enabled with GEOIP_RS_CACHE_SIZE, the number of responses
+//! kept, the least recently used being evicted first. Entries carry the build time of the
+//! database they were resolved with: once another database is loaded they're stale, and dropped
+//! when they're next read rather than all at once on reload. GEOIP_RS_CACHE_TTL, in seconds, caps
+//! how long an entry is served anyway.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+const DEFAULT_TTL: u64 = 3600;
+
+/// What a response depends on, besides the database.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// The API key, for keys resolved against a database of their own.
+    pub database: Option<String>,
+    pub endpoint: String,
+    pub ip_address: String,
+    pub language: String,
+}
+
+struct Entry {
+    geoip: Value,
+    build_epoch: u64,
+    inserted: Instant,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, Entry>,
+    /// Keys from the least to the most recently used, evicted from the front when the cache is
+    /// full. Keys read, replaced or dropped in the meantime are skipped thanks to their generation.
+    order: VecDeque<(CacheKey, u64)>,
+    generation: u64,
+}
+
+pub struct Cache {
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Cache {
+    pub fn from_env() -> Result<Option<Cache>, String> {
+        let capacity = match env::var("GEOIP_RS_CACHE_SIZE") {
+            Ok(size) => size
+                .parse::<usize>()
+                .map_err(|_| format!("GEOIP_RS_CACHE_SIZE must be a number, got {}", size))?,
+            Err(_) => return Ok(None),
+        };
+        let ttl = match env::var("GEOIP_RS_CACHE_TTL") {
+            Ok(ttl) => ttl
+                .parse::<u64>()
+                .map_err(|_| format!("GEOIP_RS_CACHE_TTL must be a number of seconds, got {}", ttl))?,
+            Err(_) => DEFAULT_TTL,
+        };
+
+        if capacity == 0 {
+            return Ok(None);
+        }
+        Ok(Some(Cache {
+            capacity,
+            ttl: Duration::from_secs(ttl),
+            entries: Mutex::new(Entries::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    /// The response cached for `key`, unless it was resolved with another database than the one
+    /// built at `build_epoch`, or is older than the TTL.
+    pub fn get(&self, key: &CacheKey, build_epoch: u64) -> Option<Value> {
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = match entries.map.get(key) {
+            Some(entry) => entry.build_epoch == build_epoch && entry.inserted.elapsed() <= self.ttl,
+            None => {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            }
+        };
+
+        if fresh {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            entries.generation += 1;
+            let generation = entries.generation;
+            entries.order.push_back((key.clone(), generation));
+            let geoip = entries.map.get_mut(key).map(|entry| {
+                entry.generation = generation;
+                entry.geoip.clone()
+            });
+            self.compact(&mut entries);
+            geoip
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            entries.map.remove(key);
+            None
+        }
+    }
+
+    pub fn insert(&self, key: CacheKey, build_epoch: u64, geoip: Value) {
+        let mut entries = self.entries.lock().unwrap();
+        while entries.map.len() >= self.capacity && !entries.map.contains_key(&key) {
+            let (evicted, generation) = match entries.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if entries.map.get(&evicted).map_or(false, |entry| entry.generation == generation) {
+                entries.map.remove(&evicted);
+            }
+        }
+
+        entries.generation += 1;
+        let generation = entries.generation;
+        entries.order.push_back((key.clone(), generation));
+        entries.map.insert(
+            key,
+            Entry {
+                geoip,
+                build_epoch,
+                inserted: Instant::now(),
+                generation,
+            },
+        );
+
+        self.compact(&mut entries);
+    }
+
+    /// Keeps the queue from growing with the keys read again or dropped as stale.
+    fn compact(&self, entries: &mut Entries) {
+        if entries.order.len() > self.capacity * 2 {
+            let Entries { map, order, .. } = entries;
+            order.retain(|(key, generation)| map.get(key).map_or(false, |entry| entry.generation == *generation));
+        }
+    }
+
+    /// Hits and misses since startup, and the entries kept.
+    pub fn counts(&self) -> (u64, u64, usize) {
+        let entries = self.entries.lock().unwrap().map.len();
+        (self.hits.load(Ordering::Relaxed), self.misses.load(Ordering::Relaxed), entries)
+    }
+
+    /// Entries, capacity, and hits and misses since startup.
+    pub fn stats(&self) -> Value {
+        let (hits, misses, entries) = self.counts();
+        json!({
+            "entries": entries,
+            "capacity": self.capacity,
+            "ttlSecs": self.ttl.as_secs(),
+            "hits": hits,
+            "misses": misses,
+        })
+    }
+}
@@ -0,0 +1,29 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coordinates of the centers of the countries, embedded from `data/country-centroids.json`,
+//! returned for records with a country and no location when GEOIP_RS_COUNTRY_CENTROIDS is set.
+
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+static CENTROIDS: OnceLock<HashMap<String, (f64, f64)>> = OnceLock::new();
+
+/// The `(latitude, longitude)` of the center of the country with ISO code `code`, if known.
+pub fn centroid(code: &str) -> Option<(f64, f64)> {
+    CENTROIDS
+        .get_or_init(|| serde_json::from_str(include_str!("../data/country-centroids.json")).unwrap())
+        .get(code)
+        .copied()
+}
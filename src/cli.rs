@@ -0,0 +1,70 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+use std::env;
+
+use crate::provider::MaxMindProvider;
+
+/// Command line arguments of a subcommand: `--name value` / `--name=value` options
+/// (an option followed by another option or by nothing is a switch) and positional arguments.
+pub struct Args {
+    options: HashMap<String, String>,
+    pub positional: Vec<String>,
+}
+
+impl Args {
+    pub fn parse(args: &[String]) -> Args {
+        let mut options = HashMap::new();
+        let mut positional = Vec::new();
+
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with("--") || arg == "--" {
+                positional.push(arg.to_string());
+                continue;
+            }
+
+            let arg = arg.trim_start_matches("--");
+            if let Some(eq) = arg.find('=') {
+                options.insert(arg[..eq].to_string(), arg[eq + 1..].to_string());
+            } else if args.peek().map_or(false, |next| !next.starts_with("--")) {
+                options.insert(arg.to_string(), args.next().unwrap().to_string());
+            } else {
+                options.insert(arg.to_string(), String::from("true"));
+            }
+        }
+
+        Args { options, positional }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(String::as_str)
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.options.contains_key(name)
+    }
+}
+
+/// Opens the database specified with `--db`, falling back to the GEOIP_RS_DB_PATH env var.
+pub fn open_db(args: &Args) -> Result<MaxMindProvider, String> {
+    let path = args
+        .get("db")
+        .map(String::from)
+        .or_else(|| env::var("GEOIP_RS_DB_PATH").ok())
+        .ok_or_else(|| String::from("You must specify the db path, either with --db or as GEOIP_RS_DB_PATH env var"))?;
+
+    MaxMindProvider::open(&path).map_err(|err| format!("Can not open {}: {}", path, err))
+}
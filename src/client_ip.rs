@@ -0,0 +1,530 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! How the IP address to resolve is picked out of a request: the `ip` param, refused when it's not
+//! an IP address, else the address of the caller, picked with the strategy set in
+//! GEOIP_RS_CLIENT_IP_STRATEGY out of the proxy headers and the address of the socket.
+//!
+//! With GEOIP_RS_TRUSTED_PROXIES set, the proxy headers are only believed when the socket is
+//! connected to one of those proxies: requests straight from the callers are resolved with the
+//! address of the socket, whatever headers they forge.
+
+use std::env;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use actix_web::http::HeaderMap;
+use ipnetwork::IpNetwork;
+
+/// Where the IP address to resolve was found.
+#[derive(Clone, Debug, PartialEq, Hash)]
+pub enum IpSource {
+    Query,
+    Forwarded,
+    XForwardedFor,
+    XRealIp,
+    Socket,
+    /// Another header, named in the header priority of the strategy.
+    Header(String),
+}
+
+impl IpSource {
+    pub fn as_str(&self) -> &str {
+        match self {
+            IpSource::Query => "query",
+            IpSource::Forwarded => "forwarded",
+            IpSource::XForwardedFor => "x-forwarded-for",
+            IpSource::XRealIp => "x-real-ip",
+            IpSource::Socket => "socket",
+            IpSource::Header(name) => name,
+        }
+    }
+}
+
+/// The IP address to resolve, and how it was found.
+#[derive(Clone, Debug)]
+pub struct ClientIp {
+    pub ip_address: String,
+    pub source: IpSource,
+    /// The addresses the request went through, as they were considered: the ones of the
+    /// Forwarded or X-Forwarded-For header, from the client to the last proxy, then the one of
+    /// the socket.
+    pub chain: Vec<String>,
+}
+
+/// Why there's no IP address to resolve in a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum IpError {
+    /// The IP address asked for, in the `ip` param or the path, is not one.
+    InvalidIp(String),
+    /// The request has neither an `ip` param nor a caller address, e.g. on a Unix socket.
+    NoAddress,
+}
+
+impl IpError {
+    /// Machine readable code of the error, the `error` of its response.
+    pub fn code(&self) -> &'static str {
+        match self {
+            IpError::InvalidIp(_) => "invalid_ip",
+            IpError::NoAddress => "no_ip_address",
+        }
+    }
+}
+
+impl fmt::Display for IpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IpError::InvalidIp(ip_address) => write!(f, "{} is not an IP address", ip_address),
+            IpError::NoAddress => write!(f, "the request has no IP address to resolve"),
+        }
+    }
+}
+
+/// How the address of the caller is picked out of the chain of addresses the request went
+/// through. Different CDN and load balancer setups need different rules.
+#[derive(Clone, Debug, Default)]
+pub enum Strategy {
+    /// X-Real-IP, or the first address of the chain (`leftmost`, the default). With
+    /// GEOIP_RS_TRUSTED_PROXIES set, the last address of the chain that's not a trusted proxy,
+    /// like [Strategy::RightmostUntrusted].
+    #[default]
+    Leftmost,
+    /// The last address of the chain that's not one of GEOIP_RS_TRUSTED_PROXIES
+    /// (`rightmost-untrusted`). It can't be spoofed by the caller.
+    RightmostUntrusted,
+    /// The address `n` hops from the end of the chain, 0 being the socket (`hop:<n>`).
+    Hop(usize),
+    /// The first of these headers that's in the request, `socket` standing for the address of
+    /// the socket (`headers:<name>,<name>,...`). Comma separated headers give their first address.
+    Headers(Vec<String>),
+}
+
+/// Picks the IP address to resolve out of a request with a [Strategy], trusting the proxy headers
+/// only from the proxies of GEOIP_RS_TRUSTED_PROXIES, when they're set.
+#[derive(Clone, Debug)]
+pub struct ClientIpPicker {
+    strategy: Strategy,
+    /// The networks of the proxies in front of geoip-rs. Any peer is trusted without them.
+    trusted: Vec<IpNetwork>,
+    /// The headers the chain of addresses is read from: the first one in the request, in the
+    /// order of GEOIP_RS_PROXY_HEADERS.
+    chain_headers: Vec<IpSource>,
+}
+
+impl Default for ClientIpPicker {
+    fn default() -> ClientIpPicker {
+        ClientIpPicker {
+            strategy: Strategy::default(),
+            trusted: Vec::new(),
+            chain_headers: vec![IpSource::Forwarded, IpSource::XForwardedFor],
+        }
+    }
+}
+
+impl ClientIpPicker {
+    pub fn from_env() -> Result<ClientIpPicker, String> {
+        let mut picker = ClientIpPicker {
+            strategy: Strategy::from_env()?,
+            trusted: trusted_proxies()?,
+            ..ClientIpPicker::default()
+        };
+        if let Ok(headers) = env::var("GEOIP_RS_PROXY_HEADERS") {
+            picker.chain_headers = headers
+                .split(',')
+                .map(|name| match name.trim().to_lowercase().as_str() {
+                    "forwarded" => Ok(IpSource::Forwarded),
+                    "x-forwarded-for" => Ok(IpSource::XForwardedFor),
+                    "x-real-ip" => Ok(IpSource::XRealIp),
+                    name => Err(format!(
+                        "GEOIP_RS_PROXY_HEADERS can list forwarded, x-forwarded-for and x-real-ip, got {}",
+                        name
+                    )),
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        Ok(picker)
+    }
+
+    /// Picks the IP address to resolve: the `ip` param, or the address of the caller.
+    pub fn client_ip(
+        &self,
+        ip: Option<String>,
+        headers: &HeaderMap,
+        peer: Option<SocketAddr>,
+    ) -> Result<ClientIp, IpError> {
+        // An empty `ip` param is no param
+        let ip = ip.filter(|ip_address| !ip_address.trim().is_empty());
+        if let Some(ip_address) = ip.as_ref().filter(|ip_address| ip_address.trim().parse::<IpAddr>().is_err()) {
+            return Err(IpError::InvalidIp(ip_address.clone()));
+        }
+
+        // Headers sent by anyone but the trusted proxies are ignored
+        let untrusted = HeaderMap::new();
+        let from_proxy = self.trusted.is_empty() || peer.map_or(false, |peer| self.is_trusted(peer.ip()));
+        let headers = if from_proxy { headers } else { &untrusted };
+
+        let (forwarded, forwarded_source) = self
+            .chain_headers
+            .iter()
+            .map(|source| {
+                let forwarded = match source {
+                    IpSource::Forwarded => forwarded_for(headers),
+                    IpSource::XRealIp => header_list(headers, "X-Real-IP"),
+                    _ => header_list(headers, "X-Forwarded-For"),
+                };
+                (forwarded, source.clone())
+            })
+            .find(|(forwarded, _)| !forwarded.is_empty())
+            .unwrap_or((Vec::new(), IpSource::Socket));
+
+        let mut chain = forwarded.clone();
+        chain.extend(peer.map(|peer| peer.ip().to_string()));
+
+        let from_chain = |index: usize| {
+            let source = if index < forwarded.len() {
+                forwarded_source.clone()
+            } else {
+                IpSource::Socket
+            };
+            chain.get(index).map(|ip_address| (ip_address.clone(), source))
+        };
+
+        let rightmost_untrusted = || {
+            let untrusted = chain.iter().rposition(|ip_address| match ip_address.parse::<IpAddr>() {
+                Ok(ip) => !self.is_trusted(ip),
+                Err(_) => true,
+            });
+            // When the whole chain is trusted, the caller is the first proxy
+            from_chain(untrusted.unwrap_or(0))
+        };
+
+        let caller = match &self.strategy {
+            // Behind trusted proxies, the addresses the callers prepend to the chain, or send in
+            // headers the proxies pass along, are skipped
+            Strategy::Leftmost if !self.trusted.is_empty() => rightmost_untrusted(),
+            Strategy::Leftmost => header_list(headers, "X-Real-IP")
+                .into_iter()
+                .next()
+                .map(|ip_address| (ip_address, IpSource::XRealIp))
+                .or_else(|| from_chain(0)),
+            Strategy::RightmostUntrusted => rightmost_untrusted(),
+            Strategy::Hop(n) => chain.len().checked_sub(n + 1).and_then(from_chain),
+            Strategy::Headers(names) => names.iter().find_map(|name| match name.as_str() {
+                "socket" => peer.map(|peer| (peer.ip().to_string(), IpSource::Socket)),
+                "forwarded" => forwarded_for(headers).into_iter().next().map(|ip| (ip, IpSource::Forwarded)),
+                name => header_list(headers, name).into_iter().next().map(|ip| (ip, source_of(name))),
+            }),
+        };
+
+        let (ip_address, source) = ip
+            .map(|ip_address| (ip_address, IpSource::Query))
+            .or(caller)
+            .or_else(|| peer.map(|peer| (peer.ip().to_string(), IpSource::Socket)))
+            .ok_or(IpError::NoAddress)?;
+
+        Ok(ClientIp {
+            ip_address,
+            source,
+            chain,
+        })
+    }
+
+    /// The address of the caller, whatever the `ip` param, e.g. to rate limit or log the request.
+    /// Without trusted proxies it's the address of the socket, since anyone can forge the headers.
+    pub fn caller_ip(&self, headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+        let peer_ip = peer.map(|peer| peer.ip());
+        if self.trusted.is_empty() {
+            return peer_ip;
+        }
+        self.client_ip(None, headers, peer)
+            .ok()
+            .and_then(|client_ip| client_ip.ip_address.parse().ok())
+            .or(peer_ip)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|network| network.contains(ip))
+    }
+}
+
+impl Strategy {
+    pub fn from_env() -> Result<Strategy, String> {
+        let strategy = match env::var("GEOIP_RS_CLIENT_IP_STRATEGY") {
+            Ok(strategy) => strategy,
+            Err(_) => return Ok(Strategy::Leftmost),
+        };
+
+        match strategy.as_str() {
+            "leftmost" => Ok(Strategy::Leftmost),
+            "rightmost-untrusted" => Ok(Strategy::RightmostUntrusted),
+            _ => match strategy.split_once(':') {
+                Some(("hop", n)) => n
+                    .parse()
+                    .map(Strategy::Hop)
+                    .map_err(|_| format!("The hop of GEOIP_RS_CLIENT_IP_STRATEGY must be a number, got {}", n)),
+                Some(("headers", names)) => Ok(Strategy::Headers(
+                    names.split(',').map(|name| name.trim().to_lowercase()).collect(),
+                )),
+                _ => Err(format!(
+                    "GEOIP_RS_CLIENT_IP_STRATEGY must be leftmost, rightmost-untrusted, hop:<n> or headers:<name>,..., got {}",
+                    strategy
+                )),
+            },
+        }
+    }
+}
+
+/// The networks of the proxies in front of geoip-rs, comma separated in GEOIP_RS_TRUSTED_PROXIES,
+/// e.g. `10.0.0.0/8,2001:db8::/32`.
+fn trusted_proxies() -> Result<Vec<IpNetwork>, String> {
+    env::var("GEOIP_RS_TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|network| !network.is_empty())
+        .map(|network| {
+            network
+                .parse::<IpNetwork>()
+                .map_err(|err| format!("Invalid network {} in GEOIP_RS_TRUSTED_PROXIES: {}", network, err))
+        })
+        .collect()
+}
+
+fn source_of(header: &str) -> IpSource {
+    match header {
+        "x-forwarded-for" => IpSource::XForwardedFor,
+        "x-real-ip" => IpSource::XRealIp,
+        header => IpSource::Header(header.to_string()),
+    }
+}
+
+/// The comma separated values of a header, across its occurrences.
+fn header_list(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+        .collect()
+}
+
+/// The `for` addresses of the Forwarded header (RFC 7239), e.g.
+/// `for=192.0.2.60;proto=http, for="[2001:db8:cafe::17]:4711"`, without quotes, brackets and ports.
+fn forwarded_for(headers: &HeaderMap) -> Vec<String> {
+    header_list(headers, "Forwarded")
+        .iter()
+        .filter_map(|element| {
+            element
+                .split(';')
+                .filter_map(|pair| pair.trim().split_once('='))
+                .find(|(name, _)| name.eq_ignore_ascii_case("for"))
+                .map(|(_, node)| strip_port(node.trim_matches('"')))
+        })
+        .collect()
+}
+
+/// `node` without its port, e.g. `192.0.2.60` for `192.0.2.60:4711` and `2001:db8:cafe::17` for
+/// `[2001:db8:cafe::17]:4711`.
+fn strip_port(node: &str) -> String {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or("").to_string();
+    }
+    match node.rsplit_once(':') {
+        Some((ip, _)) if !ip.contains(':') => ip.to_string(),
+        _ => node.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn picker(strategy: Strategy, trusted: &[&str]) -> ClientIpPicker {
+        ClientIpPicker {
+            strategy,
+            trusted: trusted.iter().map(|network| network.parse().unwrap()).collect(),
+            ..ClientIpPicker::default()
+        }
+    }
+
+    fn headers(headers: &[(&str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(HeaderName::from_bytes(name.as_bytes()).unwrap(), HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn peer(ip: &str) -> Option<SocketAddr> {
+        Some(SocketAddr::new(ip.parse().unwrap(), 4711))
+    }
+
+    fn caller(picker: &ClientIpPicker, headers: &HeaderMap, peer: Option<SocketAddr>) -> (String, IpSource) {
+        let client_ip = picker.client_ip(None, headers, peer).unwrap();
+        (client_ip.ip_address, client_ip.source)
+    }
+
+    #[test]
+    fn leftmost_takes_the_first_address_without_trusted_proxies() {
+        let picker = picker(Strategy::Leftmost, &[]);
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7, 10.0.0.2")]);
+
+        let client_ip = picker.client_ip(None, &headers, peer("10.0.0.1")).unwrap();
+        assert_eq!(client_ip.ip_address, "203.0.113.7");
+        assert_eq!(client_ip.source, IpSource::XForwardedFor);
+        assert_eq!(client_ip.chain, vec!["203.0.113.7", "10.0.0.2", "10.0.0.1"]);
+    }
+
+    #[test]
+    fn leftmost_prefers_x_real_ip_without_trusted_proxies() {
+        let picker = picker(Strategy::Leftmost, &[]);
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7"), ("X-Real-IP", "198.51.100.4")]);
+
+        assert_eq!(
+            caller(&picker, &headers, peer("10.0.0.1")),
+            (String::from("198.51.100.4"), IpSource::XRealIp)
+        );
+    }
+
+    #[test]
+    fn spoofed_leftmost_entries_are_skipped_behind_trusted_proxies() {
+        let headers = headers(&[
+            ("X-Forwarded-For", "1.2.3.4, 203.0.113.7, 10.0.0.2"),
+            ("X-Real-IP", "1.2.3.4"),
+        ]);
+        let expected = (String::from("203.0.113.7"), IpSource::XForwardedFor);
+
+        let leftmost = picker(Strategy::Leftmost, &["10.0.0.0/8"]);
+        assert_eq!(caller(&leftmost, &headers, peer("10.0.0.1")), expected);
+        let rightmost_untrusted = picker(Strategy::RightmostUntrusted, &["10.0.0.0/8"]);
+        assert_eq!(caller(&rightmost_untrusted, &headers, peer("10.0.0.1")), expected);
+    }
+
+    #[test]
+    fn headers_of_untrusted_peers_are_ignored() {
+        let picker = picker(Strategy::Leftmost, &["10.0.0.0/8"]);
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7"), ("X-Real-IP", "203.0.113.7")]);
+
+        let client_ip = picker.client_ip(None, &headers, peer("198.51.100.9")).unwrap();
+        assert_eq!(client_ip.ip_address, "198.51.100.9");
+        assert_eq!(client_ip.source, IpSource::Socket);
+        assert_eq!(client_ip.chain, vec!["198.51.100.9"]);
+    }
+
+    #[test]
+    fn all_trusted_chains_give_the_first_proxy() {
+        let picker = picker(Strategy::RightmostUntrusted, &["10.0.0.0/8"]);
+        let headers = headers(&[("X-Forwarded-For", "10.0.0.3, 10.0.0.2")]);
+
+        assert_eq!(
+            caller(&picker, &headers, peer("10.0.0.1")),
+            (String::from("10.0.0.3"), IpSource::XForwardedFor)
+        );
+        assert_eq!(
+            caller(&picker, &HeaderMap::new(), peer("10.0.0.1")),
+            (String::from("10.0.0.1"), IpSource::Socket)
+        );
+    }
+
+    #[test]
+    fn hops_count_from_the_socket() {
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7, 10.0.0.2")]);
+
+        assert_eq!(
+            caller(&picker(Strategy::Hop(0), &[]), &headers, peer("10.0.0.1")),
+            (String::from("10.0.0.1"), IpSource::Socket)
+        );
+        assert_eq!(
+            caller(&picker(Strategy::Hop(2), &[]), &headers, peer("10.0.0.1")),
+            (String::from("203.0.113.7"), IpSource::XForwardedFor)
+        );
+        // Past the start of the chain, the socket is the fallback
+        assert_eq!(
+            caller(&picker(Strategy::Hop(3), &[]), &headers, peer("10.0.0.1")),
+            (String::from("10.0.0.1"), IpSource::Socket)
+        );
+    }
+
+    #[test]
+    fn forwarded_addresses_lose_their_brackets_and_ports() {
+        let picker = picker(Strategy::Leftmost, &[]);
+        let headers = headers(&[(
+            "Forwarded",
+            "for=\"[2001:db8:cafe::17]:4711\";proto=https, for=192.0.2.60:8080;by=203.0.113.43",
+        )]);
+
+        let client_ip = picker.client_ip(None, &headers, peer("10.0.0.1")).unwrap();
+        assert_eq!(client_ip.ip_address, "2001:db8:cafe::17");
+        assert_eq!(client_ip.source, IpSource::Forwarded);
+        assert_eq!(client_ip.chain, vec!["2001:db8:cafe::17", "192.0.2.60", "10.0.0.1"]);
+    }
+
+    #[test]
+    fn strip_port_keeps_bare_ipv6_addresses() {
+        assert_eq!(strip_port("192.0.2.60:8080"), "192.0.2.60");
+        assert_eq!(strip_port("[2001:db8::1]:4711"), "2001:db8::1");
+        assert_eq!(strip_port("[2001:db8::1]"), "2001:db8::1");
+        assert_eq!(strip_port("2001:db8::1"), "2001:db8::1");
+    }
+
+    #[test]
+    fn chain_headers_are_read_in_the_order_of_the_proxy_headers() {
+        let headers = headers(&[("Forwarded", "for=192.0.2.60"), ("X-Forwarded-For", "203.0.113.7")]);
+
+        // Forwarded, then X-Forwarded-For, by default
+        let forwarded_first = picker(Strategy::Hop(1), &[]);
+        assert_eq!(
+            caller(&forwarded_first, &headers, peer("10.0.0.1")),
+            (String::from("192.0.2.60"), IpSource::Forwarded)
+        );
+        let x_forwarded_for_first = ClientIpPicker {
+            chain_headers: vec![IpSource::XForwardedFor, IpSource::Forwarded],
+            ..forwarded_first
+        };
+        assert_eq!(
+            caller(&x_forwarded_for_first, &headers, peer("10.0.0.1")),
+            (String::from("203.0.113.7"), IpSource::XForwardedFor)
+        );
+    }
+
+    #[test]
+    fn the_ip_param_wins_unless_it_is_not_an_ip_address() {
+        let picker = picker(Strategy::Leftmost, &[]);
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7")]);
+
+        let client_ip = picker.client_ip(Some(String::from("46.51.179.90")), &headers, peer("10.0.0.1")).unwrap();
+        assert_eq!(client_ip.ip_address, "46.51.179.90");
+        assert_eq!(client_ip.source, IpSource::Query);
+        assert_eq!(
+            picker.client_ip(Some(String::from("nope")), &headers, peer("10.0.0.1")).unwrap_err(),
+            IpError::InvalidIp(String::from("nope"))
+        );
+        assert_eq!(picker.client_ip(None, &HeaderMap::new(), None).unwrap_err(), IpError::NoAddress);
+    }
+
+    #[test]
+    fn caller_ip_trusts_the_headers_only_from_trusted_proxies() {
+        let headers = headers(&[("X-Forwarded-For", "203.0.113.7")]);
+
+        let untrusted = picker(Strategy::Leftmost, &[]);
+        assert_eq!(untrusted.caller_ip(&headers, peer("10.0.0.1")), "10.0.0.1".parse().ok());
+        let trusted = picker(Strategy::Leftmost, &["10.0.0.0/8"]);
+        assert_eq!(trusted.caller_ip(&headers, peer("10.0.0.1")), "203.0.113.7".parse().ok());
+        assert_eq!(trusted.caller_ip(&headers, peer("198.51.100.9")), "198.51.100.9".parse().ok());
+    }
+}
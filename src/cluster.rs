@@ -0,0 +1,177 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cluster mode: replicas pull the database from a primary instance, so that only the primary
+//! needs MaxMind credentials and the whole fleet serves the same database. The primary serves
+//! its database file at `GET /database/blob`, an admin endpoint, with the build time of the
+//! database as ETag. Replicas set GEOIP_RS_PRIMARY_URL and poll it every
+//! GEOIP_RS_PRIMARY_POLL_INTERVAL seconds, sending the build time of their own database as
+//! If-None-Match, and install and reload the database they get back. Failed pulls are retried
+//! with the backoff of the downloads.
+
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use maxminddb::Reader;
+
+use crate::admin::Admin;
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::reload::Reloads;
+use crate::updater::{self, Backoff, Status};
+
+const DEFAULT_POLL_INTERVAL: u64 = 300;
+
+pub async fn blob(req: HttpRequest, admin: web::Data<Admin>, provider: web::Data<Arc<MaxMindProvider>>) -> HttpResponse {
+    let request = req.clone();
+    admin
+        .run_async(&req, "database-blob", move || async move {
+            let loaded = etag(provider.build_epoch());
+            let not_modified = request
+                .headers()
+                .get(header::IF_NONE_MATCH)
+                .and_then(|tags| tags.to_str().ok())
+                .map_or(false, |tags| tags.split(',').any(|tag| tag.trim() == loaded));
+            if not_modified {
+                return HttpResponse::NotModified().header(header::ETAG, loaded).finish();
+            }
+
+            // The whole file is read, off the workers so that lookups aren't held up meanwhile.
+            let path = provider.path().to_path_buf();
+            let read = web::block(move || Ok::<_, String>(read_database(&path)))
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()));
+            match read {
+                Ok((file, build_epoch)) => HttpResponse::Ok()
+                    .content_type("application/octet-stream")
+                    .header(header::ETAG, etag(build_epoch))
+                    .body(file),
+                Err(err) => HttpResponse::InternalServerError().json(serde_json::json!({ "error": err })),
+            }
+        })
+        .await
+}
+
+/// The database file and its build time. The file, rather than the loaded database, is read
+/// since it might have been replaced already.
+fn read_database(path: &Path) -> Result<(Vec<u8>, u64), String> {
+    let file = fs::read(path).map_err(|err| format!("Can not read {}: {}", path.display(), err))?;
+    let build_epoch = Reader::from_source(file.as_slice())
+        .map_err(|err| format!("Can not open {}: {}", path.display(), err))?
+        .metadata
+        .build_epoch;
+    Ok((file, build_epoch))
+}
+
+fn etag(build_epoch: u64) -> String {
+    format!("\"{}\"", build_epoch)
+}
+
+/// Where a replica pulls the database from.
+pub struct Replica {
+    url: String,
+    /// Admin token of the primary (GEOIP_RS_PRIMARY_TOKEN).
+    token: Option<String>,
+    interval: Duration,
+    backoff: Backoff,
+}
+
+impl Replica {
+    /// The primary set in the env, if this instance is a replica.
+    pub fn from_env() -> Result<Option<Replica>, String> {
+        let url = match env::var("GEOIP_RS_PRIMARY_URL") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let interval = match env::var("GEOIP_RS_PRIMARY_POLL_INTERVAL") {
+            Ok(interval) => interval
+                .parse::<u64>()
+                .ok()
+                .filter(|interval| *interval > 0)
+                .ok_or_else(|| format!("GEOIP_RS_PRIMARY_POLL_INTERVAL must be a positive number, got {}", interval))?,
+            Err(_) => DEFAULT_POLL_INTERVAL,
+        };
+
+        Ok(Some(Replica {
+            url: format!("{}/database/blob", url.trim_end_matches('/')),
+            token: env::var("GEOIP_RS_PRIMARY_TOKEN").ok(),
+            interval: Duration::from_secs(interval),
+            backoff: Backoff::from_env()?,
+        }))
+    }
+
+    /// Polls the primary on a dedicated thread, for as long as the server runs, reporting the
+    /// outcome of the pulls to `status`.
+    pub fn spawn(self, provider: Arc<MaxMindProvider>, reloads: Arc<Reloads>, status: Arc<Status>) {
+        thread::spawn(move || loop {
+            let pulled = self.backoff.retry(|| {
+                let pulled = self.pull(&provider, &reloads);
+                match &pulled {
+                    Ok(_) => status.succeeded(),
+                    Err(err) => status.failed(err),
+                }
+                pulled
+            });
+            match pulled {
+                Ok(Some(build_epoch)) => println!("Pulled the database built at {} from {}", build_epoch, self.url),
+                Ok(None) => {}
+                Err(err) => eprintln!("{}, still serving the current database", err),
+            }
+            thread::sleep(self.interval);
+        });
+    }
+
+    /// Installs and reloads the database of the primary, unless it's the one already loaded,
+    /// returning its build time.
+    fn pull(&self, provider: &MaxMindProvider, reloads: &Reloads) -> Result<Option<u64>, String> {
+        let mut request = ureq::get(&self.url);
+        request.timeout(Duration::from_secs(300));
+        request.set("If-None-Match", &etag(provider.build_epoch()));
+        if let Some(token) = &self.token {
+            request.set("Authorization", &format!("Bearer {}", token));
+        }
+
+        let response = request.call();
+        if let Some(err) = response.synthetic_error() {
+            return Err(format!("Can not pull the database from {}: {}", self.url, err));
+        }
+        if response.status() == 304 {
+            return Ok(None);
+        }
+        if !response.ok() {
+            return Err(format!("Can not pull the database from {}: HTTP {}", self.url, response.status()));
+        }
+
+        let db_path = provider.path();
+        let tmp_db_path = updater::tmp_path(db_path);
+        save(response.into_reader(), &tmp_db_path)
+            .map_err(|err| format!("Can not save {}: {}", tmp_db_path.display(), err))?;
+        updater::install(&tmp_db_path, db_path)?;
+        let diff = reloads.reload(provider)?;
+
+        Ok(Some(diff.new_build_epoch))
+    }
+}
+
+fn save<R: io::Read>(mut reader: R, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    io::copy(&mut reader, &mut file)?;
+    file.sync_all()
+}
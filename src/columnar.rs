@@ -0,0 +1,78 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Arrow IPC and Parquet encodings of enriched batches, built with the `columnar` feature.
+//! Input columns are strings, geographical coordinates are 64 bit floats.
+
+use std::sync::Arc;
+
+use arrow::array::{ArrayRef, Float64Array, StringArray};
+use arrow::datatypes::{DataType, Field, Schema};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::file::writer::InMemoryWriteableCursor;
+
+const FLOAT_COLUMNS: [&str; 2] = ["latitude", "longitude"];
+
+/// Encodes the records, the first one being the header row, as an Arrow IPC stream.
+pub fn write_arrow(records: &[csv::StringRecord]) -> Result<Vec<u8>, String> {
+    let batch = record_batch(records)?;
+
+    let mut body = Vec::new();
+    {
+        let mut writer = StreamWriter::try_new(&mut body, &batch.schema()).map_err(|err| err.to_string())?;
+        writer.write(&batch).map_err(|err| err.to_string())?;
+        writer.finish().map_err(|err| err.to_string())?;
+    }
+
+    Ok(body)
+}
+
+/// Encodes the records, the first one being the header row, as a Parquet file.
+pub fn write_parquet(records: &[csv::StringRecord]) -> Result<Vec<u8>, String> {
+    let batch = record_batch(records)?;
+
+    let cursor = InMemoryWriteableCursor::default();
+    let mut writer = ArrowWriter::try_new(cursor.clone(), batch.schema(), None).map_err(|err| err.to_string())?;
+    writer.write(&batch).map_err(|err| err.to_string())?;
+    writer.close().map_err(|err| err.to_string())?;
+
+    Ok(cursor.data())
+}
+
+fn record_batch(records: &[csv::StringRecord]) -> Result<RecordBatch, String> {
+    let (headers, rows) = match records.split_first() {
+        Some(split) => split,
+        None => return Err(String::from("missing header row")),
+    };
+
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for (index, header) in headers.iter().enumerate() {
+        let cells = rows.iter().map(|row| row.get(index).filter(|cell| !cell.is_empty()));
+
+        if FLOAT_COLUMNS.contains(&header) {
+            fields.push(Field::new(header, DataType::Float64, true));
+            let values: Vec<Option<f64>> = cells.map(|cell| cell.and_then(|cell| cell.parse().ok())).collect();
+            columns.push(Arc::new(Float64Array::from(values)));
+        } else {
+            fields.push(Field::new(header, DataType::Utf8, true));
+            let values: Vec<Option<&str>> = cells.collect();
+            columns.push(Arc::new(StringArray::from(values)));
+        }
+    }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).map_err(|err| err.to_string())
+}
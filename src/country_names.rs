@@ -0,0 +1,64 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Localized country names, for the languages MaxMind databases have no names in. The ISO 3166-1
+//! country names of `data/country-names.json`, in 39 languages, are embedded with the
+//! `country-names` feature. The JSON file set in GEOIP_RS_COUNTRY_NAMES, with the same
+//! `{"<lang>": {"<country code>": "<name>"}}` shape, takes precedence over them.
+
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+
+use serde_json::Value;
+
+#[cfg(feature = "country-names")]
+static EMBEDDED: OnceLock<Value> = OnceLock::new();
+
+static OVERRIDES: OnceLock<Option<Value>> = OnceLock::new();
+
+/// The name of the country with ISO code `code` in `language`, if known.
+pub fn country_name(language: &str, code: &str) -> Option<String> {
+    overrides()
+        .and_then(|names| names[language][code].as_str())
+        .or_else(|| embedded().and_then(|names| names[language][code].as_str()))
+        .map(String::from)
+}
+
+/// Reads the names, so that a missing or invalid GEOIP_RS_COUNTRY_NAMES file stops the server at
+/// startup rather than failing its first lookups.
+pub fn load() {
+    overrides();
+    embedded();
+}
+
+fn overrides() -> Option<&'static Value> {
+    OVERRIDES
+        .get_or_init(|| {
+            let path = env::var("GEOIP_RS_COUNTRY_NAMES").ok()?;
+            let content = fs::read_to_string(&path).unwrap_or_else(|err| panic!("Can not read {}: {}", path, err));
+            Some(content.parse().unwrap_or_else(|err| panic!("Can not parse {}: {}", path, err)))
+        })
+        .as_ref()
+}
+
+#[cfg(feature = "country-names")]
+fn embedded() -> Option<&'static Value> {
+    Some(EMBEDDED.get_or_init(|| include_str!("../data/country-names.json").parse().unwrap()))
+}
+
+#[cfg(not(feature = "country-names"))]
+fn embedded() -> Option<&'static Value> {
+    None
+}
@@ -0,0 +1,137 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /debug`, an admin endpoint reporting what an instance is up to: its resident memory, how
+//! much of the memory mapped database is resident, its open sockets and threads, and the
+//! requests in flight on each worker. Figures come from /proc, so they're only there on Linux.
+//! tokio doesn't expose its task counts: the requests in flight are the queue depth of a worker.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde_json::{json, Value};
+
+use crate::admin::Admin;
+
+pub struct Diagnostics {
+    db_path: PathBuf,
+    /// Requests in flight, one counter per worker.
+    workers: Mutex<Vec<Arc<AtomicUsize>>>,
+}
+
+impl Diagnostics {
+    pub fn new<P: AsRef<Path>>(db_path: P) -> Diagnostics {
+        Diagnostics {
+            db_path: db_path.as_ref().to_path_buf(),
+            workers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Registers a worker, returning the counter of its requests in flight.
+    pub fn worker(&self) -> Arc<AtomicUsize> {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        self.workers.lock().unwrap().push(in_flight.clone());
+        in_flight
+    }
+
+    pub fn report(&self) -> Value {
+        let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+        let workers: Vec<usize> = self
+            .workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|in_flight| in_flight.load(Ordering::Relaxed))
+            .collect();
+        let mapped = mapped(&self.db_path);
+
+        json!({
+            "rssBytes": status_kb(&status, "VmRSS").map(|kb| kb * 1024),
+            "threads": status_kb(&status, "Threads"),
+            "openSockets": open_sockets(),
+            "database": {
+                "path": self.db_path.display().to_string(),
+                "mappedBytes": mapped.map(|(size, _)| size),
+                "residentBytes": mapped.map(|(_, resident)| resident),
+            },
+            "inFlight": workers.iter().sum::<usize>(),
+            "workers": workers.iter().map(|in_flight| json!({ "inFlight": in_flight })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// A request in flight, counted until it's dropped.
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn start(in_flight: &Arc<AtomicUsize>) -> InFlight {
+        in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(in_flight.clone())
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub async fn debug(req: HttpRequest, admin: web::Data<Admin>, diagnostics: web::Data<Arc<Diagnostics>>) -> HttpResponse {
+    admin.run(&req, "debug", || HttpResponse::Ok().json(diagnostics.report()))
+}
+
+/// A field of /proc/self/status, e.g. `VmRSS:  10240 kB`, without its unit.
+fn status_kb(status: &str, field: &str) -> Option<u64> {
+    status
+        .lines()
+        .find(|line| line.split(':').next() == Some(field))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|value| value.parse().ok())
+}
+
+fn open_sockets() -> Option<usize> {
+    let fds = fs::read_dir("/proc/self/fd").ok()?;
+    let sockets = fds
+        .filter_map(Result::ok)
+        .filter_map(|fd| fs::read_link(fd.path()).ok())
+        .filter(|target| target.to_string_lossy().starts_with("socket:"))
+        .count();
+    Some(sockets)
+}
+
+/// Mapped and resident bytes of the memory maps of `path`, from /proc/self/smaps. A reloaded
+/// database stays mapped until the lookups still using it are done.
+fn mapped(path: &Path) -> Option<(u64, u64)> {
+    let path = fs::canonicalize(path).ok()?;
+    let smaps = fs::read_to_string("/proc/self/smaps").ok()?;
+
+    let (mut size, mut resident) = (0, 0);
+    let mut in_mapping = false;
+    for line in smaps.lines() {
+        let mut fields = line.split_whitespace();
+        let name = fields.next().unwrap_or("");
+        let mut bytes = || fields.next().and_then(|kb| kb.parse::<u64>().ok()).unwrap_or(0) * 1024;
+        match name {
+            "Size:" if in_mapping => size += bytes(),
+            "Rss:" if in_mapping => resident += bytes(),
+            // The first line of a mapping is its address range, ending with the mapped file
+            name if !name.ends_with(':') => in_mapping = line.ends_with(path.to_str()?),
+            _ => {}
+        }
+    }
+    Some((size, resident))
+}
@@ -0,0 +1,64 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `geoip-rs download`: downloads and installs a MaxMind database.
+
+use std::env;
+use std::path::Path;
+
+use crate::cli::Args;
+use crate::updater::{self, DownloadConfig};
+
+pub const USAGE: &str = "Usage: geoip-rs download [--edition GeoLite2-City] [--license-key key] [--account-id id] [--out dir]";
+
+pub fn run(args: &Args) -> i32 {
+    let license_key = args
+        .get("license-key")
+        .map(String::from)
+        .or_else(|| env::var("GEOIP_RS_MAXMIND_LICENSE_KEY").ok());
+
+    let mut config = match license_key {
+        Some(license_key) => DownloadConfig::new(license_key),
+        None => {
+            eprintln!(
+                "You must specify the license key, either with --license-key or as GEOIP_RS_MAXMIND_LICENSE_KEY env var\n{}",
+                USAGE
+            );
+            return 2;
+        }
+    };
+
+    if let Some(account_id) = args.get("account-id") {
+        config.account_id = Some(account_id.to_string());
+    }
+    if let Some(edition) = args.get("edition") {
+        config.edition = edition.to_string();
+    }
+    if let Some(url) = args.get("url") {
+        config.base_url = url.to_string();
+    }
+
+    let out_dir = Path::new(args.get("out").unwrap_or("."));
+
+    match updater::download(&config, out_dir) {
+        Ok(db_path) => {
+            println!("Installed {}", db_path.display());
+            0
+        }
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
+    }
+}
@@ -0,0 +1,254 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `geoip-rs enrich-log`: annotates each line of a web server access log with geographical
+//! information, and the autonomous system with the ASN database of `--asn-db` or
+//! GEOIP_RS_ASN_DB_PATH, and writes the result as NDJSON.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::IpAddr;
+
+use serde_json::{Map, Value};
+
+use crate::cli::{self, Args};
+use crate::hooks::AsnDatabase;
+use crate::provider::GeoProvider;
+use crate::settings::Settings;
+
+const COMMON_FIELDS: [&str; 7] = ["host", "ident", "user", "time", "request", "status", "bytes"];
+
+const COMBINED_FIELDS: [&str; 9] = [
+    "host",
+    "ident",
+    "user",
+    "time",
+    "request",
+    "status",
+    "bytes",
+    "referer",
+    "user_agent",
+];
+
+const JSON_IP_FIELDS: [&str; 5] = ["remote_addr", "client_ip", "ip", "host", "remote_ip"];
+
+const GEO_FIELDS: [&str; 7] = [
+    "countryCode",
+    "countryName",
+    "regionName",
+    "cityName",
+    "latitude",
+    "longitude",
+    "timeZone",
+];
+
+pub const USAGE: &str = "Usage: geoip-rs enrich-log [--db file.mmdb] [--asn-db file.mmdb] [--format common|combined|json] [--ip-field name] [--lang lang] [access.log]";
+
+enum LogFormat {
+    Common,
+    Combined,
+    Json,
+}
+
+pub fn run(args: &Args) -> i32 {
+    let format = match args.get("format").unwrap_or("combined") {
+        "common" => LogFormat::Common,
+        "combined" => LogFormat::Combined,
+        "json" => LogFormat::Json,
+        format => {
+            eprintln!("Unknown log format {}\n{}", format, USAGE);
+            return 2;
+        }
+    };
+
+    let db = match cli::open_db(args) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    let asn_path = args.get("asn-db").map(String::from).or_else(|| env::var("GEOIP_RS_ASN_DB_PATH").ok());
+    let asn = match asn_path.as_deref().map(AsnDatabase::open).transpose() {
+        Ok(asn) => asn,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    let input: Box<dyn BufRead> = match args.positional.first().map(String::as_str) {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("Can not open {}: {}", path, err);
+                return 1;
+            }
+        },
+    };
+
+    let settings = Settings::from_env();
+    let language = args.get("lang").unwrap_or("en");
+    let ip_field = args.get("ip-field");
+
+    let stdout = io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+
+    let mut skipped = 0;
+    for (line_number, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Can not read line {}: {}", line_number + 1, err);
+                return 1;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let parsed = match format {
+            LogFormat::Common => parse_log_line(&line, &COMMON_FIELDS),
+            LogFormat::Combined => parse_log_line(&line, &COMBINED_FIELDS),
+            LogFormat::Json => parse_json_line(&line),
+        };
+
+        let mut entry = match parsed {
+            Some(entry) => entry,
+            None => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        let ip_address = entry_ip(&entry, ip_field).unwrap_or_default();
+        entry.insert(String::from("geo"), geo(&db, asn.as_ref(), &settings, &ip_address, language));
+
+        if writeln!(output, "{}", Value::Object(entry)).is_err() {
+            return 1;
+        }
+    }
+
+    if skipped > 0 {
+        eprintln!("Skipped {} unparsable lines", skipped);
+    }
+
+    0
+}
+
+fn entry_ip(entry: &Map<String, Value>, ip_field: Option<&str>) -> Option<String> {
+    match ip_field {
+        Some(ip_field) => entry.get(ip_field).and_then(Value::as_str).map(String::from),
+        None => JSON_IP_FIELDS
+            .iter()
+            .filter_map(|field| entry.get(*field).and_then(Value::as_str))
+            .next()
+            .map(String::from),
+    }
+}
+
+fn geo<P: GeoProvider>(
+    db: &P,
+    asn: Option<&AsnDatabase>,
+    settings: &Settings,
+    ip_address: &str,
+    language: &str,
+) -> Value {
+    let geoip = crate::resolver::resolve(db, &settings.resolver, ip_address.trim(), language);
+    let mut geo: Map<String, Value> = match geoip.get("countryCode") {
+        Some(_) => GEO_FIELDS
+            .iter()
+            .filter_map(|field| geoip.get(*field).map(|value| (field.to_string(), value.clone())))
+            .collect(),
+        None => Map::new(),
+    };
+
+    // asn, asOrganization, and isp and organization with an ISP database
+    let ip = ip_address.trim().parse::<IpAddr>().ok();
+    if let (Some(asn), Some(ip)) = (asn, ip) {
+        match asn.lookup(ip) {
+            Ok(fields) => geo.extend(fields.into_iter().flatten()),
+            Err(err) => eprintln!("Can not look up {} in the ASN database: {}", ip, err),
+        }
+    }
+
+    if geo.is_empty() {
+        return Value::Null;
+    }
+    Value::Object(geo)
+}
+
+fn parse_json_line(line: &str) -> Option<Map<String, Value>> {
+    match serde_json::from_str(line) {
+        Ok(Value::Object(entry)) => Some(entry),
+        _ => None,
+    }
+}
+
+/// Parses a line in NCSA common or combined log format, e.g.
+/// `127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08"`
+fn parse_log_line(line: &str, names: &[&str]) -> Option<Map<String, Value>> {
+    let fields = split_log_line(line);
+    if fields.len() < names.len() {
+        return None;
+    }
+
+    let entry = names
+        .iter()
+        .zip(fields.into_iter())
+        .map(|(name, field)| (name.to_string(), Value::String(field)))
+        .collect();
+
+    Some(entry)
+}
+
+fn split_log_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut chars = line.trim_end().chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' => {
+                chars.next();
+            }
+            '[' => {
+                chars.next();
+                fields.push(chars.by_ref().take_while(|&c| c != ']').collect());
+            }
+            '"' => {
+                chars.next();
+                let mut field = String::new();
+                let mut escaped = false;
+                for c in chars.by_ref() {
+                    match c {
+                        _ if escaped => {
+                            field.push(c);
+                            escaped = false;
+                        }
+                        '\\' => escaped = true,
+                        '"' => break,
+                        _ => field.push(c),
+                    }
+                }
+                fields.push(field);
+            }
+            _ => fields.push(chars.by_ref().take_while(|&c| c != ' ').collect()),
+        }
+    }
+
+    fields
+}
@@ -0,0 +1,331 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lookups escalated to an upstream, the MaxMind web services or another geoip-rs instance, for
+//! the IP addresses the local database has no record of, or whose record lacks some fields.
+//! Upstreams answer with the same models of the database records, so their answers are used in
+//! their place.
+//!
+//! Upstreams are behind a circuit breaker: after GEOIP_RS_UPSTREAM_FAILURES consecutive failures
+//! (5 by default, 0 to disable it) they're not asked for GEOIP_RS_UPSTREAM_OPEN_SECS (30 by
+//! default), and lookups are answered with the local records alone instead of waiting on an
+//! upstream that's down. Then a single lookup probes the upstream, closing the circuit again if it
+//! succeeds.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use maxminddb::MaxMindDBError;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::provider::{DbMetadata, Edition, GeoProvider};
+use crate::remote::Remote;
+use crate::web_service::WebService;
+
+const DEFAULT_FAILURES: u32 = 5;
+const DEFAULT_OPEN_SECS: u64 = 30;
+
+pub trait Upstream: Send + Sync {
+    /// The record of `ip` according to the upstream, `None` when it has none, or can't be asked
+    /// right now.
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String>;
+
+    /// Top level fields of the records, e.g. `city`, without which the upstream is asked.
+    fn required(&self) -> &[String];
+}
+
+/// The upstream set in the env, if any.
+pub fn from_env() -> Result<Option<Arc<dyn Upstream>>, String> {
+    let upstream: Box<dyn Upstream> = match (Remote::from_env()?, WebService::from_env()?) {
+        (Some(_), Some(_)) => {
+            return Err(String::from("GEOIP_RS_REMOTE and GEOIP_RS_WEB_SERVICE can not be both set"))
+        }
+        (Some(remote), None) => Box::new(remote),
+        (None, Some(web_service)) => Box::new(web_service),
+        (None, None) => return Ok(None),
+    };
+    let failures = match env::var("GEOIP_RS_UPSTREAM_FAILURES") {
+        Ok(failures) => failures
+            .parse()
+            .map_err(|_| format!("GEOIP_RS_UPSTREAM_FAILURES must be a number, got {}", failures))?,
+        Err(_) => DEFAULT_FAILURES,
+    };
+    let open_secs = match env::var("GEOIP_RS_UPSTREAM_OPEN_SECS") {
+        Ok(secs) => secs
+            .parse()
+            .map_err(|_| format!("GEOIP_RS_UPSTREAM_OPEN_SECS must be a number of seconds, got {}", secs))?,
+        Err(_) => DEFAULT_OPEN_SECS,
+    };
+
+    if failures == 0 {
+        return Ok(Some(Arc::from(upstream)));
+    }
+    Ok(Some(Arc::new(Breaker {
+        upstream,
+        failures,
+        open_for: Duration::from_secs(open_secs),
+        circuit: Mutex::new(Circuit::Closed { failures: 0 }),
+    })))
+}
+
+/// The comma separated fields in the `name` env var.
+pub fn required_fields(name: &str) -> Vec<String> {
+    env::var(name)
+        .map(|fields| {
+            fields
+                .split(',')
+                .map(|field| field.trim().to_string())
+                .filter(|field| !field.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Answers of an upstream by IP address, `None` for the ones it has no record of either, kept
+/// for `ttl` so that an IP address looked up again, or decoded into several records, is asked
+/// once. Past `capacity` answers, the least recently used is evicted first.
+pub struct Answers {
+    capacity: usize,
+    ttl: Duration,
+    answers: Mutex<AnswerEntries>,
+}
+
+struct Answer {
+    asked: Instant,
+    record: Option<Value>,
+    generation: u64,
+}
+
+#[derive(Default)]
+struct AnswerEntries {
+    map: HashMap<IpAddr, Answer>,
+    /// IP addresses from the least to the most recently used, skipped once they're used again or
+    /// dropped, as in [crate::cache].
+    order: VecDeque<(IpAddr, u64)>,
+    generation: u64,
+}
+
+impl Answers {
+    pub fn new(capacity: usize, ttl: Duration) -> Answers {
+        Answers {
+            capacity,
+            ttl,
+            answers: Mutex::new(AnswerEntries::default()),
+        }
+    }
+
+    /// The answer for `ip`, unless there's none younger than the TTL.
+    pub fn get(&self, ip: IpAddr) -> Option<Option<Value>> {
+        let mut answers = self.answers.lock().unwrap();
+        let fresh = answers.map.get(&ip)?.asked.elapsed() < self.ttl;
+        if !fresh {
+            answers.map.remove(&ip);
+            return None;
+        }
+
+        answers.generation += 1;
+        let generation = answers.generation;
+        answers.order.push_back((ip, generation));
+        let record = answers.map.get_mut(&ip).map(|answer| {
+            answer.generation = generation;
+            answer.record.clone()
+        });
+        self.compact(&mut answers);
+        record
+    }
+
+    pub fn insert(&self, ip: IpAddr, record: Option<Value>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut answers = self.answers.lock().unwrap();
+        while answers.map.len() >= self.capacity && !answers.map.contains_key(&ip) {
+            let (evicted, generation) = match answers.order.pop_front() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            if answers.map.get(&evicted).map_or(false, |answer| answer.generation == generation) {
+                answers.map.remove(&evicted);
+            }
+        }
+
+        answers.generation += 1;
+        let generation = answers.generation;
+        answers.order.push_back((ip, generation));
+        answers.map.insert(
+            ip,
+            Answer {
+                asked: Instant::now(),
+                record,
+                generation,
+            },
+        );
+        self.compact(&mut answers);
+    }
+
+    /// Keeps the queue from growing with the IP addresses used again or dropped as stale.
+    fn compact(&self, answers: &mut AnswerEntries) {
+        if answers.order.len() > self.capacity * 2 {
+            let AnswerEntries { map, order, .. } = answers;
+            order.retain(|(ip, generation)| map.get(ip).map_or(false, |answer| answer.generation == *generation));
+        }
+    }
+}
+
+/// A provider falling back to an upstream.
+pub struct Fallback<P> {
+    db: Arc<P>,
+    upstream: Arc<dyn Upstream>,
+}
+
+impl<P> Fallback<P> {
+    pub fn new(db: Arc<P>, upstream: Arc<dyn Upstream>) -> Fallback<P> {
+        Fallback { db, upstream }
+    }
+}
+
+impl<P: GeoProvider> GeoProvider for Fallback<P> {
+    fn lookup<T: DeserializeOwned>(&self, ip: IpAddr) -> Result<T, MaxMindDBError> {
+        let decode = |record: Value| {
+            serde_json::from_value(record).map_err(|err| MaxMindDBError::DecodingError(err.to_string()))
+        };
+        let required = self.upstream.required();
+
+        // Records are decoded as they are only when their fields need to be checked
+        let local = if required.is_empty() {
+            match self.db.lookup(ip) {
+                Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+                result => return result,
+            }
+        } else {
+            match self.db.lookup::<Value>(ip) {
+                Ok(record) if complete(required, &record) => return decode(record),
+                Ok(record) => Some(record),
+                Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let upstream = self.upstream.lookup(ip).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            None
+        });
+        match upstream.or(local) {
+            Some(record) => decode(record),
+            None => Err(MaxMindDBError::AddressNotFoundError(String::from("Address not found in database"))),
+        }
+    }
+
+    fn metadata(&self) -> DbMetadata {
+        self.db.metadata()
+    }
+
+    fn build_epoch(&self) -> u64 {
+        self.db.build_epoch()
+    }
+
+    /// The upstream has the city records a country database lacks.
+    fn edition(&self) -> Edition {
+        match self.db.edition() {
+            Edition::Country => Edition::City,
+            edition => edition,
+        }
+    }
+
+    /// The upstream can have them whatever the edition of the database, e.g. the insights web
+    /// service.
+    fn has_extras(&self) -> bool {
+        true
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        self.db.reload()
+    }
+}
+
+/// Whether a local record has all the required fields.
+fn complete(required: &[String], record: &Value) -> bool {
+    required.iter().all(|field| record.get(field).map_or(false, |value| !value.is_null()))
+}
+
+enum Circuit {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    /// A lookup is probing the upstream.
+    HalfOpen,
+}
+
+/// An upstream behind a circuit breaker.
+struct Breaker {
+    upstream: Box<dyn Upstream>,
+    /// Consecutive failures opening the circuit.
+    failures: u32,
+    open_for: Duration,
+    circuit: Mutex<Circuit>,
+}
+
+impl Breaker {
+    /// Whether the upstream can be asked, turning an open circuit into a half open one once it's
+    /// been open long enough.
+    fn ask(&self) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        match *circuit {
+            Circuit::Closed { .. } => true,
+            Circuit::Open { until } if Instant::now() >= until => {
+                *circuit = Circuit::HalfOpen;
+                true
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen => false,
+        }
+    }
+
+    fn answered(&self, ok: bool) {
+        let mut circuit = self.circuit.lock().unwrap();
+        *circuit = match (&*circuit, ok) {
+            (Circuit::Closed { .. }, true) => Circuit::Closed { failures: 0 },
+            (_, true) => {
+                println!("The upstream is back, closing the circuit");
+                Circuit::Closed { failures: 0 }
+            }
+            (Circuit::Closed { failures }, false) if failures + 1 < self.failures => {
+                Circuit::Closed { failures: failures + 1 }
+            }
+            (_, false) => {
+                eprintln!("The upstream is failing, opening the circuit for {}s", self.open_for.as_secs());
+                Circuit::Open {
+                    until: Instant::now() + self.open_for,
+                }
+            }
+        };
+    }
+}
+
+impl Upstream for Breaker {
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        if !self.ask() {
+            return Ok(None);
+        }
+        let answer = self.upstream.lookup(ip);
+        self.answered(answer.is_ok());
+        answer
+    }
+
+    fn required(&self) -> &[String] {
+        self.upstream.required()
+    }
+}
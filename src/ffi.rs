@@ -0,0 +1,99 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! C API of the resolver, declared in include/geoip_rs.h.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::panic;
+use std::ptr;
+use std::sync::{Arc, RwLock};
+
+use maxminddb::Reader;
+use memmap::Mmap;
+
+use crate::resolver;
+
+static READER: RwLock<Option<Arc<Reader<Mmap>>>> = RwLock::new(None);
+
+/// Opens the database at `path`, replacing the one opened before, if any. Returns 0, or -1 when
+/// the database can't be opened.
+///
+/// # Safety
+///
+/// `path` must be a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn geoip_open(path: *const c_char) -> c_int {
+    let path = match str_arg(path) {
+        Some(path) => path,
+        None => return -1,
+    };
+    match Reader::open_mmap(path) {
+        Ok(reader) => {
+            *READER.write().unwrap_or_else(|err| err.into_inner()) = Some(Arc::new(reader));
+            0
+        }
+        Err(_) => -1,
+    }
+}
+
+/// Resolves `ip` with the names in `lang`, returning the JSON body `/{ip}` would return: IP
+/// addresses without a record have their `ip_address` and the `error` telling why. Returns NULL
+/// when no database is open. The string must be released with `geoip_free_string`.
+///
+/// # Safety
+///
+/// `ip` and `lang` must be NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn geoip_lookup(ip: *const c_char, lang: *const c_char) -> *mut c_char {
+    let (ip_address, lang) = match (str_arg(ip), str_arg(lang)) {
+        (Some(ip_address), Some(lang)) => (ip_address, lang),
+        _ => return ptr::null_mut(),
+    };
+    let reader = match READER.read().unwrap_or_else(|err| err.into_inner()).clone() {
+        Some(reader) => reader,
+        None => return ptr::null_mut(),
+    };
+
+    match panic::catch_unwind(|| resolver::resolve(reader.as_ref(), &resolver::Options::default(), ip_address.trim(), lang).to_string()) {
+        Ok(json) => CString::new(json).map(CString::into_raw).unwrap_or(ptr::null_mut()),
+        Err(_) => ptr::null_mut(),
+    }
+}
+
+/// Releases a string returned by `geoip_lookup`.
+///
+/// # Safety
+///
+/// `json` must be NULL or returned by `geoip_lookup`, and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn geoip_free_string(json: *mut c_char) {
+    if !json.is_null() {
+        drop(CString::from_raw(json));
+    }
+}
+
+/// Closes the database opened by `geoip_open`. Lookups return NULL afterwards.
+#[no_mangle]
+pub extern "C" fn geoip_close() {
+    *READER.write().unwrap_or_else(|err| err.into_inner()) = None;
+}
+
+unsafe fn str_arg<'a>(arg: *const c_char) -> Option<&'a str> {
+    if arg.is_null() {
+        return None;
+    }
+
+    CStr::from_ptr(arg).to_str().ok()
+}
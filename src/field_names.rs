@@ -0,0 +1,70 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Operator defined names of the fields of the responses, for clients with a fixed schema, e.g.
+//! the one of the provider they're migrating from. Listed in the GEOIP_RS_FIELD_NAMES JSON file:
+//! `{"rename": {"countryCode": "country_iso"}, "alias": {"cityName": "city"}}` writes
+//! `countryCode` as `country_iso` only, and `cityName` as both `cityName` and `city`.
+//!
+//! Names are changed when the responses are serialized: hooks, API key field restrictions and
+//! templates all see the original names.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+
+use serde_json::Value;
+
+#[derive(Deserialize, Default)]
+pub struct FieldNames {
+    #[serde(default)]
+    rename: BTreeMap<String, String>,
+    #[serde(default)]
+    alias: BTreeMap<String, String>,
+}
+
+impl FieldNames {
+    pub fn from_env() -> Result<FieldNames, String> {
+        let path = match env::var("GEOIP_RS_FIELD_NAMES") {
+            Ok(path) => path,
+            Err(_) => return Ok(FieldNames::default()),
+        };
+        let content = fs::read_to_string(&path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+        serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", path, err))
+    }
+
+    /// Renames the fields of a response body, or of each body of an array of responses.
+    pub fn apply(&self, response: &mut Value) {
+        if self.rename.is_empty() && self.alias.is_empty() {
+            return;
+        }
+
+        match response {
+            Value::Array(responses) => responses.iter_mut().for_each(|response| self.apply(response)),
+            Value::Object(body) => {
+                for (name, alias) in &self.alias {
+                    if let Some(value) = body.get(name).cloned() {
+                        body.insert(alias.clone(), value);
+                    }
+                }
+                for (name, renamed) in &self.rename {
+                    if let Some(value) = body.remove(name) {
+                        body.insert(renamed.clone(), value);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,145 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /fields`: the fields the responses can have with the current configuration, given the
+//! loaded database and the enabled enrichments, with their types and what supplies them, for
+//! clients building their field selection dynamically.
+
+use serde_json::{json, Map, Value};
+
+use crate::hooks::Hooks;
+use crate::provider::{DbMetadata, Edition};
+use crate::settings::Settings;
+
+/// What a field comes from.
+enum Supplier {
+    /// The request itself.
+    Request,
+    /// Any database, country ones included.
+    Country,
+    /// City and Enterprise databases.
+    City,
+    /// Enterprise databases.
+    Enterprise,
+    /// A setting, enabled by the env var.
+    Setting(&'static str),
+}
+
+struct Field {
+    name: &'static str,
+    kind: &'static str,
+    supplier: Supplier,
+    endpoints: &'static [&'static str],
+}
+
+const ALL: &[&str] = &["index", "country", "city"];
+const INDEX: &[&str] = &["index"];
+const INDEX_COUNTRY: &[&str] = &["index", "country"];
+const INDEX_CITY: &[&str] = &["index", "city"];
+
+const FIELDS: &[Field] = &[
+    field("ipAddress", "string", Supplier::Request, ALL),
+    field("latitude", "number", Supplier::City, INDEX_CITY),
+    field("longitude", "number", Supplier::City, INDEX_CITY),
+    field("locationPrecision", "string", Supplier::Setting("GEOIP_RS_COUNTRY_CENTROIDS"), INDEX_CITY),
+    field("postalCode", "string", Supplier::City, INDEX_CITY),
+    field("continentCode", "string", Supplier::Country, INDEX_COUNTRY),
+    field("continentName", "string", Supplier::Country, INDEX_COUNTRY),
+    field("countryCode", "string", Supplier::Country, ALL),
+    field("countryLabel", "string", Supplier::Country, INDEX_COUNTRY),
+    field("countryName", "string", Supplier::Country, INDEX_COUNTRY),
+    field("regionCode", "string", Supplier::City, INDEX_CITY),
+    field("regionName", "string", Supplier::City, INDEX_CITY),
+    field("provinceCode", "string", Supplier::City, INDEX_CITY),
+    field("provinceName", "string", Supplier::City, INDEX_CITY),
+    field("cityName", "string", Supplier::City, INDEX_CITY),
+    field("timeZone", "string", Supplier::City, INDEX_CITY),
+    field("isAnonymousProxy", "boolean", Supplier::Country, INDEX_COUNTRY),
+    field("isSatelliteProvider", "boolean", Supplier::Country, INDEX_COUNTRY),
+    field("tunnel", "string", Supplier::Setting("GEOIP_RS_RESOLVE_TUNNELS"), ALL),
+    field("countryConfidence", "number", Supplier::Enterprise, INDEX),
+    field("cityConfidence", "number", Supplier::Enterprise, INDEX),
+    field("postalConfidence", "number", Supplier::Enterprise, INDEX),
+    field("userType", "string", Supplier::Enterprise, INDEX),
+    field("autonomousSystemNumber", "number", Supplier::Enterprise, INDEX),
+    field("ipSource", "string", Supplier::Setting("GEOIP_RS_IP_SOURCE"), INDEX),
+    field("ipChain", "array", Supplier::Setting("GEOIP_RS_IP_SOURCE"), INDEX),
+    field("elapsedMs", "number", Supplier::Setting("GEOIP_RS_TIMING"), ALL),
+    field("snapshotDate", "string", Supplier::Setting("GEOIP_RS_SNAPSHOTS"), ALL),
+];
+
+const fn field(
+    name: &'static str,
+    kind: &'static str,
+    supplier: Supplier,
+    endpoints: &'static [&'static str],
+) -> Field {
+    Field {
+        name,
+        kind,
+        supplier,
+        endpoints,
+    }
+}
+
+/// `{name: {type, source, endpoints}}` for each field the responses can have. `source` is the
+/// type of the database, `request`, the env var of a setting, `hook` for enrichments, or
+/// `upstream` for the fields only the upstream has, when there's one.
+pub fn describe(
+    metadata: &DbMetadata,
+    settings: &Settings,
+    hooks: &Hooks,
+    upstream: bool,
+    snapshots: bool,
+) -> Map<String, Value> {
+    let database = metadata.database_type.as_str();
+    let edition = Edition::of(database);
+    let city = edition != Edition::Country;
+    let enterprise = edition == Edition::Enterprise;
+    let enabled = |name: &str| match name {
+        "GEOIP_RS_RESOLVE_TUNNELS" => settings.resolver.resolve_tunnels,
+        "GEOIP_RS_COUNTRY_CENTROIDS" => settings.resolver.country_centroids,
+        "GEOIP_RS_IP_SOURCE" => settings.ip_source,
+        "GEOIP_RS_TIMING" => settings.timing,
+        "GEOIP_RS_SNAPSHOTS" => snapshots,
+        _ => false,
+    };
+
+    let mut fields = Map::new();
+    for field in FIELDS {
+        let source = match field.supplier {
+            Supplier::Request => "request",
+            Supplier::Country => database,
+            Supplier::City if city => database,
+            Supplier::Enterprise if enterprise => database,
+            Supplier::City | Supplier::Enterprise if upstream => "upstream",
+            Supplier::Setting(name) if enabled(name) => name,
+            _ => continue,
+        };
+        fields.insert(field.name.to_string(), describe_field(field.kind, source, field.endpoints));
+    }
+    for name in hooks.fields() {
+        fields.insert(name.to_string(), describe_field("string", "hook", ALL));
+    }
+
+    fields
+}
+
+fn describe_field(kind: &str, source: &str, endpoints: &[&str]) -> Value {
+    json!({
+        "type": kind,
+        "source": source,
+        "endpoints": endpoints,
+    })
+}
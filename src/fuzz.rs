@@ -0,0 +1,62 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Entry points of the fuzz targets in fuzz/, over the parsing of the input of the callers. Only
+//! built by `cargo fuzz`, which sets `--cfg fuzzing`.
+
+use std::net::SocketAddr;
+
+use actix_web::http::{HeaderName, HeaderValue};
+use actix_web::test::TestRequest;
+
+use crate::client_ip::ClientIpPicker;
+use crate::server;
+
+/// Picks the IP address to resolve out of the headers in `input`, one per line, e.g.
+/// `X-Real-IP: 203.0.113.7`.
+pub fn client_ip(input: &str) {
+    let mut req = TestRequest::default().peer_addr(SocketAddr::from(([10, 0, 0, 1], 4711)));
+    for line in input.lines() {
+        let (name, value) = match line.find(':') {
+            Some(colon) => (&line[..colon], line[colon + 1..].trim()),
+            None => continue,
+        };
+        if HeaderName::from_bytes(name.as_bytes()).is_err() {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value) {
+            req = req.header(name, value);
+        }
+    }
+    let req = req.to_http_request();
+
+    ClientIpPicker::default().client_ip(None, req.headers(), req.peer_addr()).ok();
+}
+
+/// Validates a JSONP callback.
+pub fn callback(callback: &str) -> bool {
+    server::valid_callback(callback)
+}
+
+/// Parses a query string, e.g. `ip=203.0.113.7&lang=pt-BR`, and its language.
+pub fn lang(query_string: &str) {
+    let (query, _) = server::parse_query(query_string);
+    let _ = server::get_language(query.lang, &[String::from("en"), String::from("pt-BR")]);
+}
+
+/// Parses the body of `POST /` in both of its content types.
+pub fn batch_body(body: &[u8]) {
+    let _ = server::parse_body("application/json", body);
+    let _ = server::parse_body("application/x-www-form-urlencoded", body);
+}
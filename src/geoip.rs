@@ -0,0 +1,93 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lookups off the async executor.
+
+use std::net::IpAddr;
+use std::panic;
+use std::path::Path;
+use std::sync::Arc;
+
+use maxminddb::MaxMindDBError;
+use serde_json::Value;
+#[cfg(feature = "sync")]
+use tokio::runtime::{Builder, Runtime};
+
+use crate::provider::MaxMindProvider;
+use crate::resolver;
+
+/// A database shared by the tasks of a tokio runtime. Reading a memory mapped database can page
+/// fault on cold pages, so `lookup_async` reads it on the blocking pool of the runtime the caller
+/// is running on.
+#[derive(Clone)]
+pub struct GeoIp {
+    db: Arc<MaxMindProvider>,
+}
+
+impl GeoIp {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GeoIp, MaxMindDBError> {
+        Ok(GeoIp::new(MaxMindProvider::open(path)?))
+    }
+
+    pub fn new(db: MaxMindProvider) -> GeoIp {
+        GeoIp { db: Arc::new(db) }
+    }
+
+    /// Resolves `ip` on the current thread into the JSON body `/{ip}` would return.
+    pub fn lookup(&self, ip: IpAddr, lang: &str) -> Value {
+        resolver::resolve(self.db.as_ref(), &resolver::Options::default(), &ip.to_string(), lang)
+    }
+
+    /// Resolves `ip` on the blocking pool, see `lookup`. Must be called within a tokio runtime.
+    pub async fn lookup_async(&self, ip: IpAddr, lang: &str) -> Value {
+        let geoip = self.clone();
+        let lang = lang.to_string();
+
+        match tokio::task::spawn_blocking(move || geoip.lookup(ip, &lang)).await {
+            Ok(geoip) => geoip,
+            Err(err) => panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+/// `GeoIp` for callers outside of a tokio runtime, e.g. command line tools: lookups block the
+/// calling thread on a runtime of its own, which only has the blocking pool to run them.
+#[cfg(feature = "sync")]
+pub struct GeoIpSync {
+    geoip: GeoIp,
+    runtime: Runtime,
+}
+
+#[cfg(feature = "sync")]
+impl GeoIpSync {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<GeoIpSync, String> {
+        let geoip = GeoIp::open(path).map_err(|err| format!("Can not open the database: {}", err))?;
+
+        GeoIpSync::new(geoip)
+    }
+
+    pub fn new(geoip: GeoIp) -> Result<GeoIpSync, String> {
+        let runtime = Builder::new()
+            .basic_scheduler()
+            .build()
+            .map_err(|err| format!("Can not start the lookup runtime: {}", err))?;
+
+        Ok(GeoIpSync { geoip, runtime })
+    }
+
+    /// Resolves `ip`, see `GeoIp::lookup_async`. Must not be called within a tokio runtime.
+    pub fn lookup(&self, ip: IpAddr, lang: &str) -> Value {
+        self.runtime.handle().block_on(self.geoip.lookup_async(ip, lang))
+    }
+}
@@ -0,0 +1,120 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! gRPC service of `proto/geoip.proto`, enabled with the `grpc` feature and GEOIP_RS_GRPC_PORT:
+//! `Resolve` answers an `IpRequest` with a `Location`, `ResolveStream` answers each request of a
+//! stream as it comes. It runs on a tokio runtime of its own, next to the actix workers. The API
+//! key, when there are [API keys](crate::api_keys), is sent in the `x-api-key` metadata or as an
+//! `authorization: Bearer` token.
+
+use std::io;
+use std::net::TcpListener as StdTcpListener;
+use std::pin::Pin;
+use std::thread;
+
+use futures::{Stream, StreamExt};
+use serde_json::Value;
+use tokio::net::TcpListener;
+use tonic::transport::Server;
+use tonic::metadata::MetadataMap;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::proto::Location;
+use crate::provider::GeoProvider;
+use crate::server::Db;
+
+tonic::include_proto!("geoip");
+
+use self::geo_ip_server::{GeoIp, GeoIpServer};
+
+/// Binds `addr` and serves the gRPC requests on a dedicated thread.
+pub fn spawn<P: GeoProvider>(addr: &str, db: Db<P>) -> io::Result<()> {
+    let listener = StdTcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let mut runtime = tokio::runtime::Builder::new().threaded_scheduler().enable_all().build()?;
+
+    thread::spawn(move || {
+        let served = runtime.block_on(async move {
+            let mut listener = TcpListener::from_std(listener).map_err(|err| err.to_string())?;
+            Server::builder()
+                .add_service(GeoIpServer::new(Service { db }))
+                .serve_with_incoming(listener.incoming())
+                .await
+                .map_err(|err| err.to_string())
+        });
+        if let Err(err) = served {
+            eprintln!("gRPC server error: {}", err);
+        }
+    });
+
+    Ok(())
+}
+
+struct Service<P: GeoProvider> {
+    db: Db<P>,
+}
+
+/// The API key of the request metadata, answering `UNAUTHENTICATED` when the key is unknown, or
+/// when there's none and a key is required.
+fn api_key<P: GeoProvider>(db: &Db<P>, metadata: &MetadataMap) -> Result<Option<String>, Status> {
+    let key = metadata
+        .get("x-api-key")
+        .and_then(|value| value.to_str().ok())
+        .or_else(|| {
+            metadata
+                .get("authorization")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.strip_prefix("Bearer "))
+        })
+        .map(str::trim);
+    db.keys().check_key(key).map_err(Status::unauthenticated)
+}
+
+/// The location of the IP address of `request`, in english without a language.
+fn resolve<P: GeoProvider>(db: &Db<P>, key: Option<&str>, request: &IpRequest) -> Result<Location, Status> {
+    let language = if request.language.is_empty() { "en" } else { &request.language };
+    let mut geoip = db.lookup_protobuf(&request.ip_address, language);
+
+    match geoip.get("error").and_then(Value::as_str) {
+        Some("invalid_ip") => Err(Status::invalid_argument(format!("invalid IP address {}", request.ip_address))),
+        _ => {
+            if let Some(key) = key {
+                db.keys().restrict(key, &mut geoip);
+            }
+            Ok(Location::from(&geoip))
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl<P: GeoProvider> GeoIp for Service<P> {
+    async fn resolve(&self, request: Request<IpRequest>) -> Result<Response<Location>, Status> {
+        let key = api_key(&self.db, request.metadata())?;
+        resolve(&self.db, key.as_deref(), request.get_ref()).map(Response::new)
+    }
+
+    type ResolveStreamStream = Pin<Box<dyn Stream<Item = Result<Location, Status>> + Send + Sync + 'static>>;
+
+    async fn resolve_stream(
+        &self,
+        request: Request<Streaming<IpRequest>>,
+    ) -> Result<Response<Self::ResolveStreamStream>, Status> {
+        let key = api_key(&self.db, request.metadata())?;
+        let db = self.db.clone();
+        let locations = request
+            .into_inner()
+            .map(move |request| request.and_then(|request| resolve(&db, key.as_deref(), &request)));
+        Ok(Response::new(Box::pin(locations)))
+    }
+}
@@ -0,0 +1,92 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Upgrades of the geoip-rs binary without dropping connections, like nginx does: on SIGUSR2, the
+//! server runs its binary again, the upgraded one, handing over its listening socket in the
+//! GEOIP_RS_LISTENER_FD env var. Then it stops accepting connections, and exits once the requests
+//! in flight are served. The new process accepts connections on the very same socket, so none is
+//! refused in between.
+
+use std::env;
+use std::io;
+use std::net::TcpListener;
+
+const LISTENER_FD: &str = "GEOIP_RS_LISTENER_FD";
+
+/// The listener handed over by the previous process, if any, or a new one bound to `bind`.
+pub fn listener(bind: &str) -> io::Result<TcpListener> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::FromRawFd;
+
+        if let Ok(fd) = env::var(LISTENER_FD) {
+            env::remove_var(LISTENER_FD);
+            let fd = fd
+                .parse()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} must be a number", LISTENER_FD)))?;
+            println!("Listening on the socket handed over by the previous process");
+            return Ok(unsafe { TcpListener::from_raw_fd(fd) });
+        }
+    }
+
+    TcpListener::bind(bind)
+}
+
+/// Hands `listener` over to a new process on SIGUSR2, then stops `server` gracefully.
+#[cfg(unix)]
+pub fn upgrade_on_sigusr2(listener: TcpListener, server: actix_web::dev::Server) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr2 = signal(SignalKind::user_defined2()).expect("Can not handle SIGUSR2");
+    actix_rt::spawn(async move {
+        while sigusr2.recv().await.is_some() {
+            match spawn_upgraded(&listener) {
+                Ok(pid) => {
+                    println!("Handed the listener over to process {}, stopping", pid);
+                    server.stop(true).await;
+                    return;
+                }
+                Err(err) => eprintln!("Can not run the upgraded binary: {}", err),
+            }
+        }
+    });
+}
+
+/// Runs the binary at the path it was started with, with the same arguments: that's the upgraded
+/// one, while the path of the running process is the one of the replaced file.
+#[cfg(unix)]
+fn spawn_upgraded(listener: &TcpListener) -> io::Result<u32> {
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no program name"))?;
+    let fd = listener.as_raw_fd();
+
+    let mut command = Command::new(program);
+    command.args(args).env(LISTENER_FD, fd.to_string());
+    // Sockets are closed on exec by default
+    unsafe {
+        command.pre_exec(move || {
+            let flags = libc::fcntl(fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+
+    command.spawn().map(|child| child.id())
+}
@@ -0,0 +1,99 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /health`: the server is up, and `degraded` while the downloads of the database are
+//! failing, still serving the previous one. `GET /healthz`: the process is alive, whatever the
+//! database. `GET /readyz`: the database is loaded, and lookups can be served. `GET /dbinfo`: the
+//! metadata of the loaded database.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use maxminddb::MaxMindDBError;
+use serde_json::{json, Value};
+
+use crate::provider::{Edition, GeoProvider, MaxMindProvider};
+use crate::updater::Status;
+
+/// IP address looked up by the readiness probes, found or not in every database.
+const PROBE_IP: IpAddr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+
+pub async fn health(provider: web::Data<Arc<MaxMindProvider>>, downloads: web::Data<Arc<Status>>) -> HttpResponse {
+    let downloads = downloads.state();
+    let status = if downloads.consecutive_failures > 0 { "degraded" } else { "ok" };
+
+    HttpResponse::Ok().json(json!({
+        "status": status,
+        "buildEpoch": provider.build_epoch(),
+        "downloads": downloads,
+    }))
+}
+
+/// Liveness, for the probes restarting processes that stopped answering.
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+/// Readiness: the database is loaded and a lookup reads it. Reloads swap the databases at once,
+/// so that it stays ready throughout, unless the new database can't be read.
+pub async fn readyz(provider: web::Data<Arc<MaxMindProvider>>) -> HttpResponse {
+    if !provider.is_loaded() {
+        return not_ready();
+    }
+    match provider.lookup::<Value>(PROBE_IP) {
+        Ok(_) | Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+        Err(err) => {
+            return HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, "5")
+                .json(json!({ "ready": false, "error": format!("lookups are failing: {}", err) }))
+        }
+    }
+    HttpResponse::Ok().json(json!({ "ready": true, "buildEpoch": provider.build_epoch() }))
+}
+
+pub async fn dbinfo(provider: web::Data<Arc<MaxMindProvider>>) -> HttpResponse {
+    if !provider.is_loaded() {
+        return not_ready();
+    }
+    let metadata = provider.metadata();
+    let edition = match provider.edition() {
+        Edition::Country => "country",
+        Edition::City => "city",
+        Edition::Enterprise => "enterprise",
+    };
+
+    HttpResponse::Ok().json(json!({
+        "databaseType": metadata.database_type,
+        "edition": edition,
+        "buildEpoch": metadata.build_epoch,
+        "nodeCount": metadata.node_count,
+        "languages": metadata.languages,
+        "ipVersion": metadata.ip_version,
+        "recordSize": metadata.record_size,
+        "description": metadata.description,
+        "binaryFormatVersion": format!(
+            "{}.{}",
+            metadata.binary_format_major_version, metadata.binary_format_minor_version
+        ),
+    }))
+}
+
+/// Answer to the requests needing the database, until it's loaded.
+pub fn not_ready() -> HttpResponse {
+    HttpResponse::ServiceUnavailable()
+        .header(header::RETRY_AFTER, "5")
+        .json(json!({ "ready": false, "error": "the database is not loaded yet" }))
+}
@@ -0,0 +1,446 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Response hooks: fields added to the responses after the lookup, e.g. the name of a site or
+//! the autonomous system, from data of their own reloaded along with the database. They're
+//! configured with env vars, see [Hooks::from_env].
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+
+use ipnetwork::IpNetwork;
+use maxminddb::geoip2::Isp;
+use maxminddb::MaxMindDBError;
+use serde_json::{Map, Value};
+
+use crate::provider::{GeoProvider, MaxMindProvider};
+
+/// Post-processes a response body before it's serialized, e.g. to add custom fields.
+pub trait ResponseHook: Send + Sync {
+    fn process(&self, ip: IpAddr, response: &mut Map<String, Value>);
+
+    /// Names of the fields added to the responses, listed by `/fields`.
+    fn fields(&self) -> &[&'static str] {
+        &[]
+    }
+
+    /// Reloads the data of the hook, along with the database.
+    fn reload(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl<H: ResponseHook + ?Sized> ResponseHook for Arc<H> {
+    fn process(&self, ip: IpAddr, response: &mut Map<String, Value>) {
+        (**self).process(ip, response)
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        (**self).fields()
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        (**self).reload()
+    }
+}
+
+/// The hooks run, in order, on every response.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    hooks: Vec<Arc<dyn ResponseHook>>,
+    /// The ASN database among the hooks, also answering `/asn/{ip}`.
+    asn: Option<Arc<AsnDatabase>>,
+    /// The Anonymous IP database among the hooks, also a signal of `/risk`.
+    anonymous: Option<Arc<AnonymousIpDatabase>>,
+}
+
+impl Hooks {
+    /// Builds the hooks configured with env vars: GEOIP_RS_SITES enables [SiteNames],
+    /// GEOIP_RS_REGION_GROUPS [RegionGroups], GEOIP_RS_ASN_DB_PATH [AsnDatabase],
+    /// GEOIP_RS_ASN_ORGANIZATIONS [AsnOrganizations], GEOIP_RS_ANON_DB_PATH [AnonymousIpDatabase].
+    pub fn from_env() -> Result<Hooks, String> {
+        let mut hooks = Hooks::default();
+
+        if let Ok(path) = env::var("GEOIP_RS_SITES") {
+            hooks.add(SiteNames::from_file(&path)?);
+        }
+        if let Ok(path) = env::var("GEOIP_RS_REGION_GROUPS") {
+            hooks.add(RegionGroups::from_file(&path)?);
+        }
+        if let Ok(path) = env::var("GEOIP_RS_ASN_DB_PATH") {
+            let asn = Arc::new(AsnDatabase::open(&path)?);
+            hooks.add(asn.clone());
+            hooks.asn = Some(asn);
+        }
+        // After the ASN database, so that the listed organizations take precedence
+        if let Ok(path) = env::var("GEOIP_RS_ASN_ORGANIZATIONS") {
+            hooks.add(AsnOrganizations::from_file(&path)?);
+        }
+        if let Ok(path) = env::var("GEOIP_RS_ANON_DB_PATH") {
+            let anonymous = Arc::new(AnonymousIpDatabase::open(&path)?);
+            hooks.add(anonymous.clone());
+            hooks.anonymous = Some(anonymous);
+        }
+
+        Ok(hooks)
+    }
+
+    /// Adds `hook`, run after the ones already added.
+    pub fn add<H: ResponseHook + 'static>(&mut self, hook: H) {
+        self.hooks.push(Arc::new(hook));
+    }
+
+    /// Reloads the data of the hooks, keeping the current data of the ones failing.
+    pub fn reload(&self) {
+        for hook in &self.hooks {
+            if let Err(err) = hook.reload() {
+                eprintln!("Can not reload a response hook: {}", err);
+            }
+        }
+    }
+
+    /// The ASN database, with GEOIP_RS_ASN_DB_PATH.
+    pub fn asn(&self) -> Option<&AsnDatabase> {
+        self.asn.as_deref()
+    }
+
+    /// The Anonymous IP database, with GEOIP_RS_ANON_DB_PATH.
+    pub fn anonymous(&self) -> Option<&AnonymousIpDatabase> {
+        self.anonymous.as_deref()
+    }
+
+    /// Names of the fields the hooks add.
+    pub fn fields(&self) -> Vec<&'static str> {
+        self.hooks.iter().flat_map(|hook| hook.fields().iter().copied()).collect()
+    }
+
+    /// Runs the hooks on a response body, or on each body of an array of responses.
+    pub fn apply(&self, response: &mut Value) {
+        if self.hooks.is_empty() {
+            return;
+        }
+
+        match response {
+            Value::Array(responses) => responses.iter_mut().for_each(|response| self.apply(response)),
+            Value::Object(response) => {
+                let ip = response
+                    .get("ipAddress")
+                    .or_else(|| response.get("ip_address"))
+                    .and_then(Value::as_str)
+                    .and_then(|ip| ip.parse().ok());
+
+                if let Some(ip) = ip {
+                    for hook in &self.hooks {
+                        hook.process(ip, response);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Adds a `siteName` field to IP addresses belonging to the networks listed in a JSON file, e.g.
+/// `{"10.1.0.0/16": "Milan office", "10.2.0.0/16": "Dublin office"}`. The most specific network wins.
+pub struct SiteNames {
+    sites: Vec<(IpNetwork, String)>,
+}
+
+impl SiteNames {
+    pub fn from_file(path: &str) -> Result<SiteNames, String> {
+        let content = fs::read_to_string(path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+        let sites: BTreeMap<String, String> =
+            serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", path, err))?;
+
+        let mut sites = sites
+            .into_iter()
+            .map(|(network, name)| {
+                network
+                    .parse::<IpNetwork>()
+                    .map(|network| (network, name))
+                    .map_err(|err| format!("Invalid network {} in {}: {}", network, path, err))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        sites.sort_by(|(a, _), (b, _)| b.prefix().cmp(&a.prefix()));
+
+        Ok(SiteNames { sites })
+    }
+}
+
+impl ResponseHook for SiteNames {
+    fn process(&self, ip: IpAddr, response: &mut Map<String, Value>) {
+        if let Some((_, name)) = self.sites.iter().find(|(network, _)| network.contains(ip)) {
+            response.insert(String::from("siteName"), Value::String(name.to_string()));
+        }
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        &["siteName"]
+    }
+}
+
+/// Adds a `regionGroup` field with the operator defined group of the country, listed in a JSON
+/// file, e.g. `{"EMEA": ["IT", "DE", "ZA"], "APAC": ["JP", "AU"]}`. A country is in one group
+/// at most.
+pub struct RegionGroups {
+    groups: HashMap<String, String>,
+}
+
+impl RegionGroups {
+    pub fn from_file(path: &str) -> Result<RegionGroups, String> {
+        let content = fs::read_to_string(path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+        let listed: BTreeMap<String, Vec<String>> =
+            serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", path, err))?;
+
+        let mut groups = HashMap::new();
+        for (group, countries) in listed {
+            for country in countries {
+                if let Some(other) = groups.insert(country.to_uppercase(), group.clone()) {
+                    return Err(format!("{} is in both {} and {} in {}", country, other, group, path));
+                }
+            }
+        }
+
+        Ok(RegionGroups { groups })
+    }
+}
+
+impl ResponseHook for RegionGroups {
+    fn process(&self, _ip: IpAddr, response: &mut Map<String, Value>) {
+        let group = response
+            .get("countryCode")
+            .and_then(Value::as_str)
+            .and_then(|country_code| self.groups.get(country_code));
+        if let Some(group) = group {
+            let group = Value::String(group.to_string());
+            response.insert(String::from("regionGroup"), group);
+        }
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        &["regionGroup"]
+    }
+}
+
+/// Adds the `asn` and `asOrganization` fields from a GeoLite2 ASN or GeoIP2 ISP database, and the
+/// `isp` and `organization` fields from the latter. The database is reloaded along with the main
+/// one.
+pub struct AsnDatabase {
+    provider: MaxMindProvider,
+}
+
+impl AsnDatabase {
+    pub fn open(path: &str) -> Result<AsnDatabase, String> {
+        let provider = MaxMindProvider::open(path).map_err(|err| format!("Can not open {}: {}", path, err))?;
+        Ok(AsnDatabase { provider })
+    }
+
+    /// The fields of `ip`, none when the database has no record of it.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Map<String, Value>>, MaxMindDBError> {
+        let isp: Isp = match self.provider.lookup(ip) {
+            Ok(isp) => isp,
+            Err(MaxMindDBError::AddressNotFoundError(_)) => return Ok(None),
+            Err(err) => return Err(err),
+        };
+
+        let mut fields = Map::new();
+        if let Some(asn) = isp.autonomous_system_number {
+            fields.insert(String::from("asn"), Value::from(asn));
+        }
+        let names = [
+            ("asOrganization", isp.autonomous_system_organization),
+            ("isp", isp.isp),
+            ("organization", isp.organization),
+        ];
+        for (field, name) in names.iter() {
+            if let Some(name) = name.as_ref().filter(|name| !name.is_empty()) {
+                fields.insert(String::from(*field), Value::from(name.as_str()));
+            }
+        }
+        Ok(Some(fields))
+    }
+}
+
+impl ResponseHook for AsnDatabase {
+    fn process(&self, ip: IpAddr, response: &mut Map<String, Value>) {
+        match self.lookup(ip) {
+            Ok(fields) => response.extend(fields.into_iter().flatten()),
+            Err(err) => eprintln!("Can not look up {} in {}: {}", ip, self.provider.path().display(), err),
+        }
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        &["asn", "asOrganization", "isp", "organization"]
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        self.provider.reload()
+    }
+}
+
+/// Record of a GeoIP2 Anonymous IP database.
+#[derive(Deserialize, Default)]
+pub struct AnonymousIp {
+    pub is_anonymous: Option<bool>,
+    pub is_anonymous_vpn: Option<bool>,
+    pub is_hosting_provider: Option<bool>,
+    pub is_public_proxy: Option<bool>,
+    pub is_residential_proxy: Option<bool>,
+    pub is_tor_exit_node: Option<bool>,
+}
+
+/// Adds the `isAnonymous`, `isAnonymousVpn`, `isHostingProvider`, `isPublicProxy`,
+/// `isResidentialProxy` and `isTorExitNode` fields from a GeoIP2 Anonymous IP database, which has
+/// records of the anonymous networks alone: the IP addresses it has no record of get `false`. The
+/// database is reloaded along with the main one.
+pub struct AnonymousIpDatabase {
+    provider: MaxMindProvider,
+}
+
+impl AnonymousIpDatabase {
+    pub fn open(path: &str) -> Result<AnonymousIpDatabase, String> {
+        let provider = MaxMindProvider::open(path).map_err(|err| format!("Can not open {}: {}", path, err))?;
+        let database_type = provider.metadata().database_type;
+        if !database_type.contains("Anonymous-IP") {
+            return Err(format!("{} is a {} database, not an Anonymous IP one", path, database_type));
+        }
+        Ok(AnonymousIpDatabase { provider })
+    }
+
+    /// The record of `ip`, none when it's not an anonymous network.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<AnonymousIp>, MaxMindDBError> {
+        match self.provider.lookup(ip) {
+            Ok(anonymous) => Ok(Some(anonymous)),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl ResponseHook for AnonymousIpDatabase {
+    fn process(&self, ip: IpAddr, response: &mut Map<String, Value>) {
+        let anonymous = match self.lookup(ip) {
+            Ok(Some(anonymous)) => anonymous,
+            // Only the responses of IP addresses found in the main database get the fields then
+            Ok(None) if resolved(response) => AnonymousIp::default(),
+            Ok(None) => return,
+            Err(err) => {
+                eprintln!("Can not look up {} in {}: {}", ip, self.provider.path().display(), err);
+                return;
+            }
+        };
+
+        let flags = [
+            ("isAnonymous", anonymous.is_anonymous),
+            ("isAnonymousVpn", anonymous.is_anonymous_vpn),
+            ("isHostingProvider", anonymous.is_hosting_provider),
+            ("isPublicProxy", anonymous.is_public_proxy),
+            ("isResidentialProxy", anonymous.is_residential_proxy),
+            ("isTorExitNode", anonymous.is_tor_exit_node),
+        ];
+        for (field, flag) in flags.iter() {
+            response.insert(String::from(*field), Value::from(flag.unwrap_or(false)));
+        }
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        &[
+            "isAnonymous",
+            "isAnonymousVpn",
+            "isHostingProvider",
+            "isPublicProxy",
+            "isResidentialProxy",
+            "isTorExitNode",
+        ]
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        self.provider.reload()
+    }
+}
+
+/// Whether a response is the one of an IP address found in the main database.
+fn resolved(response: &Map<String, Value>) -> bool {
+    response.contains_key("ipAddress") && !response.contains_key("lookupError")
+}
+
+/// Adds the `asOrganization` and `asRegistry` fields with the organization and the regional
+/// internet registry of the `autonomousSystemNumber`, or `asn`, of a response, listed in a CSV file with
+/// `asn,organization,registry` columns, e.g. derived from PeeringDB or CAIDA. Organization names
+/// are normalized, trimming and collapsing whitespace, and registries uppercased.
+pub struct AsnOrganizations {
+    path: String,
+    organizations: RwLock<HashMap<u32, (String, String)>>,
+}
+
+impl AsnOrganizations {
+    pub fn from_file(path: &str) -> Result<AsnOrganizations, String> {
+        Ok(AsnOrganizations {
+            path: path.to_string(),
+            organizations: RwLock::new(AsnOrganizations::read(path)?),
+        })
+    }
+
+    fn read(path: &str) -> Result<HashMap<u32, (String, String)>, String> {
+        let mut reader = csv::Reader::from_path(path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+
+        let mut organizations = HashMap::new();
+        for record in reader.records() {
+            let record = record.map_err(|err| format!("Can not parse {}: {}", path, err))?;
+            let asn = record.get(0).unwrap_or("").trim();
+            let asn = asn
+                .trim_start_matches("AS")
+                .parse::<u32>()
+                .map_err(|_| format!("Invalid autonomous system {} in {}", asn, path))?;
+            let organization = record.get(1).unwrap_or("").split_whitespace().collect::<Vec<_>>().join(" ");
+            let registry = record.get(2).unwrap_or("").trim().to_uppercase();
+            organizations.insert(asn, (organization, registry));
+        }
+
+        Ok(organizations)
+    }
+}
+
+impl ResponseHook for AsnOrganizations {
+    fn process(&self, _ip: IpAddr, response: &mut Map<String, Value>) {
+        let asn = response.get("autonomousSystemNumber").or_else(|| response.get("asn"));
+        let asn = match asn.and_then(Value::as_u64) {
+            Some(asn) => asn as u32,
+            None => return,
+        };
+        let organizations = self.organizations.read().unwrap();
+        if let Some((organization, registry)) = organizations.get(&asn) {
+            if !organization.is_empty() {
+                response.insert(String::from("asOrganization"), Value::from(organization.as_str()));
+            }
+            if !registry.is_empty() {
+                response.insert(String::from("asRegistry"), Value::from(registry.as_str()));
+            }
+        }
+    }
+
+    fn fields(&self) -> &[&'static str] {
+        &["asOrganization", "asRegistry"]
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let organizations = AsnOrganizations::read(&self.path)?;
+        *self.organizations.write().unwrap() = organizations;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `geoip-rs inspect`: prints the metadata of a database and optionally a sample of its records.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, UNIX_EPOCH};
+
+use actix_web::http::header::HttpDate;
+
+use crate::cli::Args;
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::settings::Settings;
+
+pub const USAGE: &str = "Usage: geoip-rs inspect [--sample N] [--lang lang] <file.mmdb>";
+
+pub fn run(args: &Args) -> i32 {
+    let path = match args.positional.first().map(String::as_str).or_else(|| args.get("db")) {
+        Some(path) => path,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let sample = match args.get("sample").map(str::parse::<u32>) {
+        None => 0,
+        Some(Ok(sample)) => sample,
+        Some(Err(_)) => {
+            eprintln!("--sample must be a number\n{}", USAGE);
+            return 2;
+        }
+    };
+
+    let db = match MaxMindProvider::open(path) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Can not open {}: {}", path, err);
+            return 1;
+        }
+    };
+
+    let metadata = db.metadata();
+    println!("File:            {}", path);
+    println!("Database type:   {}", metadata.database_type);
+    for (language, description) in &metadata.description {
+        println!("Description:     {} ({})", description, language);
+    }
+    println!(
+        "Build date:      {} (epoch {})",
+        HttpDate::from(UNIX_EPOCH + Duration::from_secs(metadata.build_epoch)),
+        metadata.build_epoch
+    );
+    println!("IP version:      {}", metadata.ip_version);
+    println!("Languages:       {}", metadata.languages.join(", "));
+    println!("Node count:      {}", metadata.node_count);
+    println!("Record size:     {} bits", metadata.record_size);
+    println!(
+        "Binary format:   {}.{}",
+        metadata.binary_format_major_version, metadata.binary_format_minor_version
+    );
+
+    if sample > 0 {
+        let settings = Settings::from_env();
+        let language = args.get("lang").unwrap_or("en");
+        println!();
+        println!("Sample records:");
+
+        let step = u32::max_value() / sample;
+        for i in 0..sample {
+            let ip = IpAddr::V4(Ipv4Addr::from(i * step + step / 2));
+            let geoip = crate::resolver::resolve(&db, &settings.resolver, &ip.to_string(), language);
+            println!("{}", geoip);
+        }
+    }
+
+    0
+}
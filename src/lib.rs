@@ -0,0 +1,137 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Geographical information about IP addresses, from MaxMind GeoIP2 and GeoLite2 databases.
+//! The geoip-rs server is an HTTP front-end over this library: the `resolver` module builds the
+//! very same responses, so other services can embed the lookups.
+
+#[macro_use]
+extern crate serde_derive;
+
+#[cfg(feature = "server")]
+mod access_log;
+#[cfg(feature = "server")]
+mod admin;
+#[cfg(feature = "server")]
+mod admin_ui;
+#[cfg(feature = "server")]
+mod allowed;
+#[cfg(feature = "server")]
+mod api_keys;
+#[cfg(feature = "server")]
+mod batch;
+#[cfg(feature = "server")]
+mod cache;
+mod centroids;
+#[cfg(feature = "server")]
+mod cli;
+#[cfg(feature = "server")]
+mod client_ip;
+#[cfg(feature = "server")]
+mod cluster;
+#[cfg(feature = "columnar")]
+mod columnar;
+mod country_names;
+#[cfg(feature = "server")]
+mod diagnostics;
+#[cfg(feature = "server")]
+mod download;
+#[cfg(feature = "server")]
+mod enrich_log;
+#[cfg(feature = "server")]
+mod fallback;
+#[cfg(feature = "server")]
+mod field_names;
+#[cfg(feature = "server")]
+mod fields;
+#[cfg(feature = "ffi")]
+mod ffi;
+#[cfg(all(fuzzing, feature = "server"))]
+#[doc(hidden)]
+pub mod fuzz;
+#[cfg(feature = "async")]
+mod geoip;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "server")]
+mod handover;
+#[cfg(feature = "server")]
+mod health;
+#[cfg(feature = "server")]
+mod hooks;
+#[cfg(feature = "server")]
+mod inspect;
+#[cfg(feature = "server")]
+mod lines;
+#[cfg(feature = "server")]
+mod lookup;
+#[cfg(feature = "server")]
+mod memcached;
+#[cfg(feature = "server")]
+mod metrics;
+#[cfg(feature = "server")]
+mod networks;
+#[cfg(feature = "node")]
+mod node;
+#[cfg(feature = "server")]
+mod proto;
+pub mod provider;
+#[cfg(feature = "server")]
+mod rate_limit;
+mod records;
+#[cfg(feature = "server")]
+mod reload;
+#[cfg(feature = "server")]
+mod remote;
+pub mod resolver;
+pub mod response;
+#[cfg(feature = "server")]
+mod reverse;
+#[cfg(feature = "server")]
+mod risk;
+#[cfg(feature = "server")]
+mod slow_log;
+#[cfg(feature = "server")]
+mod snapshots;
+#[cfg(feature = "server")]
+mod tcp;
+#[cfg(feature = "server")]
+mod templates;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+mod settings;
+#[cfg(feature = "server")]
+mod shape;
+#[cfg(feature = "server")]
+mod udp;
+#[cfg(feature = "server")]
+mod updater;
+#[cfg(feature = "server")]
+mod verify;
+#[cfg(feature = "server")]
+mod web_service;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+#[cfg(feature = "server")]
+pub use crate::hooks::{Hooks, ResponseHook};
+pub use crate::resolver::GeoIpResolver;
+pub use crate::response::ResolvedIPResponse;
+#[cfg(feature = "async")]
+pub use crate::geoip::GeoIp;
+#[cfg(feature = "sync")]
+pub use crate::geoip::GeoIpSync;
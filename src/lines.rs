@@ -0,0 +1,61 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Plain TCP line protocol: each line sent on a connection is an IP address, optionally followed
+//! by a space and a language, and gets back a line with the JSON response. Works with netcat.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::provider::GeoProvider;
+use crate::server::Db;
+use crate::tcp;
+
+/// Longest line read, an IP address and a language with room to spare. Connections sending a
+/// longer one get an error line and are closed.
+const MAX_LINE_LENGTH: u64 = 256;
+
+/// Binds `addr` and serves each connection on a dedicated thread, see [tcp::accept].
+pub fn spawn<P: GeoProvider>(addr: &str, db: Db<P>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    tcp::accept(listener, "TCP", move |stream| serve(stream, &db));
+    Ok(())
+}
+
+fn serve<P: GeoProvider>(stream: TcpStream, db: &Db<P>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE_LENGTH {
+            writeln!(writer, "{}", serde_json::json!({ "error": "line_too_long" }))?;
+            return writer.flush();
+        }
+
+        let mut parts = line.split_whitespace();
+        let ip_address = match parts.next() {
+            Some(ip_address) => ip_address,
+            None => continue,
+        };
+        let language = parts.next().unwrap_or("en");
+
+        writeln!(writer, "{}", db.lookup(ip_address, language))?;
+        writer.flush()?;
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `geoip-rs lookup`: resolves the IP addresses of a file, or of stdin, one per line, the way
+//! `/{ip}` does, and writes the responses as JSON lines or CSV.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+
+use serde_json::Value;
+
+use crate::cli::{self, Args};
+use crate::resolver::resolve;
+use crate::server::{csv_value, CSV_GEO_COLUMNS};
+use crate::settings::Settings;
+
+pub const USAGE: &str = "Usage: geoip-rs lookup [--db file.mmdb] [--input ips.txt] [--output jsonl|csv] [--lang lang]";
+
+/// Where the responses are written, in the format asked for.
+enum Output<W: Write> {
+    JsonLines(BufWriter<W>),
+    Csv(csv::Writer<W>),
+}
+
+impl<W: Write> Output<W> {
+    fn new(format: &str, output: W) -> Option<Output<W>> {
+        match format {
+            "jsonl" | "json" => Some(Output::JsonLines(BufWriter::new(output))),
+            "csv" => Some(Output::Csv(csv::Writer::from_writer(output))),
+            _ => None,
+        }
+    }
+
+    fn write_headers(&mut self) -> io::Result<()> {
+        match self {
+            Output::JsonLines(_) => Ok(()),
+            Output::Csv(writer) => {
+                let headers = std::iter::once("ipAddress").chain(CSV_GEO_COLUMNS.iter().copied());
+                writer.write_record(headers).map_err(io::Error::from)
+            }
+        }
+    }
+
+    fn write(&mut self, ip_address: &str, geoip: &Value) -> io::Result<()> {
+        match self {
+            Output::JsonLines(writer) => writeln!(writer, "{}", geoip),
+            Output::Csv(writer) => {
+                let record = std::iter::once(ip_address.to_string())
+                    .chain(CSV_GEO_COLUMNS.iter().map(|column| csv_value(&geoip[column])));
+                writer.write_record(record).map_err(io::Error::from)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::JsonLines(writer) => writer.flush(),
+            Output::Csv(writer) => writer.flush(),
+        }
+    }
+}
+
+pub fn run(args: &Args) -> i32 {
+    let stdout = io::stdout();
+    let format = args.get("output").unwrap_or("jsonl");
+    let mut output = match Output::new(format, stdout.lock()) {
+        Some(output) => output,
+        None => {
+            eprintln!("Unknown output format {}\n{}", format, USAGE);
+            return 2;
+        }
+    };
+
+    let db = match cli::open_db(args) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    let input_path = args.get("input").or_else(|| args.positional.first().map(String::as_str));
+    let input: Box<dyn BufRead> = match input_path {
+        None | Some("-") => Box::new(BufReader::new(io::stdin())),
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(err) => {
+                eprintln!("Can not open {}: {}", path, err);
+                return 1;
+            }
+        },
+    };
+
+    let settings = Settings::from_env();
+    let language = args.get("lang").unwrap_or("en");
+
+    if output.write_headers().is_err() {
+        return 1;
+    }
+
+    for (line_number, line) in input.lines().enumerate() {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                eprintln!("Can not read line {}: {}", line_number + 1, err);
+                return 1;
+            }
+        };
+        let ip_address = line.trim();
+        if ip_address.is_empty() || ip_address.starts_with('#') {
+            continue;
+        }
+
+        let geoip = resolve(&db, &settings.resolver, ip_address, language);
+        if output.write(ip_address, &geoip).is_err() {
+            return 1;
+        }
+    }
+
+    match output.flush() {
+        Ok(()) => 0,
+        Err(_) => 1,
+    }
+}
@@ -0,0 +1,90 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Read-only memcached text protocol: `get <ip> [<ip> ...]` returns the JSON responses as values,
+//! so applications can use their existing memcached clients as geoip-rs clients. IP addresses
+//! not found in the database are cache misses.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::provider::GeoProvider;
+use crate::server::Db;
+use crate::tcp;
+
+/// Longest key, as in memcached.
+const MAX_KEY_LENGTH: usize = 250;
+/// Longest command line, as in memcached. Connections sending a longer one are closed.
+const MAX_LINE_LENGTH: u64 = 2048;
+
+/// Binds `addr` and serves each connection on a dedicated thread, see [tcp::accept].
+pub fn spawn<P: GeoProvider>(addr: &str, db: Db<P>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    tcp::accept(listener, "memcached", move |stream| serve(stream, &db));
+    Ok(())
+}
+
+fn serve<P: GeoProvider>(stream: TcpStream, db: &Db<P>) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        if reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        if !line.ends_with('\n') && line.len() as u64 == MAX_LINE_LENGTH {
+            writer.write_all(b"CLIENT_ERROR line too long\r\n")?;
+            return writer.flush();
+        }
+
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            Some("get") | Some("gets") => {
+                let keys: Vec<&str> = parts.collect();
+                if keys.iter().any(|key| key.len() > MAX_KEY_LENGTH) {
+                    writer.write_all(b"CLIENT_ERROR bad command line format\r\n")?;
+                    writer.flush()?;
+                    continue;
+                }
+                for key in keys {
+                    let geoip = db.lookup(key, "en");
+                    if geoip.get("ip_address").is_some() {
+                        continue;
+                    }
+                    let value = geoip.to_string();
+                    write!(writer, "VALUE {} 0 {}\r\n{}\r\n", key, value.len(), value)?;
+                }
+                writer.write_all(b"END\r\n")?;
+            }
+            Some("set") | Some("add") | Some("replace") | Some("append") | Some("prepend") | Some("cas") => {
+                // Skip the data block, then refuse to store it
+                let bytes = parts.nth(3).and_then(|bytes| bytes.parse::<u64>().ok());
+                match bytes {
+                    Some(bytes) => {
+                        io::copy(&mut reader.by_ref().take(bytes + 2), &mut io::sink())?;
+                        writer.write_all(b"SERVER_ERROR read only\r\n")?;
+                    }
+                    None => writer.write_all(b"CLIENT_ERROR bad command line format\r\n")?,
+                }
+            }
+            Some("version") => write!(writer, "VERSION geoip-rs {}\r\n", env!("CARGO_PKG_VERSION"))?,
+            Some("quit") => return Ok(()),
+            Some(_) => writer.write_all(b"ERROR\r\n")?,
+            None => continue,
+        }
+        writer.flush()?;
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /metrics`, in the Prometheus text exposition format, served with GEOIP_RS_METRICS.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::{web, HttpResponse};
+use sha2::{Digest, Sha256};
+
+use crate::cache::Cache;
+use crate::reload::Reloads;
+use crate::updater::Status;
+
+/// Lookups failed reading the database, rather than not finding the IP address in it.
+pub static LOOKUP_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Upper bounds, in seconds, of the buckets of the request durations.
+const BUCKETS: [f64; 9] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5];
+
+/// What requests are attributed to.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Labels {
+    /// The endpoint, e.g. `index` or `country`, see [endpoint].
+    pub endpoint: &'static str,
+    /// The format of the response, from its content type, see [format].
+    pub format: &'static str,
+    /// `hit` for conditional requests answered `304 Not Modified`, `miss` otherwise.
+    pub cache: &'static str,
+    /// The API key, hashed, `none` without one and `invalid` for unknown ones, see [hashed_key].
+    pub key: String,
+}
+
+#[derive(Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Counts and durations of the requests served, by [Labels].
+#[derive(Default)]
+pub struct Requests {
+    histograms: Mutex<BTreeMap<Labels, Histogram>>,
+}
+
+impl Requests {
+    pub fn observe(&self, labels: Labels, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut histograms = self.histograms.lock().unwrap();
+        let histogram = histograms.entry(labels).or_default();
+        for (bucket, le) in histogram.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if seconds <= *le {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    /// Requests served and seconds taken to answer them, by endpoint.
+    pub fn by_endpoint(&self) -> BTreeMap<&'static str, (u64, f64)> {
+        let mut totals = BTreeMap::new();
+        for (labels, histogram) in self.histograms.lock().unwrap().iter() {
+            let (count, sum) = totals.entry(labels.endpoint).or_insert((0, 0.0));
+            *count += histogram.count;
+            *sum += histogram.sum;
+        }
+        totals
+    }
+
+    fn write(&self, body: &mut String) {
+        let histograms = self.histograms.lock().unwrap();
+
+        writeln!(body, "# HELP geoip_requests_total Requests served.").unwrap();
+        writeln!(body, "# TYPE geoip_requests_total counter").unwrap();
+        for (labels, histogram) in histograms.iter() {
+            writeln!(body, "geoip_requests_total{{{}}} {}", labels, histogram.count).unwrap();
+        }
+
+        writeln!(body, "# HELP geoip_request_duration_seconds Time taken to answer requests.").unwrap();
+        writeln!(body, "# TYPE geoip_request_duration_seconds histogram").unwrap();
+        for (labels, histogram) in histograms.iter() {
+            for (bucket, le) in histogram.buckets.iter().zip(BUCKETS.iter()) {
+                writeln!(body, "geoip_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, bucket).unwrap();
+            }
+            writeln!(
+                body,
+                "geoip_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            )
+            .unwrap();
+            writeln!(body, "geoip_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum).unwrap();
+            writeln!(body, "geoip_request_duration_seconds_count{{{}}} {}", labels, histogram.count).unwrap();
+        }
+    }
+}
+
+impl std::fmt::Display for Labels {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "endpoint=\"{}\",format=\"{}\",cache=\"{}\",key=\"{}\"",
+            self.endpoint, self.format, self.cache, self.key
+        )
+    }
+}
+
+/// The endpoint of a request path, with the IP addresses of `/{ip}`, `/country/{ip}` and
+/// `/city/{ip}` left out, so that the label has a few values.
+pub fn endpoint(path: &str) -> &'static str {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match (segments.next().unwrap_or(""), segments.next()) {
+        ("", None) => "index",
+        ("country", Some(_)) => "country",
+        ("city", Some(_)) => "city",
+        ("asn", Some(_)) => "asn",
+        ("batch", None) => "batch",
+        ("batch", Some("csv")) => "batch_csv",
+        ("batch", Some("jsonl")) => "batch_jsonl",
+        ("risk", None) => "risk",
+        ("allowed", None) => "allowed",
+        ("record", Some(_)) => "record",
+        ("fields", None) => "fields",
+        ("reverse", None) => "reverse",
+        ("networks", None) => "networks",
+        ("health", None) => "health",
+        ("healthz", None) => "healthz",
+        ("readyz", None) => "readyz",
+        ("dbinfo", None) => "dbinfo",
+        ("metrics", None) => "metrics",
+        ("debug", None) | ("database", _) | ("admin", _) => "admin",
+        (_, None) => "index",
+        _ => "other",
+    }
+}
+
+/// The format of a response, from its content type.
+pub fn format(content_type: Option<&str>) -> &'static str {
+    let mime = match content_type {
+        Some(content_type) => content_type.split(';').next().unwrap_or("").trim(),
+        None => return "none",
+    };
+    match mime {
+        "application/json" => "json",
+        "application/javascript" => "jsonp",
+        "application/x-ndjson" => "jsonl",
+        "application/yaml" => "yaml",
+        "application/x-protobuf" => "protobuf",
+        "application/xml" | "text/xml" => "xml",
+        "text/html" => "html",
+        "text/csv" => "csv",
+        "text/plain" => "text",
+        "application/vnd.apache.arrow.stream" => "arrow",
+        "application/vnd.apache.parquet" => "parquet",
+        _ => "other",
+    }
+}
+
+/// A short hash of an API key, so that keys are told apart without being exposed.
+pub fn hashed_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))[..12].to_string()
+}
+
+pub async fn metrics(
+    reloads: web::Data<Arc<Reloads>>,
+    downloads: web::Data<Arc<Status>>,
+    requests: web::Data<Arc<Requests>>,
+    cache: web::Data<Option<Arc<Cache>>>,
+) -> HttpResponse {
+    let mut body = String::new();
+
+    requests.write(&mut body);
+    writeln!(body, "# HELP geoip_lookup_errors_total Lookups failed reading the database.").unwrap();
+    writeln!(body, "# TYPE geoip_lookup_errors_total counter").unwrap();
+    writeln!(body, "geoip_lookup_errors_total {}", LOOKUP_ERRORS.load(Ordering::Relaxed)).unwrap();
+
+    if let Some(cache) = cache.as_ref() {
+        let (hits, misses, entries) = cache.counts();
+        writeln!(body, "# HELP geoip_cache_hits_total Lookups answered from the cache.").unwrap();
+        writeln!(body, "# TYPE geoip_cache_hits_total counter").unwrap();
+        writeln!(body, "geoip_cache_hits_total {}", hits).unwrap();
+        writeln!(body, "# HELP geoip_cache_misses_total Lookups not found in the cache, or stale there.").unwrap();
+        writeln!(body, "# TYPE geoip_cache_misses_total counter").unwrap();
+        writeln!(body, "geoip_cache_misses_total {}", misses).unwrap();
+        gauge(&mut body, "geoip_cache_entries", "Responses kept in the cache.", entries as f64);
+    }
+
+    let downloads = downloads.state();
+    gauge(
+        &mut body,
+        "geoip_download_consecutive_failures",
+        "Downloads of the database failed since the last successful one.",
+        f64::from(downloads.consecutive_failures),
+    );
+    if let Some(last_success) = downloads.last_success {
+        gauge(
+            &mut body,
+            "geoip_last_download_success_timestamp_seconds",
+            "When a download of the database last succeeded.",
+            last_success as f64,
+        );
+    }
+
+    if let Some(diff) = reloads.last() {
+        gauge(
+            &mut body,
+            "geoip_last_reload_timestamp_seconds",
+            "When the database was last reloaded.",
+            diff.reloaded_at as f64,
+        );
+        gauge(
+            &mut body,
+            "geoip_last_reload_countries_changed_ratio",
+            "Share of the sampled IP addresses whose country changed with the last reload.",
+            diff.countries_changed_percent / 100.0,
+        );
+        gauge(
+            &mut body,
+            "geoip_last_reload_cities_changed_ratio",
+            "Share of the sampled IP addresses whose city changed with the last reload.",
+            diff.cities_changed_percent / 100.0,
+        );
+    }
+
+    HttpResponse::Ok().content_type("text/plain; version=0.0.4; charset=utf-8").body(body)
+}
+
+fn gauge(body: &mut String, name: &str, help: &str, value: f64) {
+    writeln!(body, "# HELP {} {}", name, help).unwrap();
+    writeln!(body, "# TYPE {} gauge", name).unwrap();
+    writeln!(body, "{} {}", name, value).unwrap();
+}
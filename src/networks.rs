@@ -0,0 +1,303 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Walks the search tree of a MaxMind database, for the endpoints listing its networks and
+//! records: the `maxminddb` crate only looks up single addresses. See
+//! https://maxmind.github.io/MaxMind-DB/ for the layout of the tree.
+//!
+//! `GET /networks?city=Milan&country=IT` lists the networks located in a city, e.g. to build
+//! location-based allowlists. Served with GEOIP_RS_NETWORKS. Pages resume the walk at their
+//! `cursor`, the last network examined by the previous page, and examine up to MAX_SCANNED
+//! networks, so that every request does a bounded amount of work.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::sync::Arc;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::channel::mpsc;
+use futures::executor::block_on;
+use futures::SinkExt;
+use ipnetwork::IpNetwork;
+use maxminddb::geoip2::City;
+use maxminddb::{Metadata, Reader};
+use memmap::Mmap;
+use serde_json::{json, Value};
+
+use crate::api_keys::KeyStore;
+use crate::batch;
+use crate::provider::MaxMindProvider;
+
+const DEFAULT_LIMIT: usize = 1000;
+const MAX_LIMIT: usize = 10000;
+/// Networks examined by a page at most, matching or not.
+const MAX_SCANNED: usize = 200_000;
+
+#[derive(Deserialize)]
+struct NetworksParams {
+    city: String,
+    country: Option<String>,
+    limit: Option<usize>,
+    #[serde(alias = "after")]
+    cursor: Option<IpNetwork>,
+}
+
+/// The search tree of a database, over the bytes of the whole file.
+pub struct Tree<'a> {
+    buf: &'a [u8],
+    node_count: usize,
+    record_size: u16,
+    ip_version: u16,
+}
+
+impl<'a> Tree<'a> {
+    pub fn new(buf: &'a [u8], metadata: &Metadata) -> Tree<'a> {
+        Tree {
+            buf,
+            node_count: metadata.node_count as usize,
+            record_size: metadata.record_size,
+            ip_version: metadata.ip_version,
+        }
+    }
+
+    /// Calls `f` with each network having a record, and the pointer to its record, the same for
+    /// the networks sharing a record, in the order of their addresses. The IPv4 networks of IPv6
+    /// databases are reported once, as IPv4 networks, skipping the IPv4-mapped and 6to4 aliases.
+    /// `f` returns false to stop the walk.
+    pub fn walk<F: FnMut(IpNetwork, usize) -> bool>(&self, f: F) {
+        self.walk_from(0, f)
+    }
+
+    /// Walks the networks from the address `start` on, see [Tree::resume_after], skipping the
+    /// subtrees before it altogether.
+    pub fn walk_from<F: FnMut(IpNetwork, usize) -> bool>(&self, start: u128, mut f: F) {
+        let bits = self.bits();
+        let ipv4_start = if self.ip_version == 6 { self.ipv4_start() } else { 0 };
+
+        // Records of nodes, with the network they stand for. Right records are pushed first, so
+        // that left ones are walked first.
+        let mut stack = vec![(0, 0u128, 0)];
+        while let Some((value, ip, prefix)) = stack.pop() {
+            if ip | host_mask(bits - prefix) < start {
+                continue;
+            }
+            if value < self.node_count {
+                if bits == 128 && value == ipv4_start && ip != 0 {
+                    continue;
+                }
+                for &bit in [1u8, 0].iter() {
+                    stack.push((self.record(value, bit), ip | (bit as u128) << (bits - prefix - 1), prefix + 1));
+                }
+            } else if value > self.node_count {
+                let network = match (bits, ip >> 32) {
+                    (128, 0) if prefix >= 96 => network(IpAddr::V4(Ipv4Addr::from(ip as u32)), prefix - 96),
+                    (128, _) => network(IpAddr::V6(Ipv6Addr::from(ip)), prefix),
+                    _ => network(IpAddr::V4(Ipv4Addr::from(ip as u32)), prefix),
+                };
+                if !f(network, value) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// The address of the tree the walk resumes from after `network`, none past the end.
+    /// IPv4 networks are at `::/96` in IPv6 trees, so the walk order is kept.
+    pub fn resume_after(&self, network: &IpNetwork) -> Option<u128> {
+        let last = match network {
+            IpNetwork::V4(network) => u32::from(network.ip()) as u128 | host_mask(32 - network.prefix() as usize),
+            IpNetwork::V6(_) if self.ip_version != 6 => return None,
+            IpNetwork::V6(network) => u128::from(network.ip()) | host_mask(128 - network.prefix() as usize),
+        };
+        last.checked_add(1).filter(|start| *start <= host_mask(self.bits()))
+    }
+
+    fn bits(&self) -> usize {
+        if self.ip_version == 6 {
+            128
+        } else {
+            32
+        }
+    }
+
+    /// The node of the IPv4 subtree of an IPv6 tree, at `::/96`.
+    fn ipv4_start(&self) -> usize {
+        let mut node = 0;
+        for _ in 0..96 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, 0);
+        }
+        node
+    }
+
+    /// The left (0) or right (1) record of `node`.
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let be = |bytes: &[u8]| bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize);
+        match self.record_size {
+            24 => {
+                let offset = node * 6 + bit as usize * 3;
+                be(&self.buf[offset..offset + 3])
+            }
+            28 => {
+                let offset = node * 7;
+                let middle = self.buf[offset + 3] as usize;
+                if bit == 0 {
+                    (middle & 0xF0) << 20 | be(&self.buf[offset..offset + 3])
+                } else {
+                    (middle & 0x0F) << 24 | be(&self.buf[offset + 4..offset + 7])
+                }
+            }
+            _ => {
+                let offset = node * 8 + bit as usize * 4;
+                be(&self.buf[offset..offset + 4])
+            }
+        }
+    }
+}
+
+/// The mask of the last `bits` bits of an address.
+fn host_mask(bits: usize) -> u128 {
+    if bits >= 128 {
+        u128::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+fn network(ip: IpAddr, prefix: usize) -> IpNetwork {
+    IpNetwork::new(ip, prefix as u8).unwrap()
+}
+
+/// Memory maps a database file, for a [Tree] over it.
+pub fn map(path: &Path) -> Result<Mmap, String> {
+    let file = File::open(path).map_err(|err| format!("Can not open {}: {}", path.display(), err))?;
+    unsafe { Mmap::map(&file) }.map_err(|err| format!("Can not map {}: {}", path.display(), err))
+}
+
+/// Streams `{"networks": [...], "next": ...}`, with up to `limit` networks of the city. `next` is
+/// the `cursor` of the next page, null on the last one. Pages can have fewer networks, or none,
+/// and still a `next`, when they've examined MAX_SCANNED networks.
+pub async fn networks(
+    req: HttpRequest,
+    provider: web::Data<Arc<MaxMindProvider>>,
+    keys: web::Data<Arc<KeyStore>>,
+) -> HttpResponse {
+    if let Err(response) = keys.check(&req) {
+        return response;
+    }
+    let params: NetworksParams = match serde_urlencoded::from_str(req.query_string()) {
+        Ok(params) => params,
+        Err(err) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("invalid query: {}", err))
+        }
+    };
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    if limit == 0 || limit > MAX_LIMIT {
+        return HttpResponse::BadRequest()
+            .content_type("text/plain; charset=utf-8")
+            .body(format!("limit must be within 1 and {}", MAX_LIMIT));
+    }
+
+    let mmap = match map(provider.path()) {
+        Ok(mmap) => mmap,
+        Err(err) => {
+            return HttpResponse::InternalServerError()
+                .content_type("text/plain; charset=utf-8")
+                .body(err)
+        }
+    };
+
+    // Walking takes a while: the page is sent as it's found, with backpressure from the client
+    let (mut sender, receiver) = mpsc::channel::<Result<web::Bytes, actix_web::Error>>(16);
+    let spawned = batch::spawn(move || {
+        let reader = match Reader::from_source(&mmap[..]) {
+            Ok(reader) => reader,
+            Err(err) => {
+                eprintln!("Can not read {}: {}", provider.path().display(), err);
+                return;
+            }
+        };
+        let mut send = |chunk: String| block_on(sender.send(Ok(web::Bytes::from(chunk)))).is_ok();
+        if !send(String::from("{\"networks\":[")) {
+            return;
+        }
+
+        let tree = Tree::new(&mmap, &reader.metadata);
+        let start = match params.cursor {
+            Some(cursor) => tree.resume_after(&cursor),
+            None => Some(0),
+        };
+
+        let mut matches = HashMap::new();
+        let (mut count, mut scanned, mut last, mut next) = (0, 0, None, None);
+        if let Some(start) = start {
+            tree.walk_from(start, |network, record| {
+                if count == limit || scanned == MAX_SCANNED {
+                    next = last;
+                    return false;
+                }
+                scanned += 1;
+                last = Some(network);
+
+                let matching = *matches.entry(record).or_insert_with(|| {
+                    let city: City = match reader.lookup(network.ip()) {
+                        Ok(city) => city,
+                        Err(_) => return false,
+                    };
+                    located_in(&city, &params.city, params.country.as_deref())
+                });
+                if !matching {
+                    return true;
+                }
+                let separator = if count == 0 { "" } else { "," };
+                count += 1;
+                send(format!("{}{}", separator, Value::from(network.to_string())))
+            });
+        }
+
+        let next = next.map(|network: IpNetwork| network.to_string());
+        send(format!("],\"next\":{}}}", json!(next)));
+    });
+    if let Err(busy) = spawned {
+        return busy;
+    }
+
+    HttpResponse::Ok()
+        .content_type("application/json")
+        .streaming(receiver)
+}
+
+fn located_in(city: &City, name: &str, country: Option<&str>) -> bool {
+    let in_country = match country {
+        Some(country) => city
+            .country
+            .as_ref()
+            .and_then(|c| c.iso_code.as_deref())
+            .map_or(false, |iso_code| iso_code.eq_ignore_ascii_case(country)),
+        None => true,
+    };
+    // Any of the names of the city, in any language
+    in_country
+        && city
+            .city
+            .as_ref()
+            .and_then(|c| c.names.as_ref())
+            .map_or(false, |names| names.values().any(|value| value.to_lowercase() == name.to_lowercase()))
+}
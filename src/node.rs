@@ -0,0 +1,82 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Node.js bindings of the resolver.
+
+use std::sync::Arc;
+
+use napi::bindgen_prelude::AsyncTask;
+use napi::{Env, Error, Result, Task};
+use napi_derive::napi;
+use maxminddb::Reader;
+use memmap::Mmap;
+use serde_json::Value;
+
+use crate::resolver;
+
+/// The `GeoIp` class, resolving IP addresses to the JSON bodies `/{ip}` would return.
+#[napi(js_name = "GeoIp")]
+pub struct NodeGeoIp {
+    reader: Arc<Reader<Mmap>>,
+}
+
+#[napi]
+impl NodeGeoIp {
+    /// `GeoIp.open(path)`, throwing when the database can't be opened.
+    #[napi(factory)]
+    pub fn open(path: String) -> Result<NodeGeoIp> {
+        let reader = Reader::open_mmap(&path)
+            .map_err(|err| Error::from_reason(format!("Can not open {}: {}", path, err)))?;
+
+        Ok(NodeGeoIp {
+            reader: Arc::new(reader),
+        })
+    }
+
+    /// `geoip.lookup(ip, lang)`, resolving on the JavaScript thread.
+    #[napi]
+    pub fn lookup(&self, ip: String, lang: String) -> Value {
+        resolver::resolve(self.reader.as_ref(), &resolver::Options::default(), ip.trim(), &lang)
+    }
+
+    /// `geoip.lookupBatch(ips, lang)`, a promise of the bodies, in the order of `ips`. They're
+    /// resolved on the libuv thread pool, leaving the event loop free.
+    #[napi(ts_return_type = "Promise<Array<object>>")]
+    pub fn lookup_batch(&self, ips: Vec<String>, lang: String) -> AsyncTask<LookupBatch> {
+        AsyncTask::new(LookupBatch {
+            reader: self.reader.clone(),
+            ips,
+            lang,
+        })
+    }
+}
+
+pub struct LookupBatch {
+    reader: Arc<Reader<Mmap>>,
+    ips: Vec<String>,
+    lang: String,
+}
+
+impl Task for LookupBatch {
+    type Output = Vec<Value>;
+    type JsValue = Vec<Value>;
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        Ok(self.ips.iter().map(|ip| resolver::resolve(self.reader.as_ref(), &resolver::Options::default(), ip.trim(), &self.lang)).collect())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output)
+    }
+}
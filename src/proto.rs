@@ -0,0 +1,133 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Protobuf messages of `proto/geoip.proto`, built from the JSON responses.
+
+use prost::Message;
+use serde_json::Value;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Location {
+    #[prost(string, tag = "1")]
+    pub ip_address: String,
+    #[prost(bool, tag = "2")]
+    pub found: bool,
+    #[prost(double, tag = "3")]
+    pub latitude: f64,
+    #[prost(double, tag = "4")]
+    pub longitude: f64,
+    #[prost(string, tag = "5")]
+    pub postal_code: String,
+    #[prost(string, tag = "6")]
+    pub continent_code: String,
+    #[prost(string, tag = "7")]
+    pub continent_name: String,
+    #[prost(string, tag = "8")]
+    pub country_code: String,
+    #[prost(string, tag = "9")]
+    pub country_label: String,
+    #[prost(string, tag = "10")]
+    pub country_name: String,
+    #[prost(string, tag = "11")]
+    pub region_code: String,
+    #[prost(string, tag = "12")]
+    pub region_name: String,
+    #[prost(string, tag = "13")]
+    pub province_code: String,
+    #[prost(string, tag = "14")]
+    pub province_name: String,
+    #[prost(string, tag = "15")]
+    pub city_name: String,
+    #[prost(string, tag = "16")]
+    pub time_zone: String,
+    #[prost(string, tag = "17")]
+    pub tunnel: String,
+    #[prost(bool, tag = "18")]
+    pub is_anonymous_proxy: bool,
+    #[prost(bool, tag = "19")]
+    pub is_satellite_provider: bool,
+    #[prost(uint32, tag = "20")]
+    pub country_confidence: u32,
+    #[prost(uint32, tag = "21")]
+    pub city_confidence: u32,
+    #[prost(uint32, tag = "22")]
+    pub postal_confidence: u32,
+    #[prost(string, tag = "23")]
+    pub user_type: String,
+    #[prost(string, tag = "24")]
+    pub location_precision: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Locations {
+    #[prost(message, repeated, tag = "1")]
+    pub locations: Vec<Location>,
+}
+
+impl From<&Value> for Location {
+    /// Works with the responses of all the endpoints: fields missing from them are left empty.
+    fn from(geoip: &Value) -> Location {
+        let string = |name: &str| geoip[name].as_str().unwrap_or("").to_string();
+        let number = |name: &str| geoip[name].as_f64().unwrap_or(0.0);
+        let flag = |name: &str| geoip[name].as_bool().unwrap_or(false);
+        let confidence = |name: &str| geoip[name].as_u64().unwrap_or(0) as u32;
+
+        match geoip.get("ip_address") {
+            Some(ip_address) => Location {
+                ip_address: ip_address.as_str().unwrap_or("").to_string(),
+                ..Location::default()
+            },
+            None => Location {
+                ip_address: string("ipAddress"),
+                found: true,
+                latitude: number("latitude"),
+                longitude: number("longitude"),
+                postal_code: string("postalCode"),
+                continent_code: string("continentCode"),
+                continent_name: string("continentName"),
+                country_code: string("countryCode"),
+                country_label: string("countryLabel"),
+                country_name: string("countryName"),
+                region_code: string("regionCode"),
+                region_name: string("regionName"),
+                province_code: string("provinceCode"),
+                province_name: string("provinceName"),
+                city_name: string("cityName"),
+                time_zone: string("timeZone"),
+                tunnel: string("tunnel"),
+                is_anonymous_proxy: flag("isAnonymousProxy"),
+                is_satellite_provider: flag("isSatelliteProvider"),
+                country_confidence: confidence("countryConfidence"),
+                city_confidence: confidence("cityConfidence"),
+                postal_confidence: confidence("postalConfidence"),
+                user_type: string("userType"),
+                location_precision: string("locationPrecision"),
+            },
+        }
+    }
+}
+
+/// Encodes a response, or an array of responses, as a protobuf `Location` or `Locations`.
+pub fn encode(geoip: &Value) -> Vec<u8> {
+    let mut body = Vec::new();
+    match geoip {
+        Value::Array(geoips) => Locations {
+            locations: geoips.iter().map(Location::from).collect(),
+        }
+        .encode(&mut body),
+        geoip => Location::from(geoip).encode(&mut body),
+    }
+    .unwrap();
+    body
+}
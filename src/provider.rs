@@ -0,0 +1,245 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Where the geographical information comes from: the `GeoProvider` trait, implemented by the
+//! MaxMind readers and, with the `mmap` feature, by `MaxMindProvider`, the memory mapped database
+//! file the server runs on, reloaded in place.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+#[cfg(feature = "mmap")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "mmap")]
+use std::sync::{Arc, RwLock};
+
+use maxminddb::{MaxMindDBError, Metadata, Reader};
+#[cfg(feature = "mmap")]
+use memmap::Mmap;
+use serde::de::DeserializeOwned;
+
+/// A source of geographical information. Records are returned in the shape of the
+/// MaxMind GeoIP2 models, so any provider can be used wherever a MaxMind database is.
+pub trait GeoProvider: Send + Sync + 'static {
+    /// Looks up `ip`, returning its record deserialized as `T`
+    /// (e.g. `maxminddb::geoip2::City`). Missing records are `AddressNotFoundError`s.
+    fn lookup<T: DeserializeOwned>(&self, ip: IpAddr) -> Result<T, MaxMindDBError>;
+
+    /// Describes the currently loaded data.
+    fn metadata(&self) -> DbMetadata;
+
+    /// Build time of the currently loaded data, in seconds since the epoch.
+    fn build_epoch(&self) -> u64 {
+        self.metadata().build_epoch
+    }
+
+    /// Edition of the currently loaded data, telling which records it has.
+    fn edition(&self) -> Edition {
+        Edition::of(&self.metadata().database_type)
+    }
+
+    /// Whether the records can have the [Extras](crate::records::Extras) fields, decoded apart
+    /// from the `maxminddb::geoip2` models: only Enterprise ones do.
+    fn has_extras(&self) -> bool {
+        self.edition() == Edition::Enterprise
+    }
+
+    /// Reloads the underlying data. On failure, the provider keeps serving the current data.
+    fn reload(&self) -> Result<(), String>;
+}
+
+/// What the records of a database have, detected from the type in its metadata, e.g.
+/// `GeoLite2-Country`, so that a country database is answered with the country fields alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Edition {
+    /// Country and continent, e.g. GeoLite2-Country or DBIP-Country-Lite.
+    Country,
+    /// Location down to the city, and the editions not known to be country ones.
+    City,
+    /// City records along with the confidences, user types and autonomous systems of GeoIP2
+    /// Enterprise.
+    Enterprise,
+}
+
+impl Edition {
+    pub fn of(database_type: &str) -> Edition {
+        if database_type.contains("Enterprise") {
+            Edition::Enterprise
+        } else if database_type.contains("Country") {
+            Edition::Country
+        } else {
+            Edition::City
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct DbMetadata {
+    pub database_type: String,
+    pub description: BTreeMap<String, String>,
+    pub build_epoch: u64,
+    pub ip_version: u16,
+    pub languages: Vec<String>,
+    pub node_count: u32,
+    pub record_size: u16,
+    pub binary_format_major_version: u16,
+    pub binary_format_minor_version: u16,
+}
+
+impl From<&Metadata> for DbMetadata {
+    fn from(metadata: &Metadata) -> DbMetadata {
+        DbMetadata {
+            database_type: metadata.database_type.clone(),
+            description: metadata.description.clone(),
+            build_epoch: metadata.build_epoch,
+            ip_version: metadata.ip_version,
+            languages: metadata.languages.clone(),
+            node_count: metadata.node_count,
+            record_size: metadata.record_size,
+            binary_format_major_version: metadata.binary_format_major_version,
+            binary_format_minor_version: metadata.binary_format_minor_version,
+        }
+    }
+}
+
+/// A MaxMind database as is, e.g. the bytes supplied by a WebAssembly host. There's no file to
+/// reload it from.
+impl<S: AsRef<[u8]> + Send + Sync + 'static> GeoProvider for Reader<S> {
+    fn lookup<T: DeserializeOwned>(&self, ip: IpAddr) -> Result<T, MaxMindDBError> {
+        Reader::lookup(self, ip)
+    }
+
+    fn metadata(&self) -> DbMetadata {
+        DbMetadata::from(&self.metadata)
+    }
+
+    fn edition(&self) -> Edition {
+        Edition::of(&self.metadata.database_type)
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        Err(String::from("The database wasn't opened from a file"))
+    }
+}
+
+/// A memory mapped MaxMind database file.
+#[cfg(feature = "mmap")]
+pub struct MaxMindProvider {
+    path: PathBuf,
+    /// Missing until the file is first loaded, when the provider is [MaxMindProvider::pending].
+    reader: RwLock<Option<Arc<Reader<Mmap>>>>,
+}
+
+#[cfg(feature = "mmap")]
+impl MaxMindProvider {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<MaxMindProvider, MaxMindDBError> {
+        let reader = Reader::open_mmap(&path)?;
+
+        Ok(MaxMindProvider {
+            path: path.as_ref().to_path_buf(),
+            reader: RwLock::new(Some(Arc::new(reader))),
+        })
+    }
+
+    /// A provider of a file not there yet, e.g. while it's being downloaded: lookups fail until
+    /// it's loaded with [GeoProvider::reload].
+    pub fn pending<P: AsRef<Path>>(path: P) -> MaxMindProvider {
+        MaxMindProvider {
+            path: path.as_ref().to_path_buf(),
+            reader: RwLock::new(None),
+        }
+    }
+
+    pub fn reader(&self) -> Option<Arc<Reader<Mmap>>> {
+        self.reader.read().unwrap().clone()
+    }
+
+    /// Whether the database was loaded, see [MaxMindProvider::pending].
+    pub fn is_loaded(&self) -> bool {
+        self.reader.read().unwrap().is_some()
+    }
+
+    /// Path of the database file, the one reloaded.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl GeoProvider for MaxMindProvider {
+    fn lookup<T: DeserializeOwned>(&self, ip: IpAddr) -> Result<T, MaxMindDBError> {
+        match self.reader() {
+            Some(reader) => reader.lookup(ip),
+            None => Err(MaxMindDBError::IoError(format!("{} is not loaded yet", self.path.display()))),
+        }
+    }
+
+    fn metadata(&self) -> DbMetadata {
+        self.reader().map_or_else(DbMetadata::default, |reader| DbMetadata::from(&reader.metadata))
+    }
+
+    fn build_epoch(&self) -> u64 {
+        self.reader().map_or(0, |reader| reader.metadata.build_epoch)
+    }
+
+    fn edition(&self) -> Edition {
+        self.reader().map_or(Edition::City, |reader| Edition::of(&reader.metadata.database_type))
+    }
+
+    fn reload(&self) -> Result<(), String> {
+        let reader = Reader::open_mmap(&self.path)
+            .map_err(|err| format!("Can not open {}: {}", self.path.display(), err))?;
+        check(self.reader().as_deref(), &reader)
+            .map_err(|err| format!("Not loading {}: {}", self.path.display(), err))?;
+
+        *self.reader.write().unwrap() = Some(Arc::new(reader));
+
+        Ok(())
+    }
+}
+
+/// IP addresses whose records are read before a database is loaded.
+#[cfg(feature = "mmap")]
+const CHECKED_IPS: [&str; 4] = ["1.1.1.1", "8.8.8.8", "81.2.69.142", "2001:4860:4860::8888"];
+
+/// Checks a database before it replaces `current`: it must be of the same edition, e.g. `City`,
+/// and have records that can be read, so that a truncated or wrong file is never served.
+#[cfg(feature = "mmap")]
+fn check(current: Option<&Reader<Mmap>>, new: &Reader<Mmap>) -> Result<(), String> {
+    let edition = |reader: &Reader<Mmap>| {
+        let database_type = &reader.metadata.database_type;
+        database_type.rsplit('-').next().unwrap_or(database_type).to_string()
+    };
+    if let Some(current) = current {
+        if edition(current) != edition(new) {
+            return Err(format!(
+                "it's a {} database, not a {} one like the loaded one",
+                new.metadata.database_type, current.metadata.database_type
+            ));
+        }
+    }
+    if new.metadata.node_count == 0 {
+        return Err(String::from("it has no records"));
+    }
+
+    for ip in CHECKED_IPS.iter().map(|ip| ip.parse::<IpAddr>().unwrap()) {
+        if ip.is_ipv6() && new.metadata.ip_version != 6 {
+            continue;
+        }
+        match new.lookup::<serde_json::Value>(ip) {
+            Ok(_) | Err(MaxMindDBError::AddressNotFoundError(_)) => {}
+            Err(err) => return Err(format!("can not read the record of {}: {}", ip, err)),
+        }
+    }
+    Ok(())
+}
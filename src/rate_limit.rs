@@ -0,0 +1,220 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Token bucket rate limiting of the calling IP addresses, set with GEOIP_RS_RATE_LIMIT, and the
+//! RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers of
+//! draft-ietf-httpapi-ratelimit-headers, telling the callers where they stand.
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::{header, HeaderMap, HeaderName, HeaderValue};
+use actix_web::HttpResponse;
+
+/// Calling IP addresses tracked before the ones with a full bucket are forgotten: they'd get a
+/// full bucket anyway.
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+/// The oldest IP addresses looked at for a full bucket to forget, when a new one comes past
+/// [MAX_TRACKED_CLIENTS]. When none of them has, the oldest is forgotten anyway.
+const EVICTION_CANDIDATES: usize = 16;
+
+/// Allows bursts of up to `capacity` operations, refilled at `rate` operations per second.
+pub struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: f64, rate: f64) -> TokenBucket {
+        TokenBucket {
+            capacity,
+            rate,
+            tokens: capacity,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returning false if there's none left.
+    pub fn take(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled_at = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// How long until there's a token to take.
+    pub fn retry_after(&self) -> Duration {
+        Duration::from_secs_f64(((1.0 - self.tokens) / self.rate).max(0.0))
+    }
+
+    pub fn capacity(&self) -> f64 {
+        self.capacity
+    }
+
+    /// Tokens left as of the last `take`.
+    pub fn remaining(&self) -> f64 {
+        self.tokens
+    }
+
+    /// How long until the bucket is full again.
+    pub fn reset(&self) -> Duration {
+        Duration::from_secs_f64(((self.capacity - self.tokens) / self.rate).max(0.0))
+    }
+
+    fn full(&self) -> bool {
+        self.refilled_at.elapsed() >= self.reset()
+    }
+}
+
+/// Where a caller stands against its rate limit.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    /// Requests allowed in a burst.
+    pub limit: u64,
+    pub remaining: u64,
+    /// Seconds until `remaining` is back to `limit`.
+    pub reset: u64,
+}
+
+impl RateLimit {
+    pub fn of(bucket: &TokenBucket) -> RateLimit {
+        RateLimit {
+            limit: bucket.capacity() as u64,
+            remaining: bucket.remaining().max(0.0) as u64,
+            reset: bucket.reset().as_secs_f64().ceil() as u64,
+        }
+    }
+
+    /// Sets the RateLimit-* headers.
+    pub fn set_headers(&self, headers: &mut HeaderMap) {
+        let values = [
+            ("ratelimit-limit", self.limit),
+            ("ratelimit-remaining", self.remaining),
+            ("ratelimit-reset", self.reset),
+        ];
+        for &(name, value) in values.iter() {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+}
+
+/// The rate limit of each calling IP address: GEOIP_RS_RATE_LIMIT requests per minute, in bursts
+/// of up to GEOIP_RS_RATE_LIMIT_BURST requests (GEOIP_RS_RATE_LIMIT by default).
+pub struct RateLimiter {
+    burst: f64,
+    /// Requests per second.
+    rate: f64,
+    buckets: Mutex<Buckets>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    map: HashMap<IpAddr, TokenBucket>,
+    /// The IP addresses of `map`, from the first tracked.
+    order: VecDeque<IpAddr>,
+}
+
+impl Buckets {
+    /// Forgets one IP address, looking at a few of the oldest for one with a full bucket.
+    fn evict(&mut self) {
+        for _ in 0..EVICTION_CANDIDATES {
+            let ip = match self.order.pop_front() {
+                Some(ip) => ip,
+                None => return,
+            };
+            if self.map.get(&ip).map_or(true, TokenBucket::full) {
+                self.map.remove(&ip);
+                return;
+            }
+            self.order.push_back(ip);
+        }
+        if let Some(ip) = self.order.pop_front() {
+            self.map.remove(&ip);
+        }
+    }
+}
+
+impl RateLimiter {
+    /// The rate limiter set in the env, if any.
+    pub fn from_env() -> Result<Option<RateLimiter>, String> {
+        let rate = match env::var("GEOIP_RS_RATE_LIMIT") {
+            Ok(rate) => rate,
+            Err(_) => return Ok(None),
+        };
+        let rate = rate
+            .parse::<f64>()
+            .ok()
+            .filter(|rate| *rate > 0.0)
+            .ok_or_else(|| format!("GEOIP_RS_RATE_LIMIT must be a positive number, got {}", rate))?;
+        let burst = match env::var("GEOIP_RS_RATE_LIMIT_BURST") {
+            Ok(burst) => burst
+                .parse::<f64>()
+                .ok()
+                .filter(|burst| *burst >= 1.0)
+                .ok_or_else(|| format!("GEOIP_RS_RATE_LIMIT_BURST must be a number from 1, got {}", burst))?,
+            Err(_) => rate.max(1.0),
+        };
+
+        Ok(Some(RateLimiter::new(rate, burst)))
+    }
+
+    /// Allows `rate` requests per minute, in bursts of up to `burst` requests.
+    pub fn new(rate: f64, burst: f64) -> RateLimiter {
+        RateLimiter {
+            burst,
+            rate: rate / 60.0,
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    /// Takes a token from the bucket of `ip`, returning where it stands, or the `429 Too Many
+    /// Requests` response when it exceeded its rate.
+    pub fn check(&self, ip: IpAddr) -> Result<RateLimit, HttpResponse> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.map.contains_key(&ip) {
+            if buckets.map.len() >= MAX_TRACKED_CLIENTS {
+                buckets.evict();
+            }
+            buckets.order.push_back(ip);
+        }
+
+        let (burst, rate) = (self.burst, self.rate);
+        let bucket = buckets.map.entry(ip).or_insert_with(|| TokenBucket::new(burst, rate));
+        if !bucket.take() {
+            return Err(too_many_requests(bucket.retry_after().as_secs() + 1, RateLimit::of(bucket)));
+        }
+        Ok(RateLimit::of(bucket))
+    }
+}
+
+/// `429 Too Many Requests`, with the seconds to wait before retrying in Retry-After.
+pub fn too_many_requests(retry_after: u64, rate_limit: RateLimit) -> HttpResponse {
+    let mut response = HttpResponse::TooManyRequests()
+        .header(header::RETRY_AFTER, retry_after.to_string())
+        .json(serde_json::json!({ "error": "rate limit exceeded", "retryAfter": retry_after }));
+    rate_limit.set_headers(response.headers_mut());
+    response
+}
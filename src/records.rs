@@ -0,0 +1,58 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Database fields the `maxminddb::geoip2` models don't map, looked up alongside them.
+
+/// Fields of GeoIP2 Enterprise and ISP records: confidence percentages, the user type and the
+/// autonomous system.
+#[derive(Deserialize, Debug, Default)]
+pub struct Extras {
+    pub city: Option<Confidence>,
+    pub country: Option<Confidence>,
+    pub postal: Option<Confidence>,
+    pub traits: Option<Traits>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Confidence {
+    pub confidence: Option<u16>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Traits {
+    pub user_type: Option<String>,
+    pub autonomous_system_number: Option<u32>,
+}
+
+impl Extras {
+    pub fn city(&self) -> Option<u16> {
+        self.city.as_ref().and_then(|city| city.confidence)
+    }
+
+    pub fn country(&self) -> Option<u16> {
+        self.country.as_ref().and_then(|country| country.confidence)
+    }
+
+    pub fn postal(&self) -> Option<u16> {
+        self.postal.as_ref().and_then(|postal| postal.confidence)
+    }
+
+    pub fn user_type(&self) -> Option<String> {
+        self.traits.as_ref().and_then(|traits| traits.user_type.clone())
+    }
+
+    pub fn autonomous_system_number(&self) -> Option<u32> {
+        self.traits.as_ref().and_then(|traits| traits.autonomous_system_number)
+    }
+}
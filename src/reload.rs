@@ -0,0 +1,244 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reloads of the database, each compared against the previous one: a sample of IPv4 addresses
+//! is resolved with both, and the share of changed countries and cities is reported at
+//! `GET /admin/last-reload`, an admin endpoint, and in the metrics. A bad data release shows up
+//! as a jump of the changes.
+//!
+//! Besides the replicas and the downloads, which reload the database they install, the database
+//! file is reloaded on SIGHUP and with `POST /admin/reload`, an admin endpoint. Requests in flight
+//! finish with the database they started with.
+
+use std::env;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use maxminddb::geoip2::City;
+use maxminddb::Reader;
+use memmap::Mmap;
+
+use crate::admin::Admin;
+use crate::provider::{GeoProvider, MaxMindProvider};
+
+const DEFAULT_SAMPLES: u32 = 10_000;
+/// How often the file of a [MaxMindProvider::pending] provider is checked.
+const PENDING_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How the reloaded database differs from the previous one.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ReloadDiff {
+    /// When the reload happened, in seconds since the epoch.
+    pub reloaded_at: u64,
+    pub old_build_epoch: u64,
+    pub new_build_epoch: u64,
+    /// IP addresses resolved with both databases.
+    pub sampled: u32,
+    /// Sampled IP addresses found in either database.
+    pub found: u32,
+    pub countries_changed: u32,
+    pub cities_changed: u32,
+    /// Changed countries, in percent of the IP addresses found.
+    pub countries_changed_percent: f64,
+    /// Changed cities, in percent of the IP addresses found.
+    pub cities_changed_percent: f64,
+}
+
+/// Reloads the database and keeps the diff of the last reload.
+pub struct Reloads {
+    samples: u32,
+    last: Mutex<Option<ReloadDiff>>,
+    /// Run after every reload.
+    listeners: Mutex<Vec<Box<dyn Fn() + Send>>>,
+    /// Held while reloading, so that concurrent reloads compare each database with the one it
+    /// replaced.
+    reloading: Mutex<()>,
+}
+
+impl Reloads {
+    /// Samples GEOIP_RS_RELOAD_SAMPLES IP addresses per reload.
+    pub fn from_env() -> Result<Reloads, String> {
+        let samples = match env::var("GEOIP_RS_RELOAD_SAMPLES") {
+            Ok(samples) => samples
+                .parse::<u32>()
+                .map_err(|_| format!("GEOIP_RS_RELOAD_SAMPLES must be a number, got {}", samples))?,
+            Err(_) => DEFAULT_SAMPLES,
+        };
+
+        Ok(Reloads {
+            samples,
+            last: Mutex::new(None),
+            listeners: Mutex::new(Vec::new()),
+            reloading: Mutex::new(()),
+        })
+    }
+
+    /// Runs `listener` after every reload, e.g. to refresh the data enriching the responses on
+    /// the same schedule as the database.
+    pub fn on_reload<F: Fn() + Send + 'static>(&self, listener: F) {
+        self.listeners.lock().unwrap().push(Box::new(listener));
+    }
+
+    /// Reloads `provider`, comparing the new database with the previous one. The first load of a
+    /// [MaxMindProvider::pending] provider has nothing to compare with, and isn't reported.
+    pub fn reload(&self, provider: &MaxMindProvider) -> Result<ReloadDiff, String> {
+        let _reloading = self.reloading.lock().unwrap();
+        let old = provider.reader();
+        provider.reload()?;
+        let new = provider.reader().ok_or_else(|| String::from("The database was not loaded"))?;
+        self.listeners.lock().unwrap().iter().for_each(|listener| listener());
+
+        let old = match old {
+            Some(old) => old,
+            None => {
+                println!("Loaded the database built at {}", new.metadata.build_epoch);
+                let mut diff = compare(&new, &new, 0);
+                diff.old_build_epoch = 0;
+                return Ok(diff);
+            }
+        };
+        let diff = compare(&old, &new, self.samples);
+        println!(
+            "Reloaded the database built at {}: {:.2}% of countries and {:.2}% of cities changed",
+            diff.new_build_epoch, diff.countries_changed_percent, diff.cities_changed_percent
+        );
+        *self.last.lock().unwrap() = Some(diff.clone());
+
+        Ok(diff)
+    }
+
+    pub fn last(&self) -> Option<ReloadDiff> {
+        self.last.lock().unwrap().clone()
+    }
+}
+
+/// Loads the database of a [MaxMindProvider::pending] provider, on a dedicated thread, as soon as
+/// its file can be opened.
+pub fn load_when_ready(provider: Arc<MaxMindProvider>, reloads: Arc<Reloads>) {
+    thread::spawn(move || {
+        while !provider.is_loaded() {
+            if reloads.reload(&provider).is_err() {
+                thread::sleep(PENDING_CHECK_INTERVAL);
+            }
+        }
+    });
+}
+
+pub async fn last_reload(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    reloads: web::Data<Arc<Reloads>>,
+) -> HttpResponse {
+    admin.run(&req, "last-reload", || match reloads.last() {
+        Some(diff) => HttpResponse::Ok().json(diff),
+        None => HttpResponse::NotFound().json(serde_json::json!({ "error": "the database wasn't reloaded yet" })),
+    })
+}
+
+/// `POST /admin/reload`, an admin endpoint reloading the database file, e.g. once a new one has
+/// been dropped in place, answering with the comparison with the previous one. The previous one
+/// keeps being served when the new one can't be loaded.
+pub async fn reload(
+    req: HttpRequest,
+    admin: web::Data<Admin>,
+    reloads: web::Data<Arc<Reloads>>,
+    provider: web::Data<Arc<MaxMindProvider>>,
+) -> HttpResponse {
+    admin.run(&req, "reload", || match reloads.reload(&provider) {
+        Ok(diff) => HttpResponse::Ok().json(diff),
+        Err(err) => {
+            eprintln!("{}", err);
+            HttpResponse::UnprocessableEntity().json(serde_json::json!({ "error": err }))
+        }
+    })
+}
+
+/// Reloads the database file on SIGHUP, on a thread of its own since the reload resolves the
+/// sampled IP addresses with both databases.
+#[cfg(unix)]
+pub fn reload_on_sighup(provider: Arc<MaxMindProvider>, reloads: Arc<Reloads>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sighup = signal(SignalKind::hangup()).expect("Can not handle SIGHUP");
+    actix_rt::spawn(async move {
+        while sighup.recv().await.is_some() {
+            let (provider, reloads) = (provider.clone(), reloads.clone());
+            thread::spawn(move || {
+                if let Err(err) = reloads.reload(&provider) {
+                    eprintln!("{}, still serving the previous database", err);
+                }
+            });
+        }
+    });
+}
+
+/// Resolves `samples` IPv4 addresses, one at random in each of as many equal ranges, with both
+/// databases.
+fn compare(old: &Reader<Mmap>, new: &Reader<Mmap>, samples: u32) -> ReloadDiff {
+    let mut random = XorShift::seeded();
+    let step = u32::max_value() / samples.max(1);
+
+    let (mut found, mut countries_changed, mut cities_changed) = (0, 0, 0);
+    for i in 0..samples {
+        let ip = IpAddr::V4(Ipv4Addr::from(i * step + random.next_u32() % step));
+        let (old, new) = (old.lookup::<City>(ip).ok(), new.lookup::<City>(ip).ok());
+        if old.is_none() && new.is_none() {
+            continue;
+        }
+
+        found += 1;
+        let country_of = |record: &Option<City>| record.as_ref()?.country.as_ref()?.iso_code.clone();
+        let city_of = |record: &Option<City>| record.as_ref()?.city.as_ref()?.geoname_id;
+        if country_of(&old) != country_of(&new) {
+            countries_changed += 1;
+        }
+        if city_of(&old) != city_of(&new) {
+            cities_changed += 1;
+        }
+    }
+
+    let percent = |changed: u32| if found == 0 { 0.0 } else { f64::from(changed) * 100.0 / f64::from(found) };
+    ReloadDiff {
+        reloaded_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        old_build_epoch: old.metadata.build_epoch,
+        new_build_epoch: new.metadata.build_epoch,
+        sampled: samples,
+        found,
+        countries_changed,
+        cities_changed,
+        countries_changed_percent: percent(countries_changed),
+        cities_changed_percent: percent(cities_changed),
+    }
+}
+
+/// Good enough randomness to pick the sampled addresses.
+struct XorShift(u32);
+
+impl XorShift {
+    fn seeded() -> XorShift {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+        XorShift(nanos | 1)
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Another geoip-rs instance as the upstream of the lookups, enabled with GEOIP_RS_REMOTE set to
+//! its URL, e.g. for edge instances with a small country database escalating to a central
+//! instance with the full city one. Records are fetched from its `/record/{ip}` endpoint, which
+//! answers with the records as they're stored in its database. Answers are cached for a minute,
+//! so that the records a response is decoded from take a single request.
+
+use std::env;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::fallback::{self, Answers, Upstream};
+
+const DEFAULT_TIMEOUT_MS: u64 = 500;
+const CACHE_SIZE: usize = 10_000;
+const CACHE_TTL: Duration = Duration::from_secs(60);
+
+pub struct Remote {
+    url: String,
+    api_key: Option<String>,
+    timeout: Duration,
+    required: Vec<String>,
+    cache: Answers,
+}
+
+impl Remote {
+    pub fn from_env() -> Result<Option<Remote>, String> {
+        let url = match env::var("GEOIP_RS_REMOTE") {
+            Ok(url) => url,
+            Err(_) => return Ok(None),
+        };
+        let timeout = match env::var("GEOIP_RS_REMOTE_TIMEOUT_MS") {
+            Ok(timeout) => timeout
+                .parse()
+                .map_err(|_| format!("GEOIP_RS_REMOTE_TIMEOUT_MS must be a number, got {}", timeout))?,
+            Err(_) => DEFAULT_TIMEOUT_MS,
+        };
+
+        Ok(Some(Remote {
+            url: url.trim_end_matches('/').to_string(),
+            api_key: env::var("GEOIP_RS_REMOTE_API_KEY").ok(),
+            timeout: Duration::from_millis(timeout),
+            required: fallback::required_fields("GEOIP_RS_REMOTE_FIELDS"),
+            cache: Answers::new(CACHE_SIZE, CACHE_TTL),
+        }))
+    }
+}
+
+impl Upstream for Remote {
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        if let Some(record) = self.cache.get(ip) {
+            return Ok(record);
+        }
+
+        let mut request = ureq::get(&format!("{}/record/{}", self.url, ip));
+        request.timeout(self.timeout);
+        if let Some(api_key) = &self.api_key {
+            request.set("Authorization", &format!("Bearer {}", api_key));
+        }
+
+        let response = request.call();
+        let record = match response.status() {
+            200 => Some(
+                response
+                    .into_json()
+                    .map_err(|err| format!("Can not parse the record of {} from {}: {}", ip, self.url, err))?,
+            ),
+            404 => None,
+            status => {
+                return Err(match response.synthetic_error() {
+                    Some(err) => format!("Can not look up {} in {}: {}", ip, self.url, err),
+                    None => format!("Can not look up {} in {}: HTTP {}", ip, self.url, status),
+                })
+            }
+        };
+
+        self.cache.insert(ip, record.clone());
+        Ok(record)
+    }
+
+    fn required(&self) -> &[String] {
+        &self.required
+    }
+}
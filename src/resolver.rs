@@ -0,0 +1,506 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Resolution of IP addresses into the geographical information of their records in a MaxMind
+//! GeoIP2 or GeoLite2 database, as returned by the geoip-rs server.
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr};
+
+use maxminddb::geoip2::model::Subdivision;
+use maxminddb::geoip2::City;
+use maxminddb::geoip2::Country;
+use maxminddb::MaxMindDBError;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::centroids::centroid;
+use crate::country_names::country_name;
+use crate::provider::{Edition, GeoProvider};
+use crate::records::Extras;
+use crate::response::{CityResponse, CountryResponse, LookupErrorResponse, NonResolvedIPResponse, ResolvedIPResponse};
+
+/// How IP addresses are resolved. The defaults are the ones of the geoip-rs server.
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Resolve the IPv4 address embedded in 6to4 and Teredo addresses (GEOIP_RS_RESOLVE_TUNNELS).
+    pub resolve_tunnels: bool,
+    /// Subdivision codes without the country code, `25` rather than the ISO 3166-2 `IT-25`, as
+    /// geoip-rs used to return them (GEOIP_RS_SHORT_SUBDIVISION_CODES).
+    pub short_subdivision_codes: bool,
+    /// The coordinates of the center of the country for records with no location, marked with
+    /// `locationPrecision: "country"` (GEOIP_RS_COUNTRY_CENTROIDS).
+    pub country_centroids: bool,
+}
+
+/// Resolves IP addresses into typed responses, for the Rust services embedding the lookups: the
+/// same ones as [resolve], which builds the body of `/{ip}` out of them. Responses have all of
+/// their fields whatever the database, with empty city fields for country databases.
+pub struct GeoIpResolver<P> {
+    db: P,
+    options: Options,
+}
+
+impl<P: GeoProvider> GeoIpResolver<P> {
+    /// Resolves with `db` and the default [Options], the ones of the geoip-rs server.
+    pub fn new(db: P) -> GeoIpResolver<P> {
+        GeoIpResolver::with_options(db, Options::default())
+    }
+
+    pub fn with_options(db: P, options: Options) -> GeoIpResolver<P> {
+        GeoIpResolver { db, options }
+    }
+
+    /// The record of `ip`, with names in `lang` where the database has them, `None` when it has
+    /// no record of `ip` or it can't be read, see [GeoIpResolver::try_resolve].
+    pub fn resolve(&self, ip: IpAddr, lang: &str) -> Option<ResolvedIPResponse> {
+        self.try_resolve(ip, lang).ok().flatten()
+    }
+
+    /// The record of `ip`, `None` when the database has none, failing when it can't be read.
+    pub fn try_resolve(&self, ip: IpAddr, lang: &str) -> Result<Option<ResolvedIPResponse>, String> {
+        let geoip = resolve_full(&self.db, &self.options, &ip.to_string(), lang);
+        if let Some(err) = geoip.get("lookupError").and_then(Value::as_str) {
+            return Err(err.to_string());
+        }
+        if geoip.get("ip_address").is_some() {
+            return Ok(None);
+        }
+        serde_json::from_value(geoip).map(Some).map_err(|err| err.to_string())
+    }
+
+    /// The database resolved with.
+    pub fn db(&self) -> &P {
+        &self.db
+    }
+}
+
+/// Parses an IP address, normalizing IPv4-mapped IPv6 addresses (`::ffff:a.b.c.d`) to IPv4.
+fn parse_ip(ip_address: &str) -> Option<IpAddr> {
+    ip_address.trim().parse().ok().map(unmapped)
+}
+
+/// `ip`, or the IPv4 address of an IPv4-mapped IPv6 one (`::ffff:a.b.c.d`), e.g. of the peers of
+/// a dual-stack socket.
+pub(crate) fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
+}
+
+/// Returns the IP address to look up in place of `ip`, along with the tunneling mechanism when
+/// it's the IPv4 address embedded in a 6to4 (2002::/16) or Teredo (2001::/32) address.
+fn tunneled_ip(options: &Options, ip: Option<IpAddr>) -> (Option<IpAddr>, Option<String>) {
+    let tunnel = match ip {
+        Some(IpAddr::V6(ip)) if options.resolve_tunnels => match ip.segments() {
+            [0x2002, high, low, ..] => Some(("6to4", (u32::from(high) << 16) | u32::from(low))),
+            [0x2001, 0, .., high, low] => Some(("teredo", !((u32::from(high) << 16) | u32::from(low)))),
+            _ => None,
+        },
+        _ => None,
+    };
+
+    match tunnel {
+        Some((tunnel, ipv4)) => (Some(IpAddr::V4(Ipv4Addr::from(ipv4))), Some(tunnel.to_string())),
+        None => (ip, None),
+    }
+}
+
+/// Looks up `ip`, telling IP addresses not in the database, `None`, apart from errors reading it,
+/// e.g. of a corrupted file.
+fn lookup<P: GeoProvider, T: DeserializeOwned>(db: &P, ip: Option<IpAddr>) -> Result<Option<T>, MaxMindDBError> {
+    match ip.map(|ip| db.lookup(ip)) {
+        Some(Ok(record)) => Ok(Some(record)),
+        Some(Err(MaxMindDBError::AddressNotFoundError(_))) | None => Ok(None),
+        Some(Err(err)) => Err(err),
+    }
+}
+
+/// The body of an IP address with no record, `ip` being the one looked up, if it's one.
+pub(crate) fn not_resolved(ip_address: &str, ip: Option<IpAddr>) -> serde_json::Result<Value> {
+    let range = ip.and_then(special_range);
+    let error = match (ip, range) {
+        (None, _) => "invalid_ip",
+        (Some(_), Some(_)) => "reserved_range",
+        (Some(_), None) => "not_found",
+    };
+    serde_json::to_value(&NonResolvedIPResponse {
+        ip_address: ip_address.to_string(),
+        error: error.to_string(),
+        range: range.map(String::from),
+    })
+}
+
+/// The special-purpose range of RFC 6890 `ip` belongs to, if any: IP addresses in those are
+/// never in the databases, as opposed to the public ones a database happens to miss.
+pub fn special_range(ip: IpAddr) -> Option<&'static str> {
+    let range = match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            if ip.is_private() {
+                "private"
+            } else if ip.is_loopback() {
+                "loopback"
+            } else if ip.is_link_local() {
+                "link_local"
+            } else if first == 100 && second & 0xc0 == 64 {
+                "shared"
+            } else if ip.is_documentation() {
+                "documentation"
+            } else if ip.is_multicast() {
+                "multicast"
+            } else if first == 0 || first >= 240 {
+                "reserved"
+            } else {
+                return None;
+            }
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if ip.is_loopback() {
+                "loopback"
+            } else if segments[0] & 0xfe00 == 0xfc00 {
+                "private"
+            } else if segments[0] & 0xffc0 == 0xfe80 {
+                "link_local"
+            } else if segments[0] == 0x2001 && segments[1] == 0x0db8 {
+                "documentation"
+            } else if ip.is_multicast() {
+                "multicast"
+            } else if ip.is_unspecified() {
+                "reserved"
+            } else {
+                return None;
+            }
+        }
+    };
+    Some(range)
+}
+
+fn lookup_error(ip_address: &str, err: MaxMindDBError) -> Value {
+    serde_json::to_value(&LookupErrorResponse {
+        ip_address: ip_address.to_string(),
+        lookup_error: err.to_string(),
+    })
+    .unwrap()
+}
+
+/// The ISO 3166-2 code of a subdivision, e.g. `IT-25`, or its short form `25` with
+/// [Options::short_subdivision_codes].
+fn subdivision_code(options: &Options, geoip: &City, subdivision: Option<&Subdivision>) -> String {
+    let code = match subdivision.and_then(|subdiv| subdiv.iso_code.as_ref()) {
+        Some(code) => code,
+        None => return String::new(),
+    };
+    let country_code = geoip.country.as_ref().and_then(|country| country.iso_code.as_ref());
+    match country_code {
+        Some(country_code) if !options.short_subdivision_codes => format!("{}-{}", country_code, code),
+        _ => code.clone(),
+    }
+}
+
+/// The latitude and longitude of the record, and their precision when they're not the ones of the
+/// record but the center of its country. `0.0` when unknown.
+fn coordinates(options: &Options, geoip: &City) -> (f64, f64, Option<String>) {
+    let location = geoip.location.as_ref();
+    if let (Some(latitude), Some(longitude)) =
+        (location.and_then(|loc| loc.latitude), location.and_then(|loc| loc.longitude))
+    {
+        return (latitude, longitude, None);
+    }
+
+    let centroid = geoip
+        .country
+        .as_ref()
+        .and_then(|country| country.iso_code.as_ref())
+        .filter(|_| options.country_centroids)
+        .and_then(|code| centroid(code));
+    match centroid {
+        Some((latitude, longitude)) => (latitude, longitude, Some(String::from("country"))),
+        None => (0.0, 0.0, None),
+    }
+}
+
+/// `language` written the way the databases write language tags, e.g. `pt-BR` for `pt_br`, or
+/// `None` when it isn't a language tag.
+pub fn normalize_language(language: &str) -> Option<String> {
+    let mut subtags = language.trim().split(|c| c == '-' || c == '_');
+    let primary = subtags.next()?;
+    if !(2..=3).contains(&primary.len()) || !primary.chars().all(|c| c.is_ascii_alphabetic()) {
+        return None;
+    }
+
+    let mut normalized = primary.to_ascii_lowercase();
+    for subtag in subtags {
+        if !(2..=8).contains(&subtag.len()) || !subtag.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        normalized.push('-');
+        match subtag.len() {
+            // Region, e.g. `BR`
+            2 => normalized.push_str(&subtag.to_ascii_uppercase()),
+            // Script, e.g. `Hant`
+            4 => {
+                normalized.push_str(&subtag[..1].to_ascii_uppercase());
+                normalized.push_str(&subtag[1..].to_ascii_lowercase());
+            }
+            _ => normalized.push_str(&subtag.to_ascii_lowercase()),
+        }
+    }
+    Some(normalized)
+}
+
+/// The name in `language`, or in its primary language, e.g. `pt` for `pt-BR`.
+fn name_in<'a>(names: Option<&'a BTreeMap<String, String>>, language: &str) -> Option<&'a String> {
+    let names = names?;
+    names.get(language).or_else(|| language.split('-').next().and_then(|primary| names.get(primary)))
+}
+
+/// The name in `language`, falling back to the english one, empty when there's neither.
+fn localized(names: Option<&BTreeMap<String, String>>, language: &str) -> String {
+    name_in(names, language)
+        .or_else(|| names.and_then(|names| names.get("en")))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// The country name in `language`, from the database, or else from the country names of
+/// [crate::country_names], falling back to the english one.
+fn country_label(names: Option<&BTreeMap<String, String>>, code: Option<&String>, language: &str) -> String {
+    name_in(names, language)
+        .cloned()
+        .or_else(|| code.and_then(|code| country_name(language, code)))
+        .or_else(|| names.and_then(|names| names.get("en")).cloned())
+        .unwrap_or_default()
+}
+
+/// Resolves `ip_address` into the response to `/` and `/{ip}`, a JSON object with the
+/// `ip_address` and the `error` telling why when it has no record. With a country database, it's
+/// the response to `/country/{ip}`, rather than one with empty city fields, unless the
+/// coordinates of the country are asked for with [Options::country_centroids].
+pub fn resolve<P: GeoProvider>(db: &P, options: &Options, ip_address: &str, language: &str) -> Value {
+    if db.edition() == Edition::Country && !options.country_centroids {
+        return resolve_country(db, options, ip_address, language);
+    }
+    resolve_full(db, options, ip_address, language)
+}
+
+/// The response to `/` and `/{ip}` with all of its fields, whatever the edition of `db`.
+fn resolve_full<P: GeoProvider>(db: &P, options: &Options, ip_address: &str, language: &str) -> Value {
+    let language = &normalize_language(language).unwrap_or_else(|| String::from("en"));
+    let ip = parse_ip(ip_address);
+    let ip_address = &ip.map(|ip| ip.to_string()).unwrap_or_else(|| ip_address.to_string());
+    let (lookup_ip, tunnel) = tunneled_ip(options, ip);
+    let lookup: Option<City> = match lookup(db, lookup_ip) {
+        Ok(lookup) => lookup,
+        Err(err) => return lookup_error(ip_address, err),
+    };
+
+    match lookup {
+        Some(geoip) => {
+            // Decoded apart, only from the records having them
+            let extras: Extras = if db.has_extras() {
+                lookup_ip.and_then(|ip| db.lookup(ip).ok()).unwrap_or_default()
+            } else {
+                Extras::default()
+            };
+
+            let region = geoip
+                .subdivisions
+                .as_ref()
+                .filter(|subdivs| !subdivs.is_empty())
+                .and_then(|subdivs| subdivs.get(0));
+
+            let province = geoip
+                .subdivisions
+                .as_ref()
+                .filter(|subdivs| subdivs.len() > 1)
+                .and_then(|subdivs| subdivs.get(1));
+
+            let country_code = geoip.country.as_ref().and_then(|country| country.iso_code.as_ref());
+            let country_names = geoip.country.as_ref().and_then(|country| country.names.as_ref());
+
+            let (latitude, longitude, location_precision) = coordinates(options, &geoip);
+
+            let res = ResolvedIPResponse {
+                ip_address: ip_address.to_string(),
+                tunnel,
+                latitude,
+                longitude,
+                location_precision,
+                postal_code: geoip
+                    .postal
+                    .as_ref()
+                    .and_then(|postal| postal.code.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                continent_code: geoip
+                    .continent
+                    .as_ref()
+                    .and_then(|cont| cont.code.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                continent_name: localized(geoip.continent.as_ref().and_then(|cont| cont.names.as_ref()), language),
+                country_code: country_code.cloned().unwrap_or_default(),
+                country_label: country_label(country_names, country_code, language),
+                country_name: country_names
+                    .and_then(|names| names.get("en"))
+                    .cloned()
+                    .unwrap_or_else(|| country_label(country_names, country_code, language)),
+                region_code: subdivision_code(options, &geoip, region),
+                region_name: localized(region.and_then(|subdiv| subdiv.names.as_ref()), language),
+                province_code: subdivision_code(options, &geoip, province),
+                province_name: localized(province.and_then(|subdiv| subdiv.names.as_ref()), language),
+                city_name: localized(geoip.city.as_ref().and_then(|city| city.names.as_ref()), language),
+                time_zone: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.time_zone.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                is_anonymous_proxy: geoip
+                    .traits
+                    .as_ref()
+                    .and_then(|traits| traits.is_anonymous_proxy)
+                    .unwrap_or(false),
+                is_satellite_provider: geoip
+                    .traits
+                    .as_ref()
+                    .and_then(|traits| traits.is_satellite_provider)
+                    .unwrap_or(false),
+                country_confidence: extras.country(),
+                city_confidence: extras.city(),
+                postal_confidence: extras.postal(),
+                user_type: extras.user_type(),
+                autonomous_system_number: extras.autonomous_system_number(),
+            };
+            serde_json::to_value(&res)
+        }
+        None => not_resolved(ip_address, lookup_ip),
+    }
+        .unwrap()
+}
+
+/// Resolves `ip_address` into the response to `/country/{ip}`.
+pub fn resolve_country<P: GeoProvider>(db: &P, options: &Options, ip_address: &str, language: &str) -> Value {
+    let language = &normalize_language(language).unwrap_or_else(|| String::from("en"));
+    let ip = parse_ip(ip_address);
+    let ip_address = &ip.map(|ip| ip.to_string()).unwrap_or_else(|| ip_address.to_string());
+    let (lookup_ip, tunnel) = tunneled_ip(options, ip);
+    let lookup: Option<Country> = match lookup(db, lookup_ip) {
+        Ok(lookup) => lookup,
+        Err(err) => return lookup_error(ip_address, err),
+    };
+
+    match lookup {
+        Some(geoip) => {
+            let country_code = geoip.country.as_ref().and_then(|country| country.iso_code.as_ref());
+            let country_names = geoip.country.as_ref().and_then(|country| country.names.as_ref());
+
+            serde_json::to_value(&CountryResponse {
+                ip_address: ip_address.to_string(),
+                tunnel,
+                continent_code: geoip
+                    .continent
+                    .as_ref()
+                    .and_then(|cont| cont.code.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                continent_name: localized(geoip.continent.as_ref().and_then(|cont| cont.names.as_ref()), language),
+                country_label: country_label(country_names, country_code, language),
+                country_name: country_names
+                    .and_then(|names| names.get("en"))
+                    .cloned()
+                    .unwrap_or_else(|| country_label(country_names, country_code, language)),
+                country_code: country_code.cloned().unwrap_or_default(),
+                is_anonymous_proxy: geoip
+                    .traits
+                    .as_ref()
+                    .and_then(|traits| traits.is_anonymous_proxy)
+                    .unwrap_or(false),
+                is_satellite_provider: geoip
+                    .traits
+                    .as_ref()
+                    .and_then(|traits| traits.is_satellite_provider)
+                    .unwrap_or(false),
+            })
+        }
+        None => not_resolved(ip_address, lookup_ip),
+    }
+        .unwrap()
+}
+
+/// Resolves `ip_address` into the response to `/city/{ip}`.
+pub fn resolve_city<P: GeoProvider>(db: &P, options: &Options, ip_address: &str, language: &str) -> Value {
+    let language = &normalize_language(language).unwrap_or_else(|| String::from("en"));
+    let ip = parse_ip(ip_address);
+    let ip_address = &ip.map(|ip| ip.to_string()).unwrap_or_else(|| ip_address.to_string());
+    let (lookup_ip, tunnel) = tunneled_ip(options, ip);
+    let lookup: Option<City> = match lookup(db, lookup_ip) {
+        Ok(lookup) => lookup,
+        Err(err) => return lookup_error(ip_address, err),
+    };
+
+    match lookup {
+        Some(geoip) => {
+            let region = geoip
+                .subdivisions
+                .as_ref()
+                .filter(|subdivs| !subdivs.is_empty())
+                .and_then(|subdivs| subdivs.get(0));
+
+            let province = geoip
+                .subdivisions
+                .as_ref()
+                .filter(|subdivs| subdivs.len() > 1)
+                .and_then(|subdivs| subdivs.get(1));
+
+            let (latitude, longitude, location_precision) = coordinates(options, &geoip);
+
+            serde_json::to_value(&CityResponse {
+                ip_address: ip_address.to_string(),
+                tunnel,
+                latitude,
+                longitude,
+                location_precision,
+                postal_code: geoip
+                    .postal
+                    .as_ref()
+                    .and_then(|postal| postal.code.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                country_code: geoip
+                    .country
+                    .as_ref()
+                    .and_then(|country| country.iso_code.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+                region_code: subdivision_code(options, &geoip, region),
+                region_name: localized(region.and_then(|subdiv| subdiv.names.as_ref()), language),
+                province_code: subdivision_code(options, &geoip, province),
+                province_name: localized(province.and_then(|subdiv| subdiv.names.as_ref()), language),
+                city_name: localized(geoip.city.as_ref().and_then(|city| city.names.as_ref()), language),
+                time_zone: geoip
+                    .location
+                    .as_ref()
+                    .and_then(|loc| loc.time_zone.as_ref())
+                    .cloned()
+                    .unwrap_or_default(),
+            })
+        }
+        None => not_resolved(ip_address, lookup_ip),
+    }
+        .unwrap()
+}
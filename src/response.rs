@@ -0,0 +1,148 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Response bodies of the HTTP API. They implement both `Serialize` and `Deserialize`, so
+//! clients of the API can parse responses with the very same types the server writes them with.
+
+/// Body returned for an IP address that has no record in the database, telling why in `error`:
+/// `invalid_ip` when it's not an IP address, `reserved_range` when it's in a private or another
+/// special-purpose range, named in `range`, that databases have no records of, and `not_found`
+/// for the other ones.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct NonResolvedIPResponse {
+    pub ip_address: String,
+    #[serde(default)]
+    pub error: String,
+    /// `private`, `loopback`, `link_local`, `shared`, `documentation`, `multicast` or `reserved`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range: Option<String>,
+}
+
+/// Body of an IP address whose record can not be read, e.g. from a corrupted database, as
+/// opposed to one not in the database. The server answers it with a 500 problem response.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LookupErrorResponse {
+    pub ip_address: String,
+    pub lookup_error: String,
+}
+
+/// Body returned by `/` and `/{ip}` for an IP address found in the database.
+/// Fields missing from the record are empty strings, and `0.0` for coordinates.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ResolvedIPResponse {
+    pub ip_address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// `country` when the record has no location and the coordinates are the center of its
+    /// country, with GEOIP_RS_COUNTRY_CENTROIDS set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_precision: Option<String>,
+    pub postal_code: String,
+    pub continent_code: String,
+    pub continent_name: String,
+    pub country_code: String,
+    /// Country name in the requested language.
+    pub country_label: String,
+    /// Country name in english.
+    pub country_name: String,
+    /// First level subdivision, as an ISO 3166-2 code like `IT-25`.
+    pub region_code: String,
+    pub region_name: String,
+    /// Second level subdivision, as an ISO 3166-2 code like `IT-MI`.
+    pub province_code: String,
+    pub province_name: String,
+    pub city_name: String,
+    pub time_zone: String,
+    /// Legacy flag of IP addresses belonging to anonymous proxies.
+    #[serde(default)]
+    pub is_anonymous_proxy: bool,
+    /// Legacy flag of IP addresses belonging to satellite internet providers.
+    #[serde(default)]
+    pub is_satellite_provider: bool,
+    /// Tunneling mechanism (`6to4` or `teredo`) of an IPv6 address resolved through the
+    /// IPv4 address it embeds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<String>,
+    /// Confidence, from 0 to 100, that the country is correct. Enterprise databases only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub country_confidence: Option<u16>,
+    /// Confidence, from 0 to 100, that the city is correct. Enterprise databases only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city_confidence: Option<u16>,
+    /// Confidence, from 0 to 100, that the postal code is correct. Enterprise databases only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub postal_confidence: Option<u16>,
+    /// Kind of user of the network, e.g. `residential`, `business`, `cellular` or `hosting`.
+    /// Enterprise databases only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_type: Option<String>,
+    /// Number of the autonomous system of the network. Enterprise and ISP databases only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autonomous_system_number: Option<u32>,
+}
+
+/// Body returned by `/country/{ip}` for an IP address found in the database.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CountryResponse {
+    pub ip_address: String,
+    pub continent_code: String,
+    pub continent_name: String,
+    pub country_code: String,
+    pub country_label: String,
+    pub country_name: String,
+    /// See [ResolvedIPResponse::is_anonymous_proxy].
+    #[serde(default)]
+    pub is_anonymous_proxy: bool,
+    /// See [ResolvedIPResponse::is_satellite_provider].
+    #[serde(default)]
+    pub is_satellite_provider: bool,
+    /// See [ResolvedIPResponse::tunnel].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<String>,
+}
+
+/// Body returned by `/city/{ip}` for an IP address found in the database.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CityResponse {
+    pub ip_address: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    /// See [ResolvedIPResponse::location_precision].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location_precision: Option<String>,
+    pub postal_code: String,
+    pub country_code: String,
+    pub region_code: String,
+    pub region_name: String,
+    pub province_code: String,
+    pub province_name: String,
+    pub city_name: String,
+    pub time_zone: String,
+    /// See [ResolvedIPResponse::tunnel].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tunnel: Option<String>,
+}
+
+/// Any body returned by `/` and `/{ip}`, for deserializing responses without knowing in
+/// advance whether the IP address was found.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum LookupResponse {
+    Resolved(Box<ResolvedIPResponse>),
+    NonResolved(NonResolvedIPResponse),
+}
@@ -0,0 +1,248 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /reverse?lat=..&lon=..`: the location closest to some coordinates among the ones of the
+//! records of the database, for callers with coordinates but no IP addresses. The response is
+//! the one of a network located there, with the `network` and its `distanceKm`. Served with
+//! GEOIP_RS_REVERSE_GEOCODING: the locations are indexed in a k-d tree, built on a dedicated
+//! thread at startup and again once another database is loaded.
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use ipnetwork::IpNetwork;
+use maxminddb::geoip2::City;
+use maxminddb::Reader;
+use serde_json::{json, Value};
+
+use crate::api_keys::KeyStore;
+use crate::networks::{self, Tree};
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::resolver::{resolve, Options};
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+#[derive(Deserialize)]
+struct ReverseParams {
+    lat: f64,
+    lon: f64,
+    lang: Option<String>,
+}
+
+/// A location of the database, and one of the networks located there.
+struct Location {
+    latitude: f64,
+    longitude: f64,
+    network: IpNetwork,
+    /// Position on the unit sphere, where the closest point is the closest location.
+    point: [f64; 3],
+}
+
+struct Index {
+    build_epoch: u64,
+    /// Arranged as an implicit k-d tree: the median of each slice splits it along an axis.
+    locations: Vec<Location>,
+}
+
+pub struct Reverse {
+    provider: Arc<MaxMindProvider>,
+    index: Mutex<Option<Arc<Index>>>,
+    building: AtomicBool,
+}
+
+impl Reverse {
+    /// Starts indexing the database of `provider`.
+    pub fn new(provider: Arc<MaxMindProvider>) -> Arc<Reverse> {
+        let reverse = Arc::new(Reverse {
+            provider,
+            index: Mutex::new(None),
+            building: AtomicBool::new(false),
+        });
+        reverse.clone().refresh();
+        reverse
+    }
+
+    /// The index of the locations, rebuilding it once another database is loaded. The previous
+    /// one is used in the meantime.
+    fn index(self: &Arc<Reverse>) -> Option<Arc<Index>> {
+        let index = self.index.lock().unwrap().clone();
+        if index.as_ref().map_or(true, |index| index.build_epoch != self.provider.build_epoch()) {
+            self.clone().refresh();
+        }
+        index
+    }
+
+    fn refresh(self: Arc<Reverse>) {
+        if self.building.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        thread::spawn(move || {
+            match build(&self.provider) {
+                Ok(index) => {
+                    println!("Indexed {} locations for reverse geocoding", index.locations.len());
+                    *self.index.lock().unwrap() = Some(Arc::new(index));
+                }
+                Err(err) => eprintln!("Can not index the locations for reverse geocoding: {}", err),
+            }
+            self.building.store(false, Ordering::SeqCst);
+        });
+    }
+}
+
+pub async fn reverse(
+    req: HttpRequest,
+    reverse: web::Data<Arc<Reverse>>,
+    keys: web::Data<Arc<KeyStore>>,
+) -> HttpResponse {
+    let key = match keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let params: ReverseParams = match serde_urlencoded::from_str(req.query_string()) {
+        Ok(params) => params,
+        Err(err) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("lat and lon must be numbers: {}", err))
+        }
+    };
+    if !(-90.0..=90.0).contains(&params.lat) || !(-180.0..=180.0).contains(&params.lon) {
+        return HttpResponse::BadRequest()
+            .content_type("text/plain; charset=utf-8")
+            .body("lat must be within -90 and 90, lon within -180 and 180");
+    }
+
+    let index = match reverse.get_ref().index() {
+        Some(index) => index,
+        None => {
+            return HttpResponse::ServiceUnavailable()
+                .header(header::RETRY_AFTER, "30")
+                .json(json!({ "error": "the locations are still being indexed" }))
+        }
+    };
+    let location = match nearest(&index.locations, &point(params.lat, params.lon)) {
+        Some(location) => location,
+        None => return HttpResponse::NotFound().json(json!({ "error": "the database has no locations" })),
+    };
+
+    // The response of the network, as if it was looked up
+    let language = params.lang.as_deref().unwrap_or("en");
+    let mut geoip = resolve(reverse.provider.as_ref(), &Options::default(), &location.network.ip().to_string(), language);
+    if let Value::Object(response) = &mut geoip {
+        response.remove("ipAddress");
+        response.remove("ip_address");
+        response.insert(String::from("network"), Value::from(location.network.to_string()));
+        let distance = distance_km(params.lat, params.lon, location.latitude, location.longitude);
+        response.insert(String::from("distanceKm"), Value::from(distance));
+    }
+    if let Some(key) = &key {
+        keys.restrict(key, &mut geoip);
+    }
+    HttpResponse::Ok().json(geoip)
+}
+
+/// Indexes the distinct locations of the records of the database.
+fn build(provider: &MaxMindProvider) -> Result<Index, String> {
+    let mmap = networks::map(provider.path())?;
+    let reader = Reader::from_source(&mmap[..]).map_err(|err| err.to_string())?;
+
+    let (mut records, mut coordinates) = (HashSet::new(), HashSet::new());
+    let mut locations = Vec::new();
+    Tree::new(&mmap, &reader.metadata).walk(|network, record| {
+        if !records.insert(record) {
+            return true;
+        }
+        let city: City = match reader.lookup(network.ip()) {
+            Ok(city) => city,
+            Err(_) => return true,
+        };
+        let location = city.location.as_ref();
+        let latitude_longitude = location.and_then(|location| Some((location.latitude?, location.longitude?)));
+        if let Some((latitude, longitude)) = latitude_longitude {
+            if coordinates.insert((latitude.to_bits(), longitude.to_bits())) {
+                locations.push(Location {
+                    latitude,
+                    longitude,
+                    network,
+                    point: point(latitude, longitude),
+                });
+            }
+        }
+        true
+    });
+
+    arrange(&mut locations, 0);
+    Ok(Index {
+        build_epoch: reader.metadata.build_epoch,
+        locations,
+    })
+}
+
+fn arrange(locations: &mut [Location], axis: usize) {
+    if locations.len() <= 1 {
+        return;
+    }
+    let median = locations.len() / 2;
+    locations.select_nth_unstable_by(median, |a, b| a.point[axis].total_cmp(&b.point[axis]));
+    let (before, after) = locations.split_at_mut(median);
+    arrange(before, (axis + 1) % 3);
+    arrange(&mut after[1..], (axis + 1) % 3);
+}
+
+fn nearest<'a>(locations: &'a [Location], target: &[f64; 3]) -> Option<&'a Location> {
+    let mut best = None;
+    search(locations, target, 0, &mut best);
+    best.map(|(_, location)| location)
+}
+
+fn search<'a>(locations: &'a [Location], target: &[f64; 3], axis: usize, best: &mut Option<(f64, &'a Location)>) {
+    if locations.is_empty() {
+        return;
+    }
+    let median = locations.len() / 2;
+    let location = &locations[median];
+
+    let distance: f64 = (0..3).map(|i| (location.point[i] - target[i]).powi(2)).sum();
+    if best.map_or(true, |(best, _)| distance < best) {
+        *best = Some((distance, location));
+    }
+
+    let offset = target[axis] - location.point[axis];
+    let (near, far) = if offset < 0.0 {
+        (&locations[..median], &locations[median + 1..])
+    } else {
+        (&locations[median + 1..], &locations[..median])
+    };
+    search(near, target, (axis + 1) % 3, best);
+    if best.map_or(true, |(best, _)| offset * offset < best) {
+        search(far, target, (axis + 1) % 3, best);
+    }
+}
+
+fn point(latitude: f64, longitude: f64) -> [f64; 3] {
+    let (latitude, longitude) = (latitude.to_radians(), longitude.to_radians());
+    [latitude.cos() * longitude.cos(), latitude.cos() * longitude.sin(), latitude.sin()]
+}
+
+/// Great-circle distance, with the haversine formula.
+fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (dlat, dlon) = ((lat2 - lat1).to_radians(), (lon2 - lon1).to_radians());
+    let a = (dlat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
@@ -0,0 +1,197 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `GET /risk`: a 0 to 100 risk score of an IP address, combining the signals geoip-rs has or can
+//! load, with the reasons behind it. Signals and their weights are configured in the JSON file
+//! set in GEOIP_RS_RISK, e.g.
+//! `{"torExits": "/var/lib/geoip-rs/tor-exits.txt", "blocklists": {"drop": "/var/lib/geoip-rs/drop.txt"},
+//! "hostingAsns": [16509, 14061], "countries": {"KP": 60}, "weights": {"tor": 80}}`.
+//! Each signal found adds its weight to the score as an independent probability, so that the
+//! score grows with the signals but never goes past 100.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
+use maxminddb::geoip2::City;
+use serde_json::{json, Value};
+
+use crate::hooks::{AnonymousIp, AnonymousIpDatabase};
+use crate::provider::GeoProvider;
+use crate::records::Extras;
+
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+struct RiskConfig {
+    /// Exit node addresses of the Tor network, one per line.
+    tor_exits: Option<String>,
+    /// Files of blocked networks or addresses, one per line, by name.
+    #[serde(default)]
+    blocklists: BTreeMap<String, String>,
+    /// Autonomous systems of hosting providers, for databases with the autonomous system.
+    #[serde(default)]
+    hosting_asns: HashSet<u32>,
+    /// Weight of the countries, by ISO code.
+    #[serde(default)]
+    countries: HashMap<String, u8>,
+    /// Weight of the signals, overriding the default ones.
+    #[serde(default)]
+    weights: HashMap<String, u8>,
+}
+
+/// Default weights of the signals.
+const WEIGHTS: [(&str, u8); 7] = [
+    ("anonymousProxy", 60),
+    ("publicProxy", 60),
+    ("vpn", 40),
+    ("tor", 70),
+    ("hosting", 30),
+    ("blocklist", 80),
+    ("satelliteProvider", 10),
+];
+
+pub struct Risk {
+    tor_exits: Vec<IpNetwork>,
+    blocklists: Vec<(String, Vec<IpNetwork>)>,
+    hosting_asns: HashSet<u32>,
+    countries: HashMap<String, u8>,
+    weights: HashMap<String, u8>,
+}
+
+impl Risk {
+    pub fn from_env() -> Result<Risk, String> {
+        let config = match env::var("GEOIP_RS_RISK") {
+            Ok(path) => {
+                let content = fs::read_to_string(&path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+                serde_json::from_str(&content).map_err(|err| format!("Can not parse {}: {}", path, err))?
+            }
+            Err(_) => RiskConfig::default(),
+        };
+
+        let mut weights: HashMap<String, u8> =
+            WEIGHTS.iter().map(|(signal, weight)| (signal.to_string(), *weight)).collect();
+        weights.extend(config.weights);
+
+        Ok(Risk {
+            tor_exits: match &config.tor_exits {
+                Some(path) => networks(path)?,
+                None => Vec::new(),
+            },
+            blocklists: config
+                .blocklists
+                .iter()
+                .map(|(name, path)| networks(path).map(|networks| (name.clone(), networks)))
+                .collect::<Result<_, _>>()?,
+            hosting_asns: config.hosting_asns,
+            countries: config.countries,
+            weights,
+        })
+    }
+
+    /// The risk score of `ip`, and the signals found, with their weights. The flags of the
+    /// `anonymous` database, when there's one, are signals too.
+    pub fn assess<P: GeoProvider>(&self, db: &P, anonymous: Option<&AnonymousIpDatabase>, ip: IpAddr) -> Value {
+        let city: Option<City> = db.lookup(ip).ok();
+        let extras: Extras = db.lookup(ip).ok().unwrap_or_default();
+        let traits = city.as_ref().and_then(|city| city.traits.as_ref());
+        let anonymous = match anonymous.map(|anonymous| anonymous.lookup(ip)) {
+            Some(Ok(anonymous)) => anonymous.unwrap_or_default(),
+            Some(Err(err)) => {
+                eprintln!("Can not look up {} in the Anonymous IP database: {}", ip, err);
+                AnonymousIp::default()
+            }
+            None => AnonymousIp::default(),
+        };
+
+        let mut reasons = Vec::new();
+        let mut found = |signal: &str, detail: Option<String>| {
+            let weight = self.weights.get(signal).copied().unwrap_or(0);
+            if weight > 0 {
+                reasons.push((signal.to_string(), detail, weight));
+            }
+        };
+
+        if traits.and_then(|traits| traits.is_anonymous_proxy).unwrap_or(false) {
+            found("anonymousProxy", None);
+        }
+        if traits.and_then(|traits| traits.is_satellite_provider).unwrap_or(false) {
+            found("satelliteProvider", None);
+        }
+        if anonymous.is_public_proxy.unwrap_or(false) {
+            found("publicProxy", None);
+        }
+        if anonymous.is_anonymous_vpn.unwrap_or(false) {
+            found("vpn", None);
+        }
+        if anonymous.is_tor_exit_node.unwrap_or(false) || self.tor_exits.iter().any(|network| network.contains(ip)) {
+            found("tor", None);
+        }
+        let asn = extras.autonomous_system_number();
+        let hosting_asn = asn.map_or(false, |asn| self.hosting_asns.contains(&asn));
+        let hosting_provider = anonymous.is_hosting_provider.unwrap_or(false);
+        if extras.user_type().as_deref() == Some("hosting") || hosting_asn || hosting_provider {
+            found("hosting", asn.map(|asn| format!("AS{}", asn)));
+        }
+        for (name, networks) in &self.blocklists {
+            if networks.iter().any(|network| network.contains(ip)) {
+                found("blocklist", Some(name.clone()));
+            }
+        }
+
+        let country = city
+            .as_ref()
+            .and_then(|city| city.country.as_ref())
+            .and_then(|country| country.iso_code.clone());
+        if let Some((country, weight)) = country
+            .as_ref()
+            .and_then(|country| self.countries.get(country).map(|weight| (country, *weight)))
+        {
+            if weight > 0 {
+                reasons.push((String::from("country"), Some(country.clone()), weight));
+            }
+        }
+
+        let safe = reasons
+            .iter()
+            .fold(1.0, |safe, (_, _, weight)| safe * (1.0 - f64::from((*weight).min(100)) / 100.0));
+
+        json!({
+            "ipAddress": ip.to_string(),
+            "score": ((1.0 - safe) * 100.0).round() as u8,
+            "reasons": reasons
+                .into_iter()
+                .map(|(signal, detail, weight)| json!({ "signal": signal, "detail": detail, "weight": weight }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Networks or addresses listed in a file, one per line. Empty lines and `#` or `;` comments are
+/// skipped, so that the common blocklist formats can be used as is.
+fn networks(path: &str) -> Result<Vec<IpNetwork>, String> {
+    let content = fs::read_to_string(path).map_err(|err| format!("Can not read {}: {}", path, err))?;
+    content
+        .lines()
+        .map(|line| line.split(|c| c == '#' || c == ';').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let network = line.split_whitespace().next().unwrap_or(line);
+            network
+                .parse::<IpNetwork>()
+                .map_err(|err| format!("Invalid network {} in {}: {}", network, path, err))
+        })
+        .collect()
+}
@@ -0,0 +1,2087 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The geoip-rs server: the HTTP API and the command line.
+
+use std::{env, process};
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use actix_cors::Cors;
+use actix_web::http::header;
+use actix_web::dev::{BodySize, MessageBody, Service};
+use actix_web::http::header::HttpDate;
+use actix_web::http::HeaderMap;
+use actix_web::http::HeaderName;
+use actix_web::http::HeaderValue;
+use actix_web::http::Method;
+use actix_web::http::StatusCode;
+use actix_web::middleware::DefaultHeaders;
+use actix_web::web;
+use actix_web::App;
+use actix_web::HttpMessage;
+use actix_web::HttpRequest;
+use actix_web::HttpResponse;
+use actix_web::HttpServer;
+use actix_web::Scope;
+use futures::StreamExt;
+use maxminddb::MaxMindDBError;
+use serde_json::Value;
+
+use crate::access_log::{self, AccessLog};
+use crate::admin::Admin;
+use crate::admin_ui::{self, AdminUi};
+use crate::allowed::Policy;
+use crate::api_keys::{self, KeyStore};
+use crate::batch::{self, PayloadReader};
+use crate::cache::{Cache, CacheKey};
+use crate::cli;
+use crate::client_ip::IpError;
+use crate::cluster::{self, Replica};
+#[cfg(feature = "columnar")]
+use crate::columnar;
+use crate::country_names;
+use crate::diagnostics::{self, Diagnostics, InFlight};
+use crate::download;
+use crate::enrich_log;
+use crate::fallback::{self, Fallback, Upstream};
+use crate::field_names::FieldNames;
+use crate::fields;
+#[cfg(feature = "grpc")]
+use crate::grpc;
+use crate::handover;
+use crate::health;
+use crate::hooks::{Hooks, ResponseHook};
+use crate::inspect;
+use crate::lines;
+use crate::lookup;
+use crate::memcached;
+use crate::metrics;
+use crate::networks;
+use crate::proto;
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::rate_limit::RateLimiter;
+use crate::reload::{self, Reloads};
+use crate::resolver::{normalize_language, not_resolved, resolve, resolve_city, resolve_country};
+use crate::reverse::{self, Reverse};
+use crate::risk::Risk;
+use crate::settings::{env_flag, Settings};
+use crate::shape::Shape;
+use crate::slow_log;
+use crate::snapshots::{self, Snapshots};
+use crate::templates::Templates;
+#[cfg(feature = "tls")]
+use crate::tls;
+use crate::udp;
+use crate::updater::{self, Updater};
+use crate::verify;
+
+pub(crate) const CSV_GEO_COLUMNS: [&str; 9] = [
+    "continentCode",
+    "countryCode",
+    "countryName",
+    "regionName",
+    "provinceName",
+    "cityName",
+    "latitude",
+    "longitude",
+    "timeZone",
+];
+
+const SCHEMA_VERSION: &str = "1";
+
+const DEFAULT_DB_OPEN_RETRY: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct QueryParams {
+    pub(crate) ip: Option<String>,
+    pub(crate) lang: Option<String>,
+    pub(crate) callback: Option<String>,
+    pub(crate) column: Option<String>,
+    pub(crate) format: Option<String>,
+    pub(crate) date: Option<String>,
+    pub(crate) fields: Option<String>,
+    pub(crate) case: Option<String>,
+}
+
+/// The language asked for with `lang`, normalized, e.g. `pt-BR` for `pt_br`, english without one,
+/// or a `400 Bad Request` when it isn't a language tag, or when neither it nor its primary
+/// language is one of `languages`, those of the database, if it lists any.
+pub(crate) fn get_language(lang: Option<String>, languages: &[String]) -> Result<String, HttpResponse> {
+    let lang = match lang.as_deref().map(str::trim).filter(|lang| !lang.is_empty()) {
+        Some(lang) => lang,
+        None => return Ok(String::from("en")),
+    };
+    let language = normalize_language(lang).ok_or_else(|| {
+        HttpResponse::BadRequest().json(serde_json::json!({
+            "error": "invalid_language",
+            "message": format!("{} is not a language tag, e.g. en or pt-BR", lang),
+        }))
+    })?;
+
+    let primary = language.split('-').next().unwrap_or_default();
+    if languages.is_empty() || languages.iter().any(|known| known == &language || known == primary) {
+        return Ok(language);
+    }
+    Err(HttpResponse::BadRequest().json(serde_json::json!({
+        "error": "unsupported_language",
+        "message": format!("{} is not one of the languages of the database: {}", language, languages.join(", ")),
+    })))
+}
+
+/// The state of the API: the provider and what's configured by the env vars.
+pub struct Db<P> {
+    db: Arc<P>,
+    settings: Arc<Settings>,
+    hooks: Arc<Hooks>,
+    field_names: Arc<FieldNames>,
+    templates: Arc<Templates>,
+    keys: Arc<KeyStore>,
+    risk: Arc<Risk>,
+    cache: Option<Arc<Cache>>,
+    upstream: Option<Arc<dyn Upstream>>,
+    snapshots: Arc<Snapshots>,
+}
+
+impl<P> Clone for Db<P> {
+    fn clone(&self) -> Self {
+        Db {
+            db: self.db.clone(),
+            settings: self.settings.clone(),
+            hooks: self.hooks.clone(),
+            field_names: self.field_names.clone(),
+            templates: self.templates.clone(),
+            keys: self.keys.clone(),
+            risk: self.risk.clone(),
+            cache: self.cache.clone(),
+            upstream: self.upstream.clone(),
+            snapshots: self.snapshots.clone(),
+        }
+    }
+}
+
+impl<P: GeoProvider> Db<P> {
+    /// The API state over `provider`, configured from the GEOIP_RS_* env vars.
+    pub fn from_env(provider: P) -> Result<Db<P>, String> {
+        Ok(Db {
+            db: Arc::new(provider),
+            settings: Arc::new(Settings::from_env()),
+            hooks: Arc::new(Hooks::from_env()?),
+            field_names: Arc::new(FieldNames::from_env()?),
+            templates: Arc::new(Templates::from_env()?),
+            keys: Arc::new(KeyStore::from_env()?),
+            risk: Arc::new(Risk::from_env()?),
+            cache: Cache::from_env()?.map(Arc::new),
+            upstream: fallback::from_env()?,
+            snapshots: Arc::new(Snapshots::from_env()?),
+        })
+    }
+
+    /// Adds `hook` to the response hooks, run after the ones configured with env vars. The clones
+    /// made before don't run it.
+    pub fn with_hook<H: ResponseHook + 'static>(mut self, hook: H) -> Db<P> {
+        Arc::make_mut(&mut self.hooks).add(hook);
+        self
+    }
+
+    pub(crate) fn provider(&self) -> &Arc<P> {
+        &self.db
+    }
+
+    pub(crate) fn keys(&self) -> &Arc<KeyStore> {
+        &self.keys
+    }
+
+    pub(crate) fn hooks(&self) -> &Arc<Hooks> {
+        &self.hooks
+    }
+
+    pub(crate) fn cache(&self) -> Option<&Arc<Cache>> {
+        self.cache.as_ref()
+    }
+
+    /// Resolves an IP address and runs the hooks on the response, for the non HTTP listeners.
+    pub(crate) fn lookup(&self, ip_address: &str, language: &str) -> Value {
+        let mut geoip = self.lookup_protobuf(ip_address, language);
+        self.field_names.apply(&mut geoip);
+        Shape::with_case(self.settings.field_case).write_case(&mut geoip);
+        geoip
+    }
+
+    /// Like `lookup`, keeping the original field names, that the protobuf messages are built from.
+    pub(crate) fn lookup_protobuf(&self, ip_address: &str, language: &str) -> Value {
+        let mut geoip = resolve(self.db.as_ref(), &self.settings.resolver, ip_address, language);
+        self.hooks.apply(&mut geoip);
+        geoip
+    }
+}
+
+pub(crate) fn parse_body(content_type: &str, body: &[u8]) -> Result<QueryParams, String> {
+    if body.is_empty() {
+        return Ok(QueryParams::default());
+    }
+
+    match content_type {
+        "application/x-www-form-urlencoded" => {
+            serde_urlencoded::from_bytes(body).map_err(|err| err.to_string())
+        }
+        _ => serde_json::from_slice(body).map_err(|err| err.to_string()),
+    }
+}
+
+pub(crate) fn parse_query(query_string: &str) -> (QueryParams, Vec<String>) {
+    let pairs: Vec<(String, String)> = serde_urlencoded::from_str(query_string).unwrap_or_default();
+
+    let mut query = QueryParams::default();
+    let mut ips = Vec::new();
+    for (key, value) in pairs {
+        match key.as_str() {
+            "ip" => ips.push(value),
+            "lang" => query.lang = Some(value),
+            "callback" => query.callback = Some(value),
+            "column" => query.column = Some(value),
+            "format" => query.format = Some(value),
+            "date" => query.date = Some(value),
+            "fields" => query.fields = Some(value),
+            "case" => query.case = Some(value),
+            _ => {}
+        }
+    }
+    query.ip = ips.first().cloned();
+
+    (query, ips)
+}
+
+/// The `format` query param or, when missing, `protobuf` if it's the accepted content type.
+fn response_format(req: &HttpRequest, format: Option<String>) -> Option<String> {
+    format.or_else(|| {
+        req.headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .filter(|accept| accept.contains("application/x-protobuf"))
+            .map(|_| String::from("protobuf"))
+    })
+}
+
+async fn index<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (mut query, ips) = parse_query(req.query_string());
+    query.format = response_format(&req, query.format);
+
+    if ips.len() > data.settings.batch_max_items {
+        return batch_too_large(
+            &data.settings,
+            format!("{} IP addresses, more than {}", ips.len(), data.settings.batch_max_items),
+        );
+    }
+    if ips.len() > 1 {
+        return lookup_many(&req, &data, key.as_deref(), ips, query).await;
+    }
+
+    lookup(&req, &data, key.as_deref(), query).await
+}
+
+async fn index_ip<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, path: web::Path<String>) -> HttpResponse {
+    resolve_path(&req, &data, path.into_inner(), "index").await
+}
+
+async fn country<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, path: web::Path<String>) -> HttpResponse {
+    resolve_path(&req, &data, path.into_inner(), "country").await
+}
+
+async fn city<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, path: web::Path<String>) -> HttpResponse {
+    resolve_path(&req, &data, path.into_inner(), "city").await
+}
+
+/// The autonomous system and the ISP of an IP address, from the ASN database of
+/// GEOIP_RS_ASN_DB_PATH alone.
+async fn asn<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, path: web::Path<String>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let ip = match path.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return ip_error(&IpError::InvalidIp(path.into_inner())),
+    };
+    let database = match data.hooks.asn() {
+        Some(database) => database,
+        None => {
+            return HttpResponse::NotFound()
+                .json(serde_json::json!({ "error": "there's no ASN database, set GEOIP_RS_ASN_DB_PATH" }))
+        }
+    };
+
+    let ip_address = ip.to_string();
+    let (status, mut geoip) = match database.lookup(ip) {
+        Ok(Some(fields)) => {
+            let mut geoip = serde_json::Map::new();
+            geoip.insert(String::from("ipAddress"), Value::from(ip_address));
+            geoip.extend(fields);
+            (StatusCode::OK, Value::Object(geoip))
+        }
+        Ok(None) => (data.settings.not_found_status, not_resolved(&ip_address, Some(ip)).unwrap()),
+        Err(err) => return lookup_failed(&Value::from(ip_address), &Value::from(err.to_string())),
+    };
+    if let Some(key) = &key {
+        data.keys.restrict(key, &mut geoip);
+    }
+    data.field_names.apply(&mut geoip);
+    HttpResponse::build(status).json(geoip)
+}
+
+async fn resolve_path<P: GeoProvider>(
+    req: &HttpRequest,
+    data: &Db<P>,
+    ip_address: String,
+    endpoint: &'static str,
+) -> HttpResponse {
+    let key = match data.keys.check(req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    if ip_address.parse::<IpAddr>().is_err() {
+        return ip_error(&IpError::InvalidIp(ip_address));
+    }
+
+    let (query, _) = parse_query(req.query_string());
+    let shape = match shape(data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+    let language = match get_language(query.lang, &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    let format = response_format(req, query.format);
+    let (key, callback) = (key.as_deref(), query.callback);
+
+    if let Some(date) = &query.date {
+        let ips = [ip_address];
+        return historical(data, key, endpoint, &ips, false, date, &language, callback, format, &shape);
+    }
+
+    let etag = etag(data, key, endpoint, &[ip_address.as_str()], &language, &callback, &format, &shape);
+
+    let lookup_key = key.map(String::from);
+    conditional(req, data, key, etag, || {
+        timed_lookup(
+            data,
+            move |data| resolve_for(data, lookup_key.as_deref(), endpoint, &ip_address, &language),
+            |geoip| respond(data, key, endpoint, callback, format, &shape, geoip),
+        )
+    })
+    .await
+}
+
+/// Resolves an IP address into the response of `endpoint`, in the database of the API key if it
+/// has one of its own, going through the cache when there's one.
+fn resolve_for<P: GeoProvider>(
+    data: &Db<P>,
+    key: Option<&str>,
+    endpoint: &str,
+    ip_address: &str,
+    language: &str,
+) -> Value {
+    let database = key.and_then(|key| data.keys.database(key));
+    let cached = data.cache.as_ref().map(|cache| {
+        let cache_key = CacheKey {
+            database: database.as_ref().and(key).map(String::from),
+            endpoint: endpoint.to_string(),
+            ip_address: ip_address.to_string(),
+            language: language.to_string(),
+        };
+        (cache, cache_key, build_epoch(data, key))
+    });
+    let hit = cached
+        .as_ref()
+        .and_then(|(cache, cache_key, build_epoch)| cache.get(cache_key, *build_epoch));
+    if let Some(geoip) = hit {
+        return geoip;
+    }
+
+    let geoip = match database {
+        Some(database) => resolve_with(data, &database, endpoint, ip_address, language),
+        None => resolve_with(data, &data.db, endpoint, ip_address, language),
+    };
+    if let Some(err) = geoip.get("lookupError") {
+        eprintln!("Can not look up {}: {}", ip_address, err);
+        metrics::LOOKUP_ERRORS.fetch_add(1, Ordering::Relaxed);
+        return geoip;
+    }
+    if let Some((cache, cache_key, build_epoch)) = cached {
+        cache.insert(cache_key, build_epoch, geoip.clone());
+    }
+    geoip
+}
+
+/// Resolves with `db`, falling back to the upstream when there's one.
+fn resolve_with<P: GeoProvider, Q: GeoProvider>(
+    data: &Db<P>,
+    db: &Arc<Q>,
+    endpoint: &str,
+    ip_address: &str,
+    language: &str,
+) -> Value {
+    match &data.upstream {
+        Some(upstream) => {
+            let fallback = Fallback::new(db.clone(), upstream.clone());
+            resolve_endpoint(&fallback, &data.settings, endpoint, ip_address, language)
+        }
+        None => resolve_endpoint(db.as_ref(), &data.settings, endpoint, ip_address, language),
+    }
+}
+
+fn resolve_endpoint<P: GeoProvider>(
+    db: &P,
+    settings: &Settings,
+    endpoint: &str,
+    ip_address: &str,
+    language: &str,
+) -> Value {
+    let options = &settings.resolver;
+    match endpoint {
+        "country" => resolve_country(db, options, ip_address, language),
+        "city" => resolve_city(db, options, ip_address, language),
+        _ => resolve(db, options, ip_address, language),
+    }
+}
+
+/// The record of an IP address as it's stored in the database, for the instances having this one
+/// as their remote.
+async fn record<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, path: web::Path<String>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let ip = match path.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return ip_error(&IpError::InvalidIp(path.into_inner())),
+    };
+
+    let database = key.as_deref().and_then(|key| data.keys.database(key));
+    let record = off_worker(&data, move |data| match database {
+        Some(database) => lookup_record(data, &database, ip),
+        None => lookup_record(data, &data.db, ip),
+    });
+    match record.await {
+        Ok(Ok(record)) => HttpResponse::Ok().json(record),
+        Ok(Err(MaxMindDBError::AddressNotFoundError(_))) => HttpResponse::NotFound().finish(),
+        Ok(Err(err)) => lookup_failed(&Value::from(ip.to_string()), &Value::from(err.to_string())),
+        Err(err) => lookup_failed(&Value::from(ip.to_string()), &Value::from(err)),
+    }
+}
+
+fn lookup_record<P: GeoProvider, Q: GeoProvider>(
+    data: &Db<P>,
+    db: &Arc<Q>,
+    ip: IpAddr,
+) -> Result<Value, MaxMindDBError> {
+    match &data.upstream {
+        Some(upstream) => Fallback::new(db.clone(), upstream.clone()).lookup(ip),
+        None => db.lookup(ip),
+    }
+}
+
+/// The fields the responses can have, those of the database of the API key if it has one of its
+/// own, and only the ones it's allowed.
+async fn fields<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let key = key.as_deref();
+    let (query, _) = parse_query(req.query_string());
+    let shape = match shape(&data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+
+    let metadata = match key.and_then(|key| data.keys.database(key)) {
+        Some(database) => database.metadata(),
+        None => data.db.metadata(),
+    };
+    let mut fields = Value::Object(fields::describe(
+        &metadata,
+        &data.settings,
+        &data.hooks,
+        data.upstream.is_some(),
+        !data.snapshots.is_empty(),
+    ));
+    if let Some(key) = key {
+        data.keys.restrict(key, &mut fields);
+    }
+    data.field_names.apply(&mut fields);
+    shape.write_case(&mut fields);
+
+    HttpResponse::Ok().json(serde_json::json!({
+        "fields": fields,
+        "limits": batch_limits(&data.settings),
+    }))
+}
+
+async fn risk<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (query, _) = parse_query(req.query_string());
+    let client_ip = match data.settings.client_ip.client_ip(query.ip, req.headers(), req.peer_addr()) {
+        Ok(client_ip) => client_ip,
+        Err(err) => return ip_error(&err),
+    };
+    let ip = match client_ip.ip_address.parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => return ip_error(&IpError::InvalidIp(client_ip.ip_address)),
+    };
+
+    let assessment = match key.and_then(|key| data.keys.database(&key)) {
+        Some(database) => data.risk.assess(database.as_ref(), data.hooks.anonymous(), ip),
+        None => data.risk.assess(data.db.as_ref(), data.hooks.anonymous(), ip),
+    };
+    HttpResponse::Ok().json(assessment)
+}
+
+#[derive(Deserialize, Default)]
+struct AllowedParams {
+    allow: Option<String>,
+    deny: Option<String>,
+}
+
+async fn allowed<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (query, _) = parse_query(req.query_string());
+    let params: AllowedParams = serde_urlencoded::from_str(req.query_string()).unwrap_or_default();
+
+    let policy = key
+        .as_deref()
+        .and_then(|key| data.keys.geo_policy(key))
+        .or_else(|| Policy::parse(params.allow.as_deref(), params.deny.as_deref()));
+    let policy = match policy {
+        Some(policy) => policy,
+        None => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body("allow or deny must be a list of countries")
+        }
+    };
+
+    let client_ip = match data.settings.client_ip.client_ip(query.ip, req.headers(), req.peer_addr()) {
+        Ok(client_ip) => client_ip,
+        Err(err) => return ip_error(&err),
+    };
+    if client_ip.ip_address.parse::<IpAddr>().is_err() {
+        return ip_error(&IpError::InvalidIp(client_ip.ip_address));
+    }
+
+    let geoip = match key.and_then(|key| data.keys.database(&key)) {
+        Some(database) => resolve_country(database.as_ref(), &data.settings.resolver, &client_ip.ip_address, "en"),
+        None => resolve_country(data.db.as_ref(), &data.settings.resolver, &client_ip.ip_address, "en"),
+    };
+    let ip_address = geoip["ipAddress"].as_str().unwrap_or(&client_ip.ip_address);
+    let country_code = geoip["countryCode"].as_str().filter(|country_code| !country_code.is_empty());
+    HttpResponse::Ok().json(policy.verdict(ip_address, country_code))
+}
+
+async fn index_post<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, body: web::Bytes) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (query, _) = parse_query(req.query_string());
+
+    let body_params = match parse_body(req.content_type(), &body) {
+        Ok(body_params) => body_params,
+        Err(err) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("invalid request body: {}", err))
+        }
+    };
+
+    let query = QueryParams {
+        ip: body_params.ip.or(query.ip),
+        lang: body_params.lang.or(query.lang),
+        callback: body_params.callback.or(query.callback),
+        column: None,
+        format: response_format(&req, body_params.format.or(query.format)),
+        date: body_params.date.or(query.date),
+        fields: body_params.fields.or(query.fields),
+        case: body_params.case.or(query.case),
+    };
+
+    lookup(&req, &data, key.as_deref(), query).await
+}
+
+async fn lookup<P: GeoProvider>(
+    req: &HttpRequest,
+    data: &Db<P>,
+    key: Option<&str>,
+    query: QueryParams,
+) -> HttpResponse {
+    let shape = match shape(data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+    let language = match get_language(query.lang, &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    let client_ip = match data.settings.client_ip.client_ip(query.ip, req.headers(), req.peer_addr()) {
+        Ok(client_ip) => client_ip,
+        Err(err) => return ip_error(&err),
+    };
+    let ip_address = client_ip.ip_address.as_str();
+
+    let (callback, format) = (query.callback, query.format);
+
+    if let Some(date) = &query.date {
+        let ips = [client_ip.ip_address.clone()];
+        return historical(data, key, "index", &ips, false, date, &language, callback, format, &shape);
+    }
+
+    // With GEOIP_RS_IP_SOURCE, the body also depends on how the IP address was picked
+    let chain = client_ip.chain.join(",");
+    let validated: &[&str] = if data.settings.ip_source {
+        &[ip_address, client_ip.source.as_str(), &chain]
+    } else {
+        &[ip_address]
+    };
+    let etag = etag(data, key, "index", validated, &language, &callback, &format, &shape);
+
+    let lookup_key = key.map(String::from);
+    conditional(req, data, key, etag, || {
+        timed_lookup(
+            data,
+            move |data| {
+                let key = lookup_key.as_deref();
+                let mut geoip = resolve_for(data, key, "index", &client_ip.ip_address, &language);
+                if let (true, Value::Object(response)) = (data.settings.ip_source, &mut geoip) {
+                    response.insert(String::from("ipSource"), Value::from(client_ip.source.as_str()));
+                    response.insert(String::from("ipChain"), Value::from(client_ip.chain));
+                }
+                geoip
+            },
+            |geoip| respond(data, key, "index", callback, format, &shape, geoip),
+        )
+    })
+    .await
+}
+
+async fn lookup_many<P: GeoProvider>(
+    req: &HttpRequest,
+    data: &Db<P>,
+    key: Option<&str>,
+    ips: Vec<String>,
+    query: QueryParams,
+) -> HttpResponse {
+    let shape = match shape(data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+    let language = match get_language(query.lang, &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    let (callback, format) = (query.callback, query.format);
+    if let Some(date) = &query.date {
+        return historical(data, key, "index", &ips, true, date, &language, callback, format, &shape);
+    }
+
+    let etag = etag(
+        data,
+        key,
+        "index",
+        &ips.iter().map(String::as_str).collect::<Vec<_>>(),
+        &language,
+        &callback,
+        &format,
+        &shape,
+    );
+
+    conditional(req, data, key, etag, || resolve_many(data, key, ips, language, callback, format, shape)).await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn resolve_many<P: GeoProvider>(
+    data: &Db<P>,
+    key: Option<&str>,
+    ips: Vec<String>,
+    language: String,
+    callback: Option<String>,
+    format: Option<String>,
+    shape: Shape,
+) -> HttpResponse {
+    let lookup_key = key.map(String::from);
+    if format.as_deref() == Some("jsonl") {
+        let data = data.clone();
+        return batch::stream("application/x-ndjson; charset=utf-8", move |chunks| {
+            let key = lookup_key.as_deref();
+            for ip_address in ips {
+                let mut geoip = resolve_for(&data, key, "index", &ip_address, &language);
+                present(&data, key, &shape, &mut geoip);
+                writeln!(chunks, "{}", geoip).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        });
+    }
+
+    timed_lookup(
+        data,
+        move |data| {
+            let key = lookup_key.as_deref();
+            let geoips: Vec<Value> = ips
+                .iter()
+                .map(|ip_address| resolve_for(data, key, "index", ip_address, &language))
+                .collect();
+            Value::Array(geoips)
+        },
+        |geoips| respond(data, key, "index", callback, format, &shape, geoips),
+    )
+    .await
+}
+
+/// Resolves IP addresses with the snapshot of the database the closest to `date`, into an array of
+/// responses when `many`, adding the date of the snapshot to the responses as `snapshotDate`.
+/// They skip the cache and the upstream, which only know about the current database.
+#[allow(clippy::too_many_arguments)]
+fn historical<P: GeoProvider>(
+    data: &Db<P>,
+    key: Option<&str>,
+    endpoint: &str,
+    ips: &[String],
+    many: bool,
+    date: &str,
+    language: &str,
+    callback: Option<String>,
+    format: Option<String>,
+    shape: &Shape,
+) -> HttpResponse {
+    let snapshot = match data.snapshots.closest(date) {
+        Ok(snapshot) => snapshot,
+        Err(err) => return invalid_date(err),
+    };
+    let snapshot_date = snapshots::date_of(snapshot.build_epoch());
+
+    timed(
+        data,
+        || {
+            let mut geoips = ips.iter().map(|ip_address| {
+                let mut geoip = resolve_endpoint(snapshot.as_ref(), &data.settings, endpoint, ip_address, language);
+                if let Value::Object(response) = &mut geoip {
+                    response.insert(String::from("snapshotDate"), Value::from(snapshot_date.as_str()));
+                }
+                geoip
+            });
+            if many {
+                Value::Array(geoips.collect())
+            } else {
+                geoips.next().unwrap_or(Value::Null)
+            }
+        },
+        |geoip| respond(data, key, endpoint, callback, format, shape, geoip),
+    )
+}
+
+/// RFC 7807 problem response to a `date` param that's not a date, or with no snapshots to look it
+/// up in.
+fn invalid_date(detail: String) -> HttpResponse {
+    HttpResponse::BadRequest()
+        .content_type("application/problem+json")
+        .json(serde_json::json!({
+            "type": "about:blank",
+            "title": "The date can not be looked up",
+            "status": 400,
+            "detail": detail,
+        }))
+}
+
+/// Runs the hooks on a response streamed as JSON lines, and restricts and renames its fields.
+fn present<P: GeoProvider>(data: &Db<P>, key: Option<&str>, shape: &Shape, geoip: &mut Value) {
+    data.hooks.apply(geoip);
+    if let Some(key) = key {
+        data.keys.restrict(key, geoip);
+    }
+    shape.select(geoip);
+    data.field_names.apply(geoip);
+    shape.write_case(geoip);
+}
+
+/// The shape of the responses asked for by the `fields` and `case` params, answering 400 to a
+/// case that's not one.
+fn shape<P: GeoProvider>(data: &Db<P>, query: &QueryParams) -> Result<Shape, HttpResponse> {
+    Shape::parse(query.fields.as_deref(), query.case.as_deref(), data.settings.field_case).map_err(|err| {
+        HttpResponse::BadRequest()
+            .content_type("text/plain; charset=utf-8")
+            .body(err)
+    })
+}
+
+/// Runs `resolve` and `respond`. With GEOIP_RS_TIMING, adds the lookup time to the response as
+/// `elapsedMs`, and the lookup and serialization times as a Server-Timing header.
+fn timed<P, R, S>(data: &Db<P>, resolve: R, respond: S) -> HttpResponse
+where
+    R: FnOnce() -> Value,
+    S: FnOnce(Value) -> HttpResponse,
+{
+    let start = Instant::now();
+    let geoip = resolve();
+    with_timing(data, geoip, start.elapsed(), respond)
+}
+
+/// [timed], for the lookups going through the upstream when there's one: they're run
+/// [off_worker].
+async fn timed_lookup<P, R, S>(data: &Db<P>, resolve: R, respond: S) -> HttpResponse
+where
+    P: GeoProvider,
+    R: FnOnce(&Db<P>) -> Value + Send + 'static,
+    S: FnOnce(Value) -> HttpResponse,
+{
+    let start = Instant::now();
+    let geoip = off_worker(data, resolve)
+        .await
+        .unwrap_or_else(|err| serde_json::json!({ "lookupError": err }));
+    with_timing(data, geoip, start.elapsed(), respond)
+}
+
+/// Runs `lookup` on the thread pool of blocking calls when there's an upstream, whose lookups
+/// wait on the network and would hold up the other requests of the worker, and right away
+/// otherwise.
+async fn off_worker<P, L, T>(data: &Db<P>, lookup: L) -> Result<T, String>
+where
+    P: GeoProvider,
+    L: FnOnce(&Db<P>) -> T + Send + 'static,
+    T: Send + 'static,
+{
+    if data.upstream.is_none() {
+        return Ok(lookup(data));
+    }
+    let data = data.clone();
+    web::block(move || Ok::<_, ()>(lookup(&data))).await.map_err(|err| err.to_string())
+}
+
+/// Responds with `geoip`, resolved in `lookup`, adding the time it took when GEOIP_RS_TIMING is set.
+fn with_timing<P, S>(data: &Db<P>, mut geoip: Value, lookup: Duration, respond: S) -> HttpResponse
+where
+    S: FnOnce(Value) -> HttpResponse,
+{
+    if !data.settings.timing {
+        return respond(geoip);
+    }
+
+    if let Value::Object(response) = &mut geoip {
+        response.insert(String::from("elapsedMs"), Value::from(millis(lookup)));
+    }
+
+    let start = Instant::now();
+    let mut response = respond(geoip);
+    let serialization = start.elapsed();
+
+    let timing = format!("lookup;dur={:.3}, serialization;dur={:.3}", millis(lookup), millis(serialization));
+    response
+        .headers_mut()
+        .insert(HeaderName::from_static("server-timing"), HeaderValue::from_str(&timing).unwrap());
+    response
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Build time of the database the requests with `key` are resolved against.
+fn build_epoch<P: GeoProvider>(data: &Db<P>, key: Option<&str>) -> u64 {
+    match key.and_then(|key| data.keys.database(key)) {
+        Some(database) => database.build_epoch(),
+        None => data.db.build_epoch(),
+    }
+}
+
+/// Strong validator of a response: the same request always gets the same body, until another
+/// database is loaded.
+#[allow(clippy::too_many_arguments)]
+fn etag<P: GeoProvider>(
+    data: &Db<P>,
+    key: Option<&str>,
+    endpoint: &str,
+    ips: &[&str],
+    language: &str,
+    callback: &Option<String>,
+    format: &Option<String>,
+    shape: &Shape,
+) -> String {
+    let mut hasher = DefaultHasher::new();
+    (build_epoch(data, key), key, endpoint, ips, language, callback, format, shape).hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Answers 304 Not Modified to GET requests whose If-None-Match matches `etag` or, without
+/// If-None-Match, whose If-Modified-Since is not older than the database build time. Otherwise
+/// builds the response and sets its ETag and Last-Modified headers.
+async fn conditional<P, F, R>(
+    req: &HttpRequest,
+    data: &Db<P>,
+    key: Option<&str>,
+    etag: String,
+    respond: F,
+) -> HttpResponse
+where
+    P: GeoProvider,
+    F: FnOnce() -> R,
+    R: Future<Output = HttpResponse>,
+{
+    let last_modified = HttpDate::from(UNIX_EPOCH + Duration::from_secs(build_epoch(data, key)));
+
+    let cacheable = req.method() == Method::GET || req.method() == Method::HEAD;
+    let not_modified = match req.headers().get(header::IF_NONE_MATCH) {
+        Some(tags) => tags
+            .to_str()
+            .map(|tags| {
+                tags.split(',')
+                    .map(|tag| tag.trim().trim_start_matches("W/"))
+                    .any(|tag| tag == "*" || tag == etag)
+            })
+            .unwrap_or(false),
+        None => req
+            .headers()
+            .get(header::IF_MODIFIED_SINCE)
+            .and_then(|since| since.to_str().ok())
+            .and_then(|since| since.parse::<HttpDate>().ok())
+            .map(|since| SystemTime::from(last_modified) <= SystemTime::from(since))
+            .unwrap_or(false),
+    };
+
+    if cacheable && not_modified {
+        return HttpResponse::NotModified()
+            .header(header::ETAG, etag)
+            .header(header::LAST_MODIFIED, last_modified)
+            .finish();
+    }
+
+    let mut response = respond().await;
+    if response.status().is_success() {
+        let headers = response.headers_mut();
+        headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+        headers.insert(header::LAST_MODIFIED, HeaderValue::from_str(&last_modified.to_string()).unwrap());
+    }
+    response
+}
+
+fn respond<P: GeoProvider>(
+    data: &Db<P>,
+    key: Option<&str>,
+    endpoint: &str,
+    callback: Option<String>,
+    format: Option<String>,
+    shape: &Shape,
+    mut geoip: Value,
+) -> HttpResponse {
+    if let Some(err) = geoip.get("lookupError") {
+        return lookup_failed(&geoip["ipAddress"], err);
+    }
+    let status = if geoip.get("ip_address").is_some() {
+        data.settings.not_found_status
+    } else {
+        StatusCode::OK
+    };
+
+    data.hooks.apply(&mut geoip);
+    if let Some(key) = key {
+        data.keys.restrict(key, &mut geoip);
+    }
+    shape.select(&mut geoip);
+
+    match data.templates.render(endpoint, &geoip) {
+        Some(Ok((content_type, body))) => return HttpResponse::build(status).content_type(content_type).body(body),
+        Some(Err(err)) => {
+            return HttpResponse::InternalServerError()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("template error: {}", err))
+        }
+        None => {}
+    }
+
+    // Protocol Buffers messages have a schema of their own
+    if format.as_deref() != Some("protobuf") {
+        data.field_names.apply(&mut geoip);
+        shape.write_case(&mut geoip);
+    }
+
+    match format.as_deref() {
+        None | Some("json") => {}
+        Some("jsonl") => {
+            return HttpResponse::build(status)
+                .content_type("application/x-ndjson; charset=utf-8")
+                .body(format!("{}\n", geoip))
+        }
+        Some("protobuf") => {
+            return HttpResponse::build(status)
+                .content_type("application/x-protobuf")
+                .body(proto::encode(&geoip))
+        }
+        Some("yaml") => {
+            return HttpResponse::build(status)
+                .content_type("application/yaml; charset=utf-8")
+                .body(serde_yaml::to_string(&geoip).unwrap())
+        }
+        Some(format) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("unsupported format: {}", format))
+        }
+    }
+
+    match callback {
+        Some(_) if key.map_or(false, |key| !data.keys.jsonp(key)) => {
+            api_keys::forbidden("JSONP is not allowed for the API key")
+        }
+        Some(callback) if !valid_callback(&callback) => HttpResponse::BadRequest()
+            .content_type("text/plain; charset=utf-8")
+            .body(format!("{} is not a function name, e.g. show or app.show", callback)),
+        Some(callback) => HttpResponse::build(status)
+            .content_type("application/javascript; charset=utf-8")
+            .body(format!(";{}({});", callback, geoip)),
+        None => HttpResponse::build(status)
+            .content_type("application/json; charset=utf-8")
+            .body(geoip.to_string()),
+    }
+}
+
+/// RFC 7807 problem response to a lookup failed reading the database, which would otherwise be
+/// mistaken for an IP address not found.
+fn lookup_failed(ip_address: &Value, err: &Value) -> HttpResponse {
+    HttpResponse::InternalServerError()
+        .content_type("application/problem+json")
+        .json(serde_json::json!({
+            "type": "about:blank",
+            "title": "The database could not be read",
+            "status": 500,
+            "detail": err,
+            "ipAddress": ip_address,
+        }))
+}
+
+/// `400 Bad Request` to a request with no IP address to resolve, with the code of the error, e.g.
+/// `{"error": "invalid_ip", "message": "foo is not an IP address", "ipAddress": "foo"}`.
+fn ip_error(err: &IpError) -> HttpResponse {
+    let mut body = serde_json::json!({ "error": err.code(), "message": err.to_string() });
+    if let IpError::InvalidIp(ip_address) = err {
+        body["ipAddress"] = Value::from(ip_address.as_str());
+    }
+    HttpResponse::BadRequest().json(body)
+}
+
+/// Whether the `callback` param is a function name, possibly qualified, e.g. `app.show`: anything
+/// else would be run by the browsers of the callers as part of the script.
+pub(crate) fn valid_callback(callback: &str) -> bool {
+    callback.len() <= 128
+        && callback.split('.').all(|name| {
+            let mut chars = name.chars();
+            chars.next().map_or(false, |c| c.is_ascii_alphabetic() || c == '_' || c == '$')
+                && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '$')
+        })
+}
+
+/// Body of `POST /batch`: an array of IP addresses, or an object with them and the language.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum BatchBody {
+    Ips(Vec<String>),
+    Object { ips: Vec<String>, lang: Option<String> },
+}
+
+async fn batch_json<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, payload: web::Payload) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (query, _) = parse_query(req.query_string());
+    if let Err(response) = check_length(&req, &data.settings) {
+        return response;
+    }
+    let body = match read_batch(&data.settings, payload).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+
+    let (ips, lang) = match serde_json::from_slice(&body) {
+        Ok(BatchBody::Ips(ips)) => (ips, None),
+        Ok(BatchBody::Object { ips, lang }) => (ips, lang),
+        Err(_) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body("invalid request body: expected an array of IP addresses, or an object with an ips array")
+        }
+    };
+    if ips.len() > data.settings.batch_max_items {
+        return batch_too_large(
+            &data.settings,
+            format!("{} IP addresses, more than {}", ips.len(), data.settings.batch_max_items),
+        );
+    }
+
+    let shape = match shape(&data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+    let language = match get_language(lang.or(query.lang), &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    let format = response_format(&req, query.format);
+    resolve_many(&data, key.as_deref(), ips, language, query.callback, format, shape).await
+}
+
+async fn batch_csv<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, payload: web::Payload) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    // The body is the CSV itself: a form upload would be enriched as if its boundaries and part
+    // headers were rows
+    if req.content_type().starts_with("multipart/") {
+        return HttpResponse::UnsupportedMediaType()
+            .content_type("text/plain; charset=utf-8")
+            .body("multipart bodies are not supported: send the CSV file as the request body, e.g. with --data-binary");
+    }
+    let (query, _) = parse_query(req.query_string());
+    let language = match get_language(query.lang, &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_length(&req, &data.settings) {
+        return response;
+    }
+
+    // CSV is streamed as the body is received, while the columnar formats need all the records
+    let streamed = match query.format.as_deref() {
+        None | Some("csv") => true,
+        #[cfg(feature = "columnar")]
+        Some("arrow") | Some("parquet") => false,
+        Some(format) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("unsupported format: {}", format))
+        }
+    };
+    if streamed {
+        let body = PayloadReader::new(payload, data.settings.batch_max_body_size);
+        let settings = data.settings.clone();
+        return match key.and_then(|key| data.keys.database(&key)) {
+            Some(database) => stream_csv(database, settings, body, query.column, language),
+            None => stream_csv(data.db.clone(), settings, body, query.column, language),
+        };
+    }
+
+    let body = match read_batch(&data.settings, payload).await {
+        Ok(body) => body,
+        Err(response) => return response,
+    };
+    let column = query.column.as_deref();
+    let enriched = match key.and_then(|key| data.keys.database(&key)) {
+        Some(database) => enrich_csv(database.as_ref(), &data.settings, &body, column, &language),
+        None => enrich_csv(data.db.as_ref(), &data.settings, &body, column, &language),
+    };
+    let records = match enriched {
+        Ok(records) => records,
+        Err(CsvError::TooManyRows) => {
+            let max_items = data.settings.batch_max_items;
+            return batch_too_large(&data.settings, format!("more than {} rows", max_items));
+        }
+        Err(CsvError::Invalid(err)) => {
+            return HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("invalid csv: {}", err))
+        }
+    };
+
+    let output = match query.format.as_deref() {
+        #[cfg(feature = "columnar")]
+        Some("arrow") => columnar::write_arrow(&records).map(|body| ("application/vnd.apache.arrow.stream", body)),
+        #[cfg(feature = "columnar")]
+        Some("parquet") => columnar::write_parquet(&records).map(|body| ("application/vnd.apache.parquet", body)),
+        _ => write_csv(&records).map(|body| ("text/csv; charset=utf-8", body)).map_err(|err| err.to_string()),
+    };
+
+    match output {
+        Ok((content_type, body)) => HttpResponse::Ok().content_type(content_type).body(body),
+        Err(err) => HttpResponse::InternalServerError()
+            .content_type("text/plain; charset=utf-8")
+            .body(err),
+    }
+}
+
+/// Enriches a CSV body as it's received, streaming the enriched rows. Errors, e.g. a row that's
+/// not CSV or one over the limits, abort the response midway.
+fn stream_csv<Q: GeoProvider>(
+    db: Arc<Q>,
+    settings: Arc<Settings>,
+    body: PayloadReader,
+    column: Option<String>,
+    language: String,
+) -> HttpResponse {
+    batch::stream("text/csv; charset=utf-8", move |chunks| {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(body);
+        let mut writer = csv::Writer::from_writer(chunks);
+
+        let mut headers = reader.headers().map_err(|err| format!("invalid csv: {}", err))?.clone();
+        let ip_column = enrich_headers(&mut headers, column.as_deref());
+        writer.write_record(&headers).map_err(|err| err.to_string())?;
+
+        for (row, record) in reader.records().enumerate() {
+            if row == settings.batch_max_items {
+                return Err(format!("more than {} rows", settings.batch_max_items));
+            }
+            let mut record = record.map_err(|err| format!("invalid csv: {}", err))?;
+            enrich_record(db.as_ref(), &settings, &mut record, ip_column, &language);
+            writer.write_record(&record).map_err(|err| err.to_string())?;
+        }
+        writer.flush().map_err(|err| err.to_string())
+    })
+}
+
+/// `POST /batch/jsonl`: resolves the IP addresses of a JSON lines body, each one a string or an
+/// object with an `ip`, streaming a JSON lines response as the body is received.
+async fn batch_jsonl<P: GeoProvider>(req: HttpRequest, data: web::Data<Db<P>>, payload: web::Payload) -> HttpResponse {
+    let key = match data.keys.check(&req) {
+        Ok(key) => key,
+        Err(response) => return response,
+    };
+    let (query, _) = parse_query(req.query_string());
+    let shape = match shape(&data, &query) {
+        Ok(shape) => shape,
+        Err(response) => return response,
+    };
+    let language = match get_language(query.lang, &data.db.metadata().languages) {
+        Ok(language) => language,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_length(&req, &data.settings) {
+        return response;
+    }
+
+    let body = PayloadReader::new(payload, data.settings.batch_max_body_size);
+    let data = data.get_ref().clone();
+    batch::stream("application/x-ndjson; charset=utf-8", move |chunks| {
+        let key = key.as_deref();
+        let mut rows = 0;
+        for line in BufReader::new(body).lines() {
+            let line = line.map_err(|err| err.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if rows == data.settings.batch_max_items {
+                return Err(format!("more than {} lines", data.settings.batch_max_items));
+            }
+            rows += 1;
+
+            let geoip = match batch_ip(&line) {
+                Some(ip_address) => {
+                    let mut geoip = resolve_for(&data, key, "index", &ip_address, &language);
+                    present(&data, key, &shape, &mut geoip);
+                    geoip
+                }
+                None => {
+                    let error = format!("not an IP address or an object with an ip: {}", line);
+                    serde_json::json!({ "error": error })
+                }
+            };
+            writeln!(chunks, "{}", geoip).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    })
+}
+
+/// The IP address of a line of a JSON lines batch.
+fn batch_ip(line: &str) -> Option<String> {
+    match serde_json::from_str(line).ok()? {
+        Value::String(ip_address) => Some(ip_address),
+        Value::Object(object) => object.get("ip").and_then(Value::as_str).map(String::from),
+        _ => None,
+    }
+}
+
+/// Answers 413 to the batches whose Content-Length is larger than GEOIP_RS_BATCH_MAX_BODY_SIZE.
+/// Chunked bodies are checked as they're read.
+fn check_length(req: &HttpRequest, settings: &Settings) -> Result<(), HttpResponse> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<usize>().ok());
+    match length {
+        Some(length) if length > settings.batch_max_body_size => Err(body_too_large(settings)),
+        _ => Ok(()),
+    }
+}
+
+/// The whole body of a batch, answering 413 once it's larger than GEOIP_RS_BATCH_MAX_BODY_SIZE.
+async fn read_batch(settings: &Settings, mut payload: web::Payload) -> Result<web::BytesMut, HttpResponse> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|err| {
+            HttpResponse::BadRequest()
+                .content_type("text/plain; charset=utf-8")
+                .body(format!("can not read the body: {}", err))
+        })?;
+        if body.len() + chunk.len() > settings.batch_max_body_size {
+            return Err(body_too_large(settings));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+fn body_too_large(settings: &Settings) -> HttpResponse {
+    let limit = settings.batch_max_body_size;
+    batch_too_large(settings, format!("the body is larger than {} bytes", limit))
+}
+
+/// RFC 7807 problem response to a batch over one of its limits, stating the limits so that
+/// clients can split their batches.
+fn batch_too_large(settings: &Settings, detail: String) -> HttpResponse {
+    HttpResponse::PayloadTooLarge()
+        .content_type("application/problem+json")
+        .json(serde_json::json!({
+            "type": "about:blank",
+            "title": "The batch is too large",
+            "status": 413,
+            "detail": detail,
+            "limits": batch_limits(settings),
+        }))
+}
+
+fn batch_limits(settings: &Settings) -> Value {
+    serde_json::json!({
+        "maxItems": settings.batch_max_items,
+        "maxBodySize": settings.batch_max_body_size,
+    })
+}
+
+enum CsvError {
+    Invalid(csv::Error),
+    TooManyRows,
+}
+
+impl From<csv::Error> for CsvError {
+    fn from(err: csv::Error) -> Self {
+        CsvError::Invalid(err)
+    }
+}
+
+/// Appends the CSV_GEO_COLUMNS to the header row and to every record of a CSV body, of up to
+/// GEOIP_RS_BATCH_MAX_ITEMS records.
+fn enrich_csv<P: GeoProvider>(
+    db: &P,
+    settings: &Settings,
+    body: &[u8],
+    column: Option<&str>,
+    language: &str,
+) -> Result<Vec<csv::StringRecord>, CsvError> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(body);
+
+    let mut headers = reader.headers()?.clone();
+    let ip_column = enrich_headers(&mut headers, column);
+    let mut records = vec![headers];
+
+    for record in reader.records() {
+        if records.len() > settings.batch_max_items {
+            return Err(CsvError::TooManyRows);
+        }
+        let mut record = record?;
+        enrich_record(db, settings, &mut record, ip_column, language);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+/// Appends the CSV_GEO_COLUMNS to the header row, returning the index of the IP address column:
+/// `column`, or `ip`, or the first one.
+fn enrich_headers(headers: &mut csv::StringRecord, column: Option<&str>) -> usize {
+    let ip_column = headers
+        .iter()
+        .position(|header| match column {
+            Some(column) => header == column,
+            None => header.eq_ignore_ascii_case("ip"),
+        })
+        .unwrap_or(0);
+
+    for geo_column in CSV_GEO_COLUMNS.iter() {
+        headers.push_field(geo_column);
+    }
+    ip_column
+}
+
+fn enrich_record<P: GeoProvider>(
+    db: &P,
+    settings: &Settings,
+    record: &mut csv::StringRecord,
+    ip_column: usize,
+    language: &str,
+) {
+    let geoip = resolve(db, &settings.resolver, record.get(ip_column).unwrap_or("").trim(), language);
+    for geo_column in CSV_GEO_COLUMNS.iter() {
+        record.push_field(&csv_value(&geoip[geo_column]));
+    }
+}
+
+fn write_csv(records: &[csv::StringRecord]) -> Result<Vec<u8>, csv::Error> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        writer.write_record(record)?;
+    }
+    writer.into_inner().map_err(|err| err.into_error().into())
+}
+
+pub(crate) fn csv_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::from(""),
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+fn db_file_path(args: &cli::Args) -> String {
+    if let Some(file) = args.get("db") {
+        return file.to_string();
+    }
+
+    if let Ok(file) = env::var("GEOIP_RS_DB_PATH") {
+        return file;
+    }
+
+    if let Some(file) = args.positional.first() {
+        return file.to_string();
+    }
+
+    panic!("You must specify the db path, either as a command line argument, with --db or as GEOIP_RS_DB_PATH env var");
+}
+
+/// Opens the database at startup. When the file is there but can't be opened, e.g. on a network
+/// file system blip or while its volume is being attached, it's retried with backoff for
+/// GEOIP_RS_DB_OPEN_RETRY_SECS seconds, 30 by default.
+async fn open_database(db_path: &str) -> Result<MaxMindProvider, MaxMindDBError> {
+    let window = match env::var("GEOIP_RS_DB_OPEN_RETRY_SECS") {
+        Ok(secs) => Duration::from_secs(
+            secs.parse()
+                .unwrap_or_else(|_| panic!("GEOIP_RS_DB_OPEN_RETRY_SECS must be a number, got {}", secs)),
+        ),
+        Err(_) => DEFAULT_DB_OPEN_RETRY,
+    };
+
+    let started = Instant::now();
+    let mut wait = Duration::from_millis(250);
+    loop {
+        match MaxMindProvider::open(db_path) {
+            Ok(provider) => return Ok(provider),
+            Err(err) if Path::new(db_path).exists() && started.elapsed() + wait <= window => {
+                eprintln!("Can not open {}: {}, retrying in {:.2}s", db_path, err, wait.as_secs_f64());
+                actix_rt::time::delay_for(wait).await;
+                wait = (wait * 2).min(Duration::from_secs(5));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Runs the command named by the first argument, serving the API by default.
+pub async fn run() {
+    dotenv::from_path(".env").ok();
+
+    let args: Vec<String> = env::args().collect();
+    let exit_code = match args.get(1).map(String::as_str) {
+        Some("serve") => {
+            serve(&cli::Args::parse(&args[2..])).await;
+            None
+        }
+        Some("enrich-log") => Some(enrich_log::run(&cli::Args::parse(&args[2..]))),
+        Some("inspect") => Some(inspect::run(&cli::Args::parse(&args[2..]))),
+        Some("lookup") => Some(lookup::run(&cli::Args::parse(&args[2..]))),
+        Some("verify") => Some(verify::run(&cli::Args::parse(&args[2..]))),
+        Some("download") => Some(download::run(&cli::Args::parse(&args[2..]))),
+        _ => {
+            serve(&cli::Args::parse(&args[1..])).await;
+            None
+        }
+    };
+    if let Some(exit_code) = exit_code {
+        process::exit(exit_code);
+    }
+}
+
+/// Returns the geoip API as a scope mounted at `path`, so it can be added to any actix application
+/// along with its own middlewares, e.g. `App::new().service(service("/geoip", db))`.
+pub fn service<P: GeoProvider>(path: &str, db: Db<P>) -> Scope {
+    // Bodies of POST / are batches too
+    let payload = web::PayloadConfig::new(db.settings.batch_max_body_size);
+    web::scope(path).data(db).app_data(payload).configure(routes::<P>)
+}
+
+fn routes<P: GeoProvider>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::post().to(index_post::<P>))
+        .route("/", web::route().to(index::<P>))
+        .route("/batch", web::post().to(batch_json::<P>))
+        .route("/batch/csv", web::post().to(batch_csv::<P>))
+        .route("/batch/jsonl", web::post().to(batch_jsonl::<P>))
+        .route("/country/{ip}", web::get().to(country::<P>))
+        .route("/city/{ip}", web::get().to(city::<P>))
+        .route("/asn/{ip}", web::get().to(asn::<P>))
+        .route("/risk", web::get().to(risk::<P>))
+        .route("/allowed", web::get().to(allowed::<P>))
+        .route("/record/{ip}", web::get().to(record::<P>))
+        .route("/fields", web::get().to(fields::<P>))
+        .route("/{ip}", web::get().to(index_ip::<P>));
+}
+
+/// Headers sent with every response: the schema version and, when configured, the
+/// deprecation Warning and Sunset date of the current schema.
+fn default_headers(deprecation_warning: Option<&str>, sunset: Option<&str>) -> DefaultHeaders {
+    let mut headers = DefaultHeaders::new().header("X-GeoIP-Schema-Version", SCHEMA_VERSION);
+    if let Some(deprecation_warning) = deprecation_warning {
+        headers = headers.header("Warning", format!("299 - \"{}\"", deprecation_warning.replace('"', "'")));
+    }
+    if let Some(sunset) = sunset {
+        headers = headers.header("Sunset", sunset);
+    }
+    headers
+}
+
+/// Address of an optional listener, from a command line option or an env var.
+fn listener_bind(args: &cli::Args, option: &str, var: &str) -> Option<String> {
+    args.get(option).map(String::from).or_else(|| env::var(var).ok())
+}
+
+/// Refuses to start a listener having no API keys when a key is required, as it would answer
+/// anyone the HTTP API refuses.
+fn refuse_keyless(keys: &KeyStore, var: &str) {
+    if keys.required() {
+        panic!("{} can not be used with GEOIP_RS_REQUIRE_API_KEY, its protocol has no API keys", var);
+    }
+}
+
+/// Reopens the access log on SIGUSR1, sent by logrotate once it moved the file away.
+#[cfg(unix)]
+fn reopen_on_sigusr1(access_log: Arc<AccessLog>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigusr1 = signal(SignalKind::user_defined1()).expect("Can not handle SIGUSR1");
+    actix_rt::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            access_log.reopen();
+        }
+    });
+}
+
+async fn serve(args: &cli::Args) {
+    let bind = match args.get("bind") {
+        Some(bind) => bind.to_string(),
+        None => {
+            let host = env::var("GEOIP_RS_HOST").unwrap_or_else(|_| String::from("127.0.0.1"));
+            let port = env::var("GEOIP_RS_PORT").unwrap_or_else(|_| String::from("3000"));
+            format!("{}:{}", host, port)
+        }
+    };
+
+    let workers = args
+        .get("workers")
+        .map(String::from)
+        .or_else(|| env::var("GEOIP_RS_WORKERS").ok())
+        .map(|workers| workers.parse::<usize>().expect("workers must be a number"));
+    let keep_alive = args
+        .get("keep-alive")
+        .map(String::from)
+        .or_else(|| env::var("GEOIP_RS_KEEP_ALIVE").ok())
+        .map(|secs| secs.parse::<usize>().expect("keep-alive must be a number of seconds"));
+    let shutdown_timeout = args
+        .get("shutdown-timeout")
+        .map(String::from)
+        .or_else(|| env::var("GEOIP_RS_SHUTDOWN_TIMEOUT").ok())
+        .map(|secs| secs.parse::<u64>().expect("shutdown-timeout must be a number of seconds"));
+
+    let tls_files = (
+        args.get("tls-cert").map(String::from).or_else(|| env::var("GEOIP_RS_TLS_CERT").ok()),
+        args.get("tls-key").map(String::from).or_else(|| env::var("GEOIP_RS_TLS_KEY").ok()),
+    );
+    let scheme = if tls_files == (None, None) { "http" } else { "https" };
+    #[cfg(feature = "tls")]
+    let tls = match tls_files {
+        (Some(cert), Some(key)) => Some(tls::config(&cert, &key).unwrap_or_else(|err| panic!("{}", err))),
+        (None, None) => None,
+        _ => panic!("GEOIP_RS_TLS_CERT and GEOIP_RS_TLS_KEY must be set together"),
+    };
+    #[cfg(not(feature = "tls"))]
+    if tls_files != (None, None) {
+        panic!("geoip-rs was built without the tls feature, GEOIP_RS_TLS_CERT and GEOIP_RS_TLS_KEY can not be set");
+    }
+
+    println!("Listening on {}://{}", scheme, bind);
+
+    let db_path = db_file_path(args);
+    let mut updater = Updater::from_env().unwrap_or_else(|err| panic!("{}", err));
+    if updater.is_some() && env::var("GEOIP_RS_PRIMARY_URL").is_ok() {
+        panic!("Replicas get the database from their primary, GEOIP_RS_AUTO_UPDATE can not be set along with GEOIP_RS_PRIMARY_URL");
+    }
+    if let Some(updater) = &mut updater {
+        if let Err(err) = updater.download_missing(Path::new(&db_path)) {
+            eprintln!("{}", err);
+        }
+    }
+    let provider = match open_database(&db_path).await {
+        Ok(provider) => provider,
+        Err(err) if env_flag("GEOIP_RS_START_WITHOUT_DATABASE") => {
+            println!("Can not open {}: {}, answering 503 until it can", db_path, err);
+            MaxMindProvider::pending(&db_path)
+        }
+        Err(err) => panic!("Can not open {}: {}", db_path, err),
+    };
+    if provider.is_loaded() {
+        let database_type = provider.metadata().database_type;
+        println!("Opened {}, a {} database ({:?} edition)", db_path, database_type, provider.edition());
+    }
+    let db = Db::from_env(provider).unwrap_or_else(|err| panic!("{}", err));
+    country_names::load();
+    let rate_limiter = RateLimiter::from_env().unwrap_or_else(|err| panic!("{}", err)).map(Arc::new);
+
+    if let Some(udp_bind) = listener_bind(args, "udp", "GEOIP_RS_UDP_BIND") {
+        refuse_keyless(db.keys(), "GEOIP_RS_UDP_BIND");
+        let format = args
+            .get("udp-format")
+            .map(String::from)
+            .or_else(|| env::var("GEOIP_RS_UDP_FORMAT").ok())
+            .unwrap_or_else(|| String::from("json"));
+        let format = udp::Format::parse(&format).unwrap_or_else(|err| panic!("{}", err));
+        let allowed = udp::allowed_peers().unwrap_or_else(|err| panic!("{}", err));
+        udp::spawn(&udp_bind, format, allowed, db.clone())
+            .unwrap_or_else(|_| panic!("Can not bind to {}", udp_bind));
+        println!("Listening on udp://{}", udp_bind);
+    }
+
+    if let Some(lines_bind) = listener_bind(args, "lines", "GEOIP_RS_LINES_BIND") {
+        refuse_keyless(db.keys(), "GEOIP_RS_LINES_BIND");
+        lines::spawn(&lines_bind, db.clone()).unwrap_or_else(|_| panic!("Can not bind to {}", lines_bind));
+        println!("Listening on tcp://{} (line protocol)", lines_bind);
+    }
+
+    if let Some(memcached_bind) = listener_bind(args, "memcached", "GEOIP_RS_MEMCACHED_BIND") {
+        refuse_keyless(db.keys(), "GEOIP_RS_MEMCACHED_BIND");
+        memcached::spawn(&memcached_bind, db.clone())
+            .unwrap_or_else(|_| panic!("Can not bind to {}", memcached_bind));
+        println!("Listening on tcp://{} (memcached protocol)", memcached_bind);
+    }
+
+    #[cfg(feature = "grpc")]
+    if let Some(grpc_port) = listener_bind(args, "grpc-port", "GEOIP_RS_GRPC_PORT") {
+        let host = env::var("GEOIP_RS_HOST").unwrap_or_else(|_| String::from("127.0.0.1"));
+        let grpc_bind = format!("{}:{}", host, grpc_port);
+        grpc::spawn(&grpc_bind, db.clone()).unwrap_or_else(|_| panic!("Can not bind to {}", grpc_bind));
+        println!("Listening on grpc://{}", grpc_bind);
+    }
+
+    let deprecation_warning = env::var("GEOIP_RS_DEPRECATION_WARNING").ok();
+    let sunset = env::var("GEOIP_RS_SUNSET").ok();
+    if let Some(sunset) = &sunset {
+        sunset
+            .parse::<HttpDate>()
+            .unwrap_or_else(|_| panic!("GEOIP_RS_SUNSET must be an HTTP date, got {}", sunset));
+    }
+
+    let slow_request = env::var("GEOIP_RS_SLOW_REQUEST_MS").ok().map(|millis| {
+        Duration::from_millis(millis.parse().expect("GEOIP_RS_SLOW_REQUEST_MS must be a number"))
+    });
+
+    let access_log = AccessLog::from_env().unwrap_or_else(|err| panic!("{}", err)).map(Arc::new);
+    #[cfg(unix)]
+    {
+        if let Some(access_log) = access_log.clone() {
+            reopen_on_sigusr1(access_log);
+        }
+    }
+
+    let admin = Admin::from_env().unwrap_or_else(|err| panic!("{}", err));
+    let diagnostics = Arc::new(Diagnostics::new(&db_path));
+
+    let reloads = Arc::new(Reloads::from_env().unwrap_or_else(|err| panic!("{}", err)));
+    let hooks = db.hooks().clone();
+    reloads.on_reload(move || hooks.reload());
+    let serve_metrics = env_flag("GEOIP_RS_METRICS");
+    let requests = Arc::new(metrics::Requests::default());
+    let serve_networks = env_flag("GEOIP_RS_NETWORKS");
+    let reverse = if env_flag("GEOIP_RS_REVERSE_GEOCODING") {
+        Some(Reverse::new(db.provider().clone()))
+    } else {
+        None
+    };
+
+    if !db.provider().is_loaded() {
+        reload::load_when_ready(db.provider().clone(), reloads.clone());
+    }
+    #[cfg(unix)]
+    reload::reload_on_sighup(db.provider().clone(), reloads.clone());
+
+    let downloads = Arc::new(updater::Status::default());
+    if let Some(replica) = Replica::from_env().unwrap_or_else(|err| panic!("{}", err)) {
+        replica.spawn(db.provider().clone(), reloads.clone(), downloads.clone());
+    }
+    if let Some(updater) = updater {
+        updater.spawn(db.provider().clone(), reloads.clone(), downloads.clone());
+    }
+
+    let admin_ui = if env_flag("GEOIP_RS_ADMIN_UI") {
+        Some(Arc::new(AdminUi {
+            provider: db.provider().clone(),
+            cache: db.cache().cloned(),
+            requests: requests.clone(),
+            reloads: reloads.clone(),
+            downloads: downloads.clone(),
+            diagnostics: diagnostics.clone(),
+        }))
+    } else {
+        None
+    };
+    // The admin UI shows the requests served, even when they're not exported as metrics
+    let record_requests = serve_metrics || admin_ui.is_some();
+
+    let mut server = HttpServer::new(move || {
+        let rate_limiter = rate_limiter.clone();
+        let in_flight = diagnostics.worker();
+        let access_log = access_log.clone();
+        let reloads = reloads.clone();
+        let reverse = reverse.clone();
+        let requests = requests.clone();
+        let recorder = requests.clone();
+        let keys = db.keys().clone();
+        let limited_keys = keys.clone();
+        let limited_callers = db.settings.clone();
+        let logged_callers = db.settings.clone();
+        let provider = db.provider().clone();
+        App::new()
+            .wrap_fn(move |req, srv| {
+                // Probes aren't limited, and keys with a rate limit of their own aren't limited by their IP address
+                let probe = matches!(metrics::endpoint(req.path()), "health" | "healthz" | "readyz");
+                let key_limit = if probe {
+                    None
+                } else {
+                    api_keys::api_key(req.request()).and_then(|key| limited_keys.rate_limit(&key))
+                };
+                // Behind trusted proxies, the callers are limited rather than the proxies
+                let caller = || limited_callers.client_ip.caller_ip(req.headers(), req.peer_addr());
+                let checked = match (key_limit, &rate_limiter) {
+                    _ if probe => Ok(None),
+                    (Some(checked), _) => checked.map(Some),
+                    (None, Some(rate_limiter)) => caller().map_or(Ok(None), |ip| rate_limiter.check(ip).map(Some)),
+                    _ => Ok(None),
+                };
+                let (response, rate_limit) = match checked {
+                    Ok(rate_limit) => (Ok(srv.call(req)), rate_limit),
+                    Err(rejection) => (Err(req.into_response(rejection)), None),
+                };
+                async move {
+                    let mut response = match response {
+                        Ok(response) => response.await?,
+                        Err(rejection) => rejection,
+                    };
+                    if let Some(rate_limit) = rate_limit {
+                        rate_limit.set_headers(response.headers_mut());
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let started = slow_request.map(|_| {
+                    let context = format!(
+                        "{} {} client={}",
+                        req.method(),
+                        req.uri(),
+                        req.connection_info().remote().unwrap_or("-")
+                    );
+                    slow_log::Started::now(context)
+                });
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if let (Some(started), Some(threshold)) = (started, slow_request) {
+                        started.log_if_slower(threshold, response.status().as_u16());
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let logged = access_log.clone().map(|access_log| {
+                    let header_value = |name: HeaderName| {
+                        req.headers().get(name).and_then(|value| value.to_str().ok()).map(String::from)
+                    };
+                    let entry = access_log::Entry {
+                        client: logged_callers
+                            .client_ip
+                            .caller_ip(req.headers(), req.peer_addr())
+                            .map_or_else(|| String::from("-"), |ip| ip.to_string()),
+                        time: SystemTime::now(),
+                        request: format!("{} {} {:?}", req.method(), req.uri(), req.version()),
+                        status: 0,
+                        bytes: None,
+                        referer: header_value(header::REFERER),
+                        user_agent: header_value(header::USER_AGENT),
+                    };
+                    (access_log, entry)
+                });
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if let Some((access_log, mut entry)) = logged {
+                        entry.status = response.status().as_u16();
+                        entry.bytes = match response.response().body().size() {
+                            BodySize::Sized(bytes) => Some(bytes as u64),
+                            BodySize::Sized64(bytes) => Some(bytes),
+                            BodySize::None | BodySize::Empty => Some(0),
+                            BodySize::Stream => None,
+                        };
+                        access_log.log(&entry);
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let observed = if record_requests {
+                    let key = match api_keys::api_key(req.request()) {
+                        Some(key) if keys.contains(&key) => metrics::hashed_key(&key),
+                        Some(_) => String::from("invalid"),
+                        None => String::from("none"),
+                    };
+                    Some((recorder.clone(), metrics::endpoint(req.path()), key, Instant::now()))
+                } else {
+                    None
+                };
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    if let Some((recorder, endpoint, key, started)) = observed {
+                        let content_type =
+                            response.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+                        let labels = metrics::Labels {
+                            endpoint,
+                            format: metrics::format(content_type),
+                            cache: if response.status() == StatusCode::NOT_MODIFIED { "hit" } else { "miss" },
+                            key,
+                        };
+                        recorder.observe(labels, started.elapsed());
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let needs_database =
+                    !matches!(metrics::endpoint(req.path()), "health" | "healthz" | "readyz" | "metrics" | "admin");
+                let response = if needs_database && !provider.is_loaded() {
+                    Err(req.into_response(health::not_ready()))
+                } else {
+                    Ok(srv.call(req))
+                };
+                async move {
+                    match response {
+                        Ok(response) => response.await,
+                        Err(not_ready) => Ok(not_ready),
+                    }
+                }
+            })
+            .wrap_fn(move |req, srv| {
+                let in_flight = InFlight::start(&in_flight);
+                let response = srv.call(req);
+                async move {
+                    let response = response.await;
+                    drop(in_flight);
+                    response
+                }
+            })
+            .wrap(default_headers(deprecation_warning.as_deref(), sunset.as_deref()))
+            .wrap(Cors::new().send_wildcard().finish())
+            .service(
+                web::resource("/debug")
+                    .data(admin.clone())
+                    .data(diagnostics.clone())
+                    .route(web::get().to(diagnostics::debug)),
+            )
+            .service(
+                web::resource("/database/blob")
+                    .data(admin.clone())
+                    .data(db.provider().clone())
+                    .route(web::get().to(cluster::blob)),
+            )
+            .service(
+                web::resource("/health")
+                    .data(db.provider().clone())
+                    .data(downloads.clone())
+                    .route(web::get().to(health::health)),
+            )
+            .service(web::resource("/healthz").route(web::get().to(health::healthz)))
+            .service(
+                web::resource("/readyz")
+                    .data(db.provider().clone())
+                    .route(web::get().to(health::readyz)),
+            )
+            .service(
+                web::resource("/dbinfo")
+                    .data(db.provider().clone())
+                    .route(web::get().to(health::dbinfo)),
+            )
+            .service(
+                web::resource("/admin/last-reload")
+                    .data(admin.clone())
+                    .data(reloads.clone())
+                    .route(web::get().to(reload::last_reload)),
+            )
+            .service(
+                web::resource("/admin/reload")
+                    .data(admin.clone())
+                    .data(reloads.clone())
+                    .data(db.provider().clone())
+                    .route(web::post().to(reload::reload)),
+            )
+            .configure(|cfg| {
+                if serve_metrics {
+                    cfg.service(
+                        web::resource("/metrics")
+                            .data(reloads)
+                            .data(downloads.clone())
+                            .data(requests.clone())
+                            .data(db.cache().cloned())
+                            .route(web::get().to(metrics::metrics)),
+                    );
+                }
+                if serve_networks {
+                    cfg.service(
+                        web::resource("/networks")
+                            .data(db.provider().clone())
+                            .data(db.keys().clone())
+                            .route(web::get().to(networks::networks)),
+                    );
+                }
+                if let Some(reverse) = reverse {
+                    cfg.service(
+                        web::resource("/reverse")
+                            .data(reverse)
+                            .data(db.keys().clone())
+                            .route(web::get().to(reverse::reverse)),
+                    );
+                }
+                if let Some(admin_ui) = admin_ui.clone() {
+                    cfg.service(
+                        web::resource("/admin/ui")
+                            .data(admin.clone())
+                            .route(web::get().to(admin_ui::page)),
+                    );
+                    cfg.service(
+                        web::resource("/admin/stats")
+                            .data(admin.clone())
+                            .data(admin_ui)
+                            .route(web::get().to(admin_ui::stats)),
+                    );
+                }
+            })
+            .service(service("", db.clone()))
+    });
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    if let Some(keep_alive) = keep_alive {
+        // 0 disables keep-alive
+        server = server.keep_alive(Some(keep_alive).filter(|&secs| secs > 0));
+    }
+    if let Some(shutdown_timeout) = shutdown_timeout {
+        server = server.shutdown_timeout(shutdown_timeout);
+    }
+
+    let listener = handover::listener(&bind).unwrap_or_else(|err| panic!("Can not bind to {}: {}", bind, err));
+    #[cfg(feature = "tls")]
+    let server = match tls {
+        Some(config) => server.listen_rustls(listener.try_clone().unwrap(), config),
+        None => server.listen(listener.try_clone().unwrap()),
+    };
+    #[cfg(not(feature = "tls"))]
+    let server = server.listen(listener.try_clone().unwrap());
+    let server = server.unwrap_or_else(|_| panic!("Can not listen on {}", bind)).run();
+    #[cfg(unix)]
+    handover::upgrade_on_sigusr2(listener, server.clone());
+
+    server.await.unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test;
+    use serde::de::DeserializeOwned;
+    use serde_json::Map;
+
+    use super::*;
+    use crate::provider::DbMetadata;
+
+    /// A city database without records.
+    struct Empty;
+
+    impl GeoProvider for Empty {
+        fn lookup<T: DeserializeOwned>(&self, _ip: IpAddr) -> Result<T, MaxMindDBError> {
+            Err(MaxMindDBError::AddressNotFoundError(String::from("Address not found in database")))
+        }
+
+        fn metadata(&self) -> DbMetadata {
+            DbMetadata {
+                database_type: String::from("GeoLite2-City"),
+                languages: vec![String::from("en")],
+                ..DbMetadata::default()
+            }
+        }
+
+        fn reload(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    struct Team;
+
+    impl ResponseHook for Team {
+        fn process(&self, _ip: IpAddr, response: &mut Map<String, Value>) {
+            response.insert(String::from("team"), Value::from("platform"));
+        }
+
+        fn fields(&self) -> &[&'static str] {
+            &["team"]
+        }
+    }
+
+    #[actix_rt::test]
+    async fn registered_hooks_add_their_fields() {
+        let db = Db::from_env(Empty).unwrap().with_hook(Team);
+        let mut app = test::init_service(App::new().service(service("", db))).await;
+
+        let req = test::TestRequest::get().uri("/203.0.113.7").to_request();
+        let geoip: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(geoip["team"], "platform");
+
+        let req = test::TestRequest::get().uri("/fields").to_request();
+        let fields: Value = test::read_response_json(&mut app, req).await;
+        assert_eq!(fields["fields"]["team"]["source"], "hook");
+    }
+}
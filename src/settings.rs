@@ -0,0 +1,86 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+
+use actix_web::http::StatusCode;
+
+use crate::client_ip::ClientIpPicker;
+use crate::resolver::Options;
+use crate::shape::Case;
+
+/// Settings of the geoip-rs server, read from GEOIP_RS_* env vars.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    /// How IP addresses are resolved.
+    pub resolver: Options,
+    /// Status of JSON responses for IP addresses not found in the database (GEOIP_RS_NOT_FOUND_STATUS).
+    pub not_found_status: StatusCode,
+    /// Report lookup and serialization times in responses (GEOIP_RS_TIMING).
+    pub timing: bool,
+    /// Report how the IP address to resolve was picked in responses (GEOIP_RS_IP_SOURCE).
+    pub ip_source: bool,
+    /// How the address of the caller is picked (GEOIP_RS_CLIENT_IP_STRATEGY).
+    pub client_ip: ClientIpPicker,
+    /// Most IP addresses or CSV rows of a batch (GEOIP_RS_BATCH_MAX_ITEMS).
+    pub batch_max_items: usize,
+    /// Most bytes of the body of a batch (GEOIP_RS_BATCH_MAX_BODY_SIZE).
+    pub batch_max_body_size: usize,
+    /// Case of the names of the fields of responses without a `case` param (GEOIP_RS_FIELD_CASE).
+    pub field_case: Option<Case>,
+}
+
+impl Settings {
+    pub fn from_env() -> Settings {
+        Settings {
+            resolver: Options {
+                resolve_tunnels: env_flag("GEOIP_RS_RESOLVE_TUNNELS"),
+                short_subdivision_codes: env_flag("GEOIP_RS_SHORT_SUBDIVISION_CODES"),
+                country_centroids: env_flag("GEOIP_RS_COUNTRY_CENTROIDS"),
+            },
+            not_found_status: env_status("GEOIP_RS_NOT_FOUND_STATUS", StatusCode::OK),
+            timing: env_flag("GEOIP_RS_TIMING"),
+            ip_source: env_flag("GEOIP_RS_IP_SOURCE"),
+            client_ip: ClientIpPicker::from_env().unwrap_or_else(|err| panic!("{}", err)),
+            batch_max_items: env_usize("GEOIP_RS_BATCH_MAX_ITEMS", 100_000),
+            batch_max_body_size: env_usize("GEOIP_RS_BATCH_MAX_BODY_SIZE", 16 * 1024 * 1024),
+            field_case: env::var("GEOIP_RS_FIELD_CASE")
+                .ok()
+                .map(|case| Case::parse(&case).unwrap_or_else(|err| panic!("GEOIP_RS_FIELD_CASE: {}", err))),
+        }
+    }
+}
+
+pub(crate) fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|value| matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+fn env_status(name: &str, default: StatusCode) -> StatusCode {
+    match env::var(name) {
+        Ok(value) => StatusCode::from_bytes(value.as_bytes())
+            .unwrap_or_else(|_| panic!("{} must be an HTTP status code, got {}", name, value)),
+        Err(_) => default,
+    }
+}
+
+fn env_usize(name: &str, default: usize) -> usize {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number, got {}", name, value)),
+        Err(_) => default,
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The shape of the responses asked for by the callers: the `fields` query param keeps only some
+//! fields, e.g. `?fields=countryCode,cityName`, and the `case` query param writes their names in
+//! `snake_case` or `camelCase`, GEOIP_RS_FIELD_CASE by default.
+//!
+//! Like the operator defined field names, the case is changed when the responses are serialized,
+//! while fields are picked by their original names, in either case.
+
+use std::collections::HashSet;
+
+use serde_json::{Map, Value};
+
+/// How the names of the fields are written.
+#[derive(Clone, Copy, Debug, PartialEq, Hash)]
+pub enum Case {
+    /// `country_code`.
+    Snake,
+    /// `countryCode`.
+    Camel,
+}
+
+impl Case {
+    pub fn parse(name: &str) -> Result<Case, String> {
+        match name {
+            "snake_case" | "snake" => Ok(Case::Snake),
+            "camelCase" | "camel" => Ok(Case::Camel),
+            _ => Err(format!("the case must be snake_case or camelCase, got {}", name)),
+        }
+    }
+
+    /// `name`, written in this case.
+    pub fn convert(self, name: &str) -> String {
+        let mut converted = String::with_capacity(name.len() + 4);
+        match self {
+            Case::Snake => {
+                for c in name.chars() {
+                    if c.is_ascii_uppercase() {
+                        converted.push('_');
+                        converted.push(c.to_ascii_lowercase());
+                    } else {
+                        converted.push(c);
+                    }
+                }
+            }
+            Case::Camel => {
+                let mut upper = false;
+                for c in name.chars() {
+                    match c {
+                        '_' => upper = !converted.is_empty(),
+                        c if upper => {
+                            converted.push(c.to_ascii_uppercase());
+                            upper = false;
+                        }
+                        c => converted.push(c),
+                    }
+                }
+            }
+        }
+        converted
+    }
+}
+
+/// The fields kept, all when `None`, and the case of their names, unchanged when `None`.
+#[derive(Clone, Debug, Default, Hash)]
+pub struct Shape {
+    /// Names of the fields kept, in camel case.
+    fields: Option<Vec<String>>,
+    case: Option<Case>,
+}
+
+impl Shape {
+    /// The shape asked for with the `fields` and `case` params, in the `default_case`
+    /// without a `case`.
+    pub fn parse(fields: Option<&str>, case: Option<&str>, default_case: Option<Case>) -> Result<Shape, String> {
+        let fields = fields.map(|fields| {
+            fields
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| Case::Camel.convert(name))
+                .collect()
+        });
+        let case = match case {
+            Some(case) => Some(Case::parse(case)?),
+            None => default_case,
+        };
+
+        Ok(Shape { fields, case })
+    }
+
+    /// The shape of the responses without params.
+    pub fn with_case(case: Option<Case>) -> Shape {
+        Shape { fields: None, case }
+    }
+
+    /// Removes the fields not asked for, from a response body or each body of an array of
+    /// responses. The `error` and `range` of responses for IP addresses not found are always kept.
+    pub fn select(&self, response: &mut Value) {
+        let fields = match &self.fields {
+            Some(fields) => fields.iter().map(String::as_str).collect::<HashSet<&str>>(),
+            None => return,
+        };
+
+        match response {
+            Value::Array(responses) => responses.iter_mut().for_each(|response| self.select(response)),
+            Value::Object(body) => {
+                let kept = std::mem::take(body).into_iter().filter(|(name, _)| {
+                    name == "error" || name == "range" || fields.contains(Case::Camel.convert(name).as_str())
+                });
+                *body = kept.collect();
+            }
+            _ => {}
+        }
+    }
+
+    /// Writes the names of the fields of a response body, or of each body of an array of
+    /// responses, in the case asked for.
+    pub fn write_case(&self, response: &mut Value) {
+        let case = match self.case {
+            Some(case) => case,
+            None => return,
+        };
+
+        match response {
+            Value::Array(responses) => responses.iter_mut().for_each(|response| self.write_case(response)),
+            Value::Object(body) => {
+                let renamed: Map<String, Value> =
+                    std::mem::take(body).into_iter().map(|(name, value)| (case.convert(&name), value)).collect();
+                *body = renamed;
+            }
+            _ => {}
+        }
+    }
+}
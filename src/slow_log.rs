@@ -0,0 +1,72 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Logs requests slower than GEOIP_RS_SLOW_REQUEST_MS, with the page faults taken while serving
+//! them: major faults point at database pages read from disk through the memory map.
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+/// Time and page faults of the current thread at the start of a request.
+pub struct Started {
+    at: Instant,
+    faults: Option<(u64, u64)>,
+    context: String,
+}
+
+impl Started {
+    /// `context` describes the request in the log line, e.g. method, URI and client address.
+    pub fn now(context: String) -> Started {
+        Started {
+            at: Instant::now(),
+            faults: page_faults(),
+            context,
+        }
+    }
+
+    pub fn log_if_slower(&self, threshold: Duration, status: u16) {
+        let elapsed = self.at.elapsed();
+        if elapsed < threshold {
+            return;
+        }
+
+        let faults = match (self.faults, page_faults()) {
+            (Some((minor_before, major_before)), Some((minor, major))) => format!(
+                " minor_faults={} major_faults={}",
+                minor - minor_before,
+                major - major_before
+            ),
+            _ => String::new(),
+        };
+
+        eprintln!(
+            "WARN slow request: {} status={} elapsed_ms={:.3}{}",
+            self.context,
+            status,
+            elapsed.as_secs_f64() * 1000.0,
+            faults
+        );
+    }
+}
+
+/// Minor and major page faults of the current thread, on Linux.
+fn page_faults() -> Option<(u64, u64)> {
+    let stat = fs::read_to_string("/proc/thread-self/stat").ok()?;
+    // Fields after the parenthesized command name, starting from the state
+    let fields: Vec<&str> = stat.rsplit(')').next()?.split_whitespace().collect();
+
+    let minor = fields.get(7)?.parse().ok()?;
+    let major = fields.get(9)?.parse().ok()?;
+    Some((minor, major))
+}
@@ -0,0 +1,135 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Historical lookups: requests with a `date` query param, e.g. `?date=2023-06-01`, are resolved
+//! against the snapshot of the database built the closest to that date, out of the `.mmdb` files
+//! of the GEOIP_RS_SNAPSHOTS directory. Snapshots are dated by the build time in their metadata,
+//! whatever their file names. Subdirectories are looked into too, one level deep, since MaxMind
+//! archives unpack into directories like `GeoLite2-City_20230601/`.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::provider::{GeoProvider, MaxMindProvider};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Default)]
+pub struct Snapshots {
+    /// Snapshots by build time, oldest first.
+    snapshots: Vec<(u64, Arc<MaxMindProvider>)>,
+}
+
+impl Snapshots {
+    pub fn from_env() -> Result<Snapshots, String> {
+        let dir = match env::var("GEOIP_RS_SNAPSHOTS") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(Snapshots::default()),
+        };
+
+        let mut snapshots = Vec::new();
+        for path in database_files(&dir)? {
+            let provider = MaxMindProvider::open(&path)
+                .map_err(|err| format!("Can not open the snapshot {}: {}", path.display(), err))?;
+            snapshots.push((provider.build_epoch(), Arc::new(provider)));
+        }
+        if snapshots.is_empty() {
+            return Err(format!("GEOIP_RS_SNAPSHOTS has no .mmdb files in {}", dir.display()));
+        }
+        snapshots.sort_by_key(|(build_epoch, _)| *build_epoch);
+        println!("Loaded {} database snapshots from {}", snapshots.len(), dir.display());
+
+        Ok(Snapshots { snapshots })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// The snapshot built the closest to `date`, a `YYYY-MM-DD` date, the older one on ties.
+    pub fn closest(&self, date: &str) -> Result<&Arc<MaxMindProvider>, String> {
+        if self.snapshots.is_empty() {
+            return Err(String::from("no database snapshots are configured"));
+        }
+        let epoch = parse_date(date).ok_or_else(|| format!("{} is not a YYYY-MM-DD date", date))?;
+
+        self.snapshots
+            .iter()
+            .min_by_key(|(build_epoch, _)| (*build_epoch as i64 - epoch as i64).abs())
+            .map(|(_, provider)| provider)
+            .ok_or_else(|| String::from("no database snapshots are configured"))
+    }
+}
+
+/// The `.mmdb` files of `dir` and of its subdirectories.
+fn database_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let entries = |dir: &Path| {
+        fs::read_dir(dir)
+            .map_err(|err| format!("Can not read {}: {}", dir.display(), err))
+            .map(|entries| entries.filter_map(Result::ok).map(|entry| entry.path()).collect::<Vec<_>>())
+    };
+
+    let mut files = Vec::new();
+    for path in entries(dir)? {
+        if path.is_dir() {
+            files.extend(entries(&path)?.into_iter().filter(|path| is_database(path)));
+        } else if is_database(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn is_database(path: &Path) -> bool {
+    path.is_file() && path.extension().map_or(false, |extension| extension == "mmdb")
+}
+
+/// The `YYYY-MM-DD` date of a Unix time, in UTC.
+pub fn date_of(epoch: u64) -> String {
+    // Civil from days, http://howardhinnant.github.io/date_algorithms.html
+    let days = (epoch / SECONDS_PER_DAY) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// The Unix time of the start of a `YYYY-MM-DD` date, in UTC.
+fn parse_date(date: &str) -> Option<u64> {
+    let mut parts = date.splitn(3, '-');
+    let year: i64 = parts.next().filter(|year| year.len() == 4)?.parse().ok()?;
+    let month: i64 = parts.next().filter(|month| month.len() == 2)?.parse().ok()?;
+    let day: i64 = parts.next().filter(|day| day.len() == 2)?.parse().ok()?;
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Days from civil, http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    Some(days as u64 * SECONDS_PER_DAY)
+}
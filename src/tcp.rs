@@ -0,0 +1,90 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Connections of the TCP listeners, the line and memcached protocols, each served on a thread of
+//! its own: at most [MAX_CONNECTIONS] at once, the ones past that being closed right away, and
+//! the ones sending nothing for [READ_TIMEOUT] being closed too.
+
+use std::io::{self, ErrorKind};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const MAX_CONNECTIONS: usize = 256;
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Accepts the connections of `listener` on a dedicated thread, serving each with `serve`.
+/// `protocol` names the listener in the errors.
+pub fn accept<F>(listener: TcpListener, protocol: &'static str, serve: F)
+where
+    F: Fn(TcpStream) -> io::Result<()> + Send + Sync + 'static,
+{
+    let serve = Arc::new(serve);
+    let connections = Arc::new(AtomicUsize::new(0));
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    eprintln!("{} accept error: {}", protocol, err);
+                    continue;
+                }
+            };
+            let slot = match Slot::take(&connections) {
+                Some(slot) => slot,
+                None => {
+                    eprintln!("{} connection refused: {} connections already", protocol, MAX_CONNECTIONS);
+                    continue;
+                }
+            };
+            if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+                eprintln!("{} connection error: {}", protocol, err);
+                continue;
+            }
+
+            let serve = serve.clone();
+            thread::spawn(move || {
+                let _slot = slot;
+                match serve(stream) {
+                    Ok(()) => {}
+                    // Closed for being idle
+                    Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                    Err(err) => eprintln!("{} connection error: {}", protocol, err),
+                }
+            });
+        }
+    });
+}
+
+/// One of the [MAX_CONNECTIONS], given back when it's dropped.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(connections: &Arc<AtomicUsize>) -> Option<Slot> {
+        if connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+            connections.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+        Some(Slot(connections.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Custom response templates, with GEOIP_RS_TEMPLATES: Handlebars templates replacing the JSON
+//! body of an endpoint with any text. The values are escaped in the XML and HTML templates, and
+//! written as they are in the other ones.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use handlebars::Handlebars;
+use serde_json::Value;
+
+/// Operator defined Handlebars templates, replacing the JSON body of an endpoint.
+///
+/// Templates are loaded from the GEOIP_RS_TEMPLATES directory and named after the endpoint they
+/// replace and the format they produce: `index.xml.hbs` renders `/` and `/{ip}` as XML,
+/// `country.txt.hbs` renders `/country/{ip}` as plain text and so on.
+#[derive(Default)]
+pub struct Templates {
+    registry: Handlebars<'static>,
+    /// The XML and HTML templates, escaping `&`, `<`, `"` and so on in the values.
+    markup: Handlebars<'static>,
+    content_types: HashMap<String, &'static str>,
+}
+
+impl Templates {
+    pub fn from_env() -> Result<Templates, String> {
+        match env::var("GEOIP_RS_TEMPLATES") {
+            Ok(dir) => Templates::from_dir(Path::new(&dir)),
+            Err(_) => Ok(Templates::default()),
+        }
+    }
+
+    pub fn from_dir(dir: &Path) -> Result<Templates, String> {
+        let mut templates = Templates::default();
+        templates.registry.register_escape_fn(handlebars::no_escape);
+
+        let entries = fs::read_dir(dir).map_err(|err| format!("Can not read {}: {}", dir.display(), err))?;
+        for entry in entries {
+            let path = entry.map_err(|err| err.to_string())?.path();
+            let file_name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+
+            let parts: Vec<&str> = file_name.split('.').collect();
+            if parts.len() != 3 || parts[2] != "hbs" {
+                continue;
+            }
+            let (endpoint, format) = (parts[0], parts[1]);
+
+            // The last template of an endpoint wins, whatever its format
+            let (registry, other) = match format {
+                "xml" | "html" => (&mut templates.markup, &mut templates.registry),
+                _ => (&mut templates.registry, &mut templates.markup),
+            };
+            other.unregister_template(endpoint);
+            registry
+                .register_template_file(endpoint, &path)
+                .map_err(|err| format!("Invalid template {}: {}", path.display(), err))?;
+            templates.content_types.insert(endpoint.to_string(), content_type(format));
+        }
+
+        Ok(templates)
+    }
+
+    /// Renders the body of `endpoint` with its template, returning its content type and the
+    /// rendered body, or None if the endpoint has no template.
+    pub fn render(&self, endpoint: &str, data: &Value) -> Option<Result<(&'static str, String), String>> {
+        let content_type = self.content_types.get(endpoint)?;
+        let registry = if self.markup.has_template(endpoint) { &self.markup } else { &self.registry };
+
+        Some(
+            registry
+                .render(endpoint, data)
+                .map(|body| (*content_type, body))
+                .map_err(|err| err.to_string()),
+        )
+    }
+}
+
+fn content_type(format: &str) -> &'static str {
+    match format {
+        "json" => "application/json; charset=utf-8",
+        "xml" => "application/xml; charset=utf-8",
+        "html" => "text/html; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "js" => "application/javascript; charset=utf-8",
+        _ => "text/plain; charset=utf-8",
+    }
+}
@@ -0,0 +1,55 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTPS, with the `tls` feature: the server terminates TLS itself, with the PEM encoded
+//! certificate chain of GEOIP_RS_TLS_CERT and the private key of GEOIP_RS_TLS_KEY.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use rustls::{Certificate, NoClientAuth, PrivateKey, ServerConfig};
+
+/// The TLS configuration of the certificate chain at `cert_path` and of the key at `key_path`,
+/// either PKCS#8 or RSA.
+pub fn config(cert_path: &str, key_path: &str) -> Result<ServerConfig, String> {
+    let chain = read_certs(cert_path)?;
+    let key = read_key(key_path)?;
+
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(chain, key)
+        .map_err(|err| format!("Can not use {} with {}: {}", cert_path, key_path, err))?;
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+
+    Ok(config)
+}
+
+fn read_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|err| format!("Can not read {}: {}", path, err))?);
+    match certs(&mut reader) {
+        Ok(chain) if !chain.is_empty() => Ok(chain),
+        _ => Err(format!("{} has no PEM encoded certificate", path)),
+    }
+}
+
+fn read_key(path: &str) -> Result<PrivateKey, String> {
+    let open = || File::open(path).map(BufReader::new).map_err(|err| format!("Can not read {}: {}", path, err));
+
+    let mut keys = pkcs8_private_keys(&mut open()?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut open()?).unwrap_or_default();
+    }
+    keys.into_iter().next().ok_or_else(|| format!("{} has no PEM encoded PKCS#8 or RSA private key", path))
+}
@@ -0,0 +1,104 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! UDP listener: a datagram with an IP address, optionally followed by a space and a language,
+//! gets back a single datagram with the response, for fire-and-forget enrichment.
+//!
+//! Source addresses of datagrams can be spoofed, and a response is much larger than its request:
+//! answered to anyone, the listener would amplify floods towards the spoofed addresses. Only the
+//! peers in the networks of GEOIP_RS_UDP_ALLOW, the loopback and private ones by default, are
+//! answered: the datagrams of the others are dropped before any lookup.
+
+use std::env;
+use std::io;
+use std::net::UdpSocket;
+use std::thread;
+
+use ipnetwork::IpNetwork;
+
+use crate::provider::GeoProvider;
+use crate::resolver::unmapped;
+use crate::server::Db;
+
+/// Encoding of the response datagrams.
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Json,
+    MessagePack,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Result<Format, String> {
+        match format {
+            "json" => Ok(Format::Json),
+            "msgpack" | "messagepack" => Ok(Format::MessagePack),
+            _ => Err(format!("Unknown UDP format {}, expected json or msgpack", format)),
+        }
+    }
+}
+
+const DEFAULT_ALLOWED: &str = "127.0.0.0/8,10.0.0.0/8,172.16.0.0/12,192.168.0.0/16,::1/128,fc00::/7";
+
+/// The networks of the peers answered, comma separated in GEOIP_RS_UDP_ALLOW, e.g. `10.0.0.0/8,2001:db8::/32`.
+pub fn allowed_peers() -> Result<Vec<IpNetwork>, String> {
+    env::var("GEOIP_RS_UDP_ALLOW")
+        .unwrap_or_else(|_| String::from(DEFAULT_ALLOWED))
+        .split(',')
+        .map(str::trim)
+        .filter(|network| !network.is_empty())
+        .map(|network| {
+            network
+                .parse::<IpNetwork>()
+                .map_err(|err| format!("Invalid network {} in GEOIP_RS_UDP_ALLOW: {}", network, err))
+        })
+        .collect()
+}
+
+/// Binds `addr` and answers the datagrams of the peers in `allowed` on a dedicated thread.
+pub fn spawn<P: GeoProvider>(addr: &str, format: Format, allowed: Vec<IpNetwork>, db: Db<P>) -> io::Result<()> {
+    let socket = UdpSocket::bind(addr)?;
+
+    thread::spawn(move || {
+        let mut buf = [0; 512];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) => {
+                    eprintln!("UDP receive error: {}", err);
+                    continue;
+                }
+            };
+            if !allowed.iter().any(|network| network.contains(unmapped(peer.ip()))) {
+                continue;
+            }
+
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let mut parts = request.split_whitespace();
+            let ip_address = parts.next().unwrap_or("");
+            let language = parts.next().unwrap_or("en");
+
+            let geoip = db.lookup(ip_address, language);
+            let response = match format {
+                Format::Json => serde_json::to_vec(&geoip).unwrap(),
+                Format::MessagePack => rmp_serde::to_vec_named(&geoip).unwrap(),
+            };
+
+            if let Err(err) = socket.send_to(&response, peer) {
+                eprintln!("UDP send error to {}: {}", peer, err);
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,453 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Downloads MaxMind databases: fetches the tarball of an edition, verifies its sha256
+//! checksum, extracts the `.mmdb` file and atomically moves it in place. Failed downloads are
+//! retried with exponential backoff, resuming the partially downloaded tarball.
+//!
+//! The server can keep its own database up to date with [Updater]: it's downloaded at startup
+//! when the file is missing, then checked for updates at intervals, and reloaded when MaxMind
+//! has a newer build.
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use flate2::read::GzDecoder;
+use maxminddb::Reader;
+use sha2::{Digest, Sha256};
+
+use crate::provider::{GeoProvider, MaxMindProvider};
+use crate::reload::Reloads;
+use crate::settings::env_flag;
+
+const DEFAULT_BASE_URL: &str = "https://download.maxmind.com";
+const DEFAULT_EDITION: &str = "GeoLite2-City";
+const DEFAULT_RETRIES: u32 = 5;
+const DEFAULT_BACKOFF_MS: u64 = 1000;
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+const DEFAULT_UPDATE_INTERVAL: u64 = 86_400;
+
+#[derive(Clone, Debug)]
+pub struct DownloadConfig {
+    pub edition: String,
+    pub license_key: String,
+    pub account_id: Option<String>,
+    pub base_url: String,
+    pub backoff: Backoff,
+}
+
+impl DownloadConfig {
+    /// Creates a configuration for `license_key`, reading the other settings from
+    /// GEOIP_RS_MAXMIND_ACCOUNT_ID, GEOIP_RS_MAXMIND_EDITION, GEOIP_RS_MAXMIND_URL and the
+    /// [Backoff] ones.
+    pub fn new(license_key: String) -> DownloadConfig {
+        DownloadConfig {
+            edition: env::var("GEOIP_RS_MAXMIND_EDITION").unwrap_or_else(|_| String::from(DEFAULT_EDITION)),
+            license_key,
+            account_id: env::var("GEOIP_RS_MAXMIND_ACCOUNT_ID").ok(),
+            base_url: env::var("GEOIP_RS_MAXMIND_URL").unwrap_or_else(|_| String::from(DEFAULT_BASE_URL)),
+            backoff: Backoff::from_env().unwrap_or_else(|err| panic!("{}", err)),
+        }
+    }
+
+    fn url(&self, suffix: &str) -> String {
+        match self.account_id {
+            Some(_) => format!(
+                "{}/geoip/databases/{}/download?suffix={}",
+                self.base_url, self.edition, suffix
+            ),
+            None => format!(
+                "{}/app/geoip_download?edition_id={}&license_key={}&suffix={}",
+                self.base_url, self.edition, self.license_key, suffix
+            ),
+        }
+    }
+
+    /// Gets `suffix`, from byte `offset` on when it's not 0. Servers ignoring the range answer
+    /// with the whole file, with a 200 rather than a 206 status.
+    fn get(&self, suffix: &str, offset: u64) -> Result<ureq::Response, String> {
+        let mut request = ureq::get(&self.url(suffix));
+        request.timeout(Duration::from_secs(300));
+        if let Some(account_id) = &self.account_id {
+            request.auth(account_id, &self.license_key);
+        }
+        if offset > 0 {
+            request.set("Range", &format!("bytes={}-", offset));
+        }
+
+        let response = request.call();
+        if let Some(err) = response.synthetic_error() {
+            return Err(format!("Can not download {} {}: {}", self.edition, suffix, err));
+        }
+        // A partial download of another release can be past the end of the file
+        if offset > 0 && response.status() == 416 {
+            return self.get(suffix, 0);
+        }
+        if !response.ok() {
+            return Err(format!(
+                "Can not download {} {}: HTTP {}",
+                self.edition,
+                suffix,
+                response.status()
+            ));
+        }
+
+        Ok(response)
+    }
+}
+
+/// Downloads the configured edition into `out_dir`, returning the path of the installed
+/// `<edition>.mmdb` file. The existing file, if any, is replaced only once the new one has
+/// been verified and successfully opened. Failures are retried as configured by
+/// [DownloadConfig::backoff].
+pub fn download(config: &DownloadConfig, out_dir: &Path) -> Result<PathBuf, String> {
+    config.backoff.retry(|| download_once(config, out_dir))
+}
+
+fn download_once(config: &DownloadConfig, out_dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(out_dir).map_err(|err| format!("Can not create {}: {}", out_dir.display(), err))?;
+
+    let db_path = out_dir.join(format!("{}.mmdb", config.edition));
+    let tmp_db_path = out_dir.join(format!(".{}.mmdb.tmp", config.edition));
+    fetch(config, &tmp_db_path, None)?;
+    install(&tmp_db_path, &db_path)?;
+
+    Ok(db_path)
+}
+
+/// Downloads and verifies the tarball of the configured edition, extracting its database to
+/// `tmp_db_path`, and returns the checksum of the tarball. When it's `known`, the tarball is the
+/// one already downloaded and `None` is returned instead, without downloading it again.
+fn fetch(config: &DownloadConfig, tmp_db_path: &Path, known: Option<&str>) -> Result<Option<String>, String> {
+    let expected_checksum = config
+        .get("tar.gz.sha256", 0)?
+        .into_string()
+        .map_err(|err| format!("Can not read checksum: {}", err))?
+        .split_whitespace()
+        .next()
+        .map(str::to_lowercase)
+        .ok_or_else(|| String::from("Empty checksum"))?;
+    if known == Some(expected_checksum.as_str()) {
+        return Ok(None);
+    }
+
+    // What's left of a failed download is resumed
+    let tarball_path = tmp_db_path.with_file_name(format!(".{}.tar.gz.tmp", config.edition));
+    let offset = fs::metadata(&tarball_path).map(|metadata| metadata.len()).unwrap_or(0);
+    let response = config.get("tar.gz", offset)?;
+    let resumed = response.status() == 206;
+    let checksum = save(response.into_reader(), &tarball_path, resumed)
+        .map_err(|err| format!("Can not save {}: {}", tarball_path.display(), err))?;
+
+    if checksum != expected_checksum {
+        fs::remove_file(&tarball_path).ok();
+        return Err(format!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            config.edition, expected_checksum, checksum
+        ));
+    }
+
+    let extracted = extract_mmdb(&tarball_path, tmp_db_path);
+    fs::remove_file(&tarball_path).ok();
+    extracted?;
+
+    Ok(Some(checksum))
+}
+
+/// Checks `tmp_db_path` is a valid database and atomically renames it to `db_path`.
+pub fn install(tmp_db_path: &Path, db_path: &Path) -> Result<(), String> {
+    if let Err(err) = Reader::open_readfile(tmp_db_path) {
+        fs::remove_file(tmp_db_path).ok();
+        return Err(format!("Downloaded database is not valid: {}", err));
+    }
+
+    fs::rename(tmp_db_path, db_path).map_err(|err| format!("Can not install {}: {}", db_path.display(), err))
+}
+
+/// Saves `reader` to `path`, or appends it when resuming, returning the checksum of the file.
+fn save<R: Read>(mut reader: R, path: &Path, append: bool) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 64 * 1024];
+
+    let mut file = if append {
+        let mut file = File::open(path)?;
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        OpenOptions::new().append(true).open(path)?
+    } else {
+        File::create(path)?
+    };
+
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        file.write_all(&buf[..read])?;
+    }
+    file.sync_all()?;
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn extract_mmdb(tarball_path: &Path, out_path: &Path) -> Result<(), String> {
+    let tarball = File::open(tarball_path).map_err(|err| err.to_string())?;
+    let mut archive = tar::Archive::new(GzDecoder::new(tarball));
+
+    for entry in archive.entries().map_err(|err| format!("Can not read tarball: {}", err))? {
+        let mut entry = entry.map_err(|err| format!("Can not read tarball: {}", err))?;
+        let is_mmdb = entry
+            .path()
+            .map(|path| path.extension().map_or(false, |ext| ext == "mmdb"))
+            .unwrap_or(false);
+
+        if is_mmdb {
+            let mut out = File::create(out_path).map_err(|err| err.to_string())?;
+            io::copy(&mut entry, &mut out).map_err(|err| format!("Can not extract database: {}", err))?;
+            out.sync_all().map_err(|err| err.to_string())?;
+            return Ok(());
+        }
+    }
+
+    Err(String::from("No .mmdb file found in tarball"))
+}
+
+/// How failed downloads are retried: up to GEOIP_RS_DOWNLOAD_RETRIES times (5 by default),
+/// waiting GEOIP_RS_DOWNLOAD_BACKOFF_MS milliseconds (1000 by default) before the first retry,
+/// twice as long before each other one, up to 5 minutes. Waits are randomized between half and
+/// all of that, so that a fleet doesn't retry in lockstep.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub retries: u32,
+    pub initial: Duration,
+}
+
+impl Backoff {
+    pub fn from_env() -> Result<Backoff, String> {
+        let retries = match env::var("GEOIP_RS_DOWNLOAD_RETRIES") {
+            Ok(retries) => retries
+                .parse()
+                .map_err(|_| format!("GEOIP_RS_DOWNLOAD_RETRIES must be a number, got {}", retries))?,
+            Err(_) => DEFAULT_RETRIES,
+        };
+        let initial = match env::var("GEOIP_RS_DOWNLOAD_BACKOFF_MS") {
+            Ok(millis) => millis
+                .parse()
+                .map_err(|_| format!("GEOIP_RS_DOWNLOAD_BACKOFF_MS must be a number, got {}", millis))?,
+            Err(_) => DEFAULT_BACKOFF_MS,
+        };
+
+        Ok(Backoff {
+            retries,
+            initial: Duration::from_millis(initial),
+        })
+    }
+
+    /// Runs `attempt` until it succeeds or the retries are over, returning the last error.
+    pub fn retry<T, F: FnMut() -> Result<T, String>>(&self, mut attempt: F) -> Result<T, String> {
+        let mut failures = 0;
+        loop {
+            match attempt() {
+                Ok(value) => return Ok(value),
+                Err(err) if failures < self.retries => {
+                    let wait = self.wait(failures);
+                    eprintln!("{}, retrying in {:.1}s", err, wait.as_secs_f64());
+                    thread::sleep(wait);
+                    failures += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn wait(&self, failures: u32) -> Duration {
+        let wait = self.initial.checked_mul(1 << failures.min(16)).map_or(MAX_BACKOFF, |wait| wait.min(MAX_BACKOFF));
+        // Nanoseconds of the clock are random enough for a jitter
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.subsec_nanos()).unwrap_or(0);
+        wait / 2 + wait.mul_f64(f64::from(nanos) / 2e9)
+    }
+}
+
+/// Outcome of the downloads of a running server, for the health check and the metrics: after
+/// failed downloads, the previous database is still served.
+#[derive(Default)]
+pub struct Status {
+    state: Mutex<DownloadState>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadState {
+    /// Downloads failed since the last successful one, retries included.
+    pub consecutive_failures: u32,
+    pub last_error: Option<String>,
+    /// When a download last succeeded, in seconds since the epoch.
+    pub last_success: Option<u64>,
+}
+
+impl Status {
+    pub fn succeeded(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.last_error = None;
+        state.last_success = Some(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_secs()));
+    }
+
+    pub fn failed(&self, err: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        state.last_error = Some(err.to_string());
+    }
+
+    pub fn state(&self) -> DownloadState {
+        self.state.lock().unwrap().clone()
+    }
+}
+
+/// Scheduled downloads of the database served, enabled with GEOIP_RS_AUTO_UPDATE and the
+/// GEOIP_RS_MAXMIND_LICENSE_KEY of a [DownloadConfig]. Updates are checked every
+/// GEOIP_RS_UPDATE_INTERVAL seconds, a day by default: the checksum of the tarball is fetched
+/// first, and the tarball itself only when it changed.
+pub struct Updater {
+    config: DownloadConfig,
+    interval: Duration,
+    /// Checksum of the tarball last downloaded.
+    checksum: Option<String>,
+}
+
+impl Updater {
+    pub fn from_env() -> Result<Option<Updater>, String> {
+        if !env_flag("GEOIP_RS_AUTO_UPDATE") {
+            return Ok(None);
+        }
+        let license_key = env::var("GEOIP_RS_MAXMIND_LICENSE_KEY")
+            .map_err(|_| String::from("GEOIP_RS_AUTO_UPDATE needs the GEOIP_RS_MAXMIND_LICENSE_KEY env var"))?;
+        let interval = match env::var("GEOIP_RS_UPDATE_INTERVAL") {
+            Ok(interval) => interval
+                .parse::<u64>()
+                .ok()
+                .filter(|interval| *interval > 0)
+                .ok_or_else(|| format!("GEOIP_RS_UPDATE_INTERVAL must be a positive number, got {}", interval))?,
+            Err(_) => DEFAULT_UPDATE_INTERVAL,
+        };
+
+        Ok(Some(Updater {
+            config: DownloadConfig::new(license_key),
+            interval: Duration::from_secs(interval),
+            checksum: None,
+        }))
+    }
+
+    /// Downloads the database to `db_path` when there's no such file, before the server opens it.
+    pub fn download_missing(&mut self, db_path: &Path) -> Result<(), String> {
+        if db_path.exists() {
+            return Ok(());
+        }
+        if let Some(dir) = db_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|err| format!("Can not create {}: {}", dir.display(), err))?;
+        }
+
+        let tmp_db_path = tmp_path(db_path);
+        let config = &self.config;
+        self.checksum = config.backoff.retry(|| fetch(config, &tmp_db_path, None))?;
+        install(&tmp_db_path, db_path)?;
+        println!("Downloaded {} to {}", config.edition, db_path.display());
+
+        Ok(())
+    }
+
+    /// Checks for updates on a dedicated thread, for as long as the server runs, reporting the
+    /// outcome of the downloads to `status`.
+    pub fn spawn(mut self, provider: Arc<MaxMindProvider>, reloads: Arc<Reloads>, status: Arc<Status>) {
+        let mut checksum = self.checksum.take();
+        thread::spawn(move || loop {
+            let updated = self.config.backoff.retry(|| {
+                let updated = self.update(&provider, &reloads, &mut checksum);
+                match &updated {
+                    Ok(_) => status.succeeded(),
+                    Err(err) => status.failed(err),
+                }
+                updated
+            });
+            match updated {
+                Ok(Some(build_epoch)) => {
+                    println!("Updated {} to the database built at {}", self.config.edition, build_epoch)
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("{}, still serving the current database", err),
+            }
+            thread::sleep(self.interval);
+        });
+    }
+
+    /// Installs and reloads the latest database of the edition when it's newer than the one
+    /// loaded, returning its build time.
+    fn update(
+        &self,
+        provider: &MaxMindProvider,
+        reloads: &Reloads,
+        checksum: &mut Option<String>,
+    ) -> Result<Option<u64>, String> {
+        let db_path = provider.path();
+        let tmp_db_path = tmp_path(db_path);
+        let fetched = match fetch(&self.config, &tmp_db_path, checksum.as_deref())? {
+            Some(fetched) => fetched,
+            None => return Ok(None),
+        };
+
+        let metadata = match Reader::open_readfile(&tmp_db_path) {
+            Ok(reader) => reader.metadata,
+            Err(err) => {
+                fs::remove_file(&tmp_db_path).ok();
+                return Err(format!("Downloaded database is not valid: {}", err));
+            }
+        };
+        let loaded = provider.metadata();
+        if provider.is_loaded() && metadata.database_type != loaded.database_type {
+            fs::remove_file(&tmp_db_path).ok();
+            return Err(format!(
+                "Not installing {}: it's a {} database, the loaded one is a {}",
+                self.config.edition, metadata.database_type, loaded.database_type
+            ));
+        }
+        if metadata.build_epoch <= loaded.build_epoch {
+            fs::remove_file(&tmp_db_path).ok();
+            *checksum = Some(fetched);
+            return Ok(None);
+        }
+
+        install(&tmp_db_path, db_path)?;
+        let diff = reloads.reload(provider)?;
+        *checksum = Some(fetched);
+
+        Ok(Some(diff.new_build_epoch))
+    }
+}
+
+/// `.<file>.tmp` next to the database file, so that it can be renamed in place.
+pub fn tmp_path(db_path: &Path) -> PathBuf {
+    let file_name = db_path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    db_path.with_file_name(format!(".{}.tmp", file_name))
+}
@@ -0,0 +1,97 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! `geoip-rs verify`: checks a database against a file of expected results, e.g.
+//!
+//! ```yaml
+//! 81.2.69.142:
+//!   country: GB
+//!   city: London
+//! 2001:218::1:
+//!   country: JP
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+
+use crate::cli::{self, Args};
+use crate::settings::Settings;
+
+pub const USAGE: &str = "Usage: geoip-rs verify [--db file.mmdb] --cases cases.yaml";
+
+#[derive(Deserialize, Debug)]
+struct Expectation {
+    country: Option<String>,
+    region: Option<String>,
+    city: Option<String>,
+}
+
+pub fn run(args: &Args) -> i32 {
+    let cases_path = match args.get("cases") {
+        Some(cases_path) => cases_path,
+        None => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    let cases: BTreeMap<String, Expectation> = match fs::read_to_string(cases_path)
+        .map_err(|err| err.to_string())
+        .and_then(|cases| serde_yaml::from_str(&cases).map_err(|err| err.to_string()))
+    {
+        Ok(cases) => cases,
+        Err(err) => {
+            eprintln!("Can not read {}: {}", cases_path, err);
+            return 1;
+        }
+    };
+
+    let db = match cli::open_db(args) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 1;
+        }
+    };
+
+    let settings = Settings::from_env();
+    let mut mismatches = 0;
+    for (ip_address, expectation) in &cases {
+        let geoip = crate::resolver::resolve(&db, &settings.resolver, ip_address, "en");
+
+        let checks = [
+            ("country", "countryCode", &expectation.country),
+            ("region", "regionCode", &expectation.region),
+            ("city", "cityName", &expectation.city),
+        ];
+
+        for (name, field, expected) in checks.iter() {
+            if let Some(expected) = expected {
+                let actual = geoip[*field].as_str().unwrap_or("");
+                if actual != expected.as_str() {
+                    mismatches += 1;
+                    println!("FAIL {}: expected {} {:?}, got {:?}", ip_address, name, expected, actual);
+                }
+            }
+        }
+    }
+
+    println!("{} cases, {} mismatches", cases.len(), mismatches);
+
+    if mismatches > 0 {
+        1
+    } else {
+        0
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! WebAssembly bindings of the resolver, for edge runtimes and browsers.
+
+use maxminddb::Reader;
+use wasm_bindgen::prelude::*;
+
+use crate::resolver;
+
+/// The `GeoIp` class, over the bytes of a database fetched by the host.
+#[wasm_bindgen(js_name = GeoIp)]
+pub struct WasmGeoIp {
+    reader: Reader<Vec<u8>>,
+}
+
+#[wasm_bindgen(js_class = GeoIp)]
+impl WasmGeoIp {
+    /// `new GeoIp(database)`, with the database as `Uint8Array`, throwing when it's invalid.
+    #[wasm_bindgen(constructor)]
+    pub fn new(database: Vec<u8>) -> Result<WasmGeoIp, JsValue> {
+        let reader = Reader::from_source(database)
+            .map_err(|err| JsValue::from_str(&format!("Invalid database: {}", err)))?;
+
+        Ok(WasmGeoIp { reader })
+    }
+
+    /// `geoip.lookup(ip, lang)`, the JSON body `/{ip}` would return.
+    pub fn lookup(&self, ip: &str, lang: &str) -> String {
+        resolver::resolve(&self.reader, &resolver::Options::default(), ip.trim(), lang).to_string()
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright 2019 Federico Fissore
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//   http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The MaxMind GeoIP2 Precision web services as the upstream of the lookups, enabled with
+//! GEOIP_RS_WEB_SERVICE set to the service, `country`, `city` or `insights`. Answers, misses
+//! included, are cached, and queries are rate limited, since they're paid for.
+
+use std::env;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::fallback::{self, Answers, Upstream};
+use crate::rate_limit::TokenBucket;
+
+const DEFAULT_URL: &str = "https://geoip.maxmind.com";
+const DEFAULT_QUERIES_PER_MINUTE: f64 = 60.0;
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+const CACHE_TTL: Duration = Duration::from_secs(24 * 3600);
+const TIMEOUT: Duration = Duration::from_secs(2);
+
+pub struct WebService {
+    url: String,
+    account_id: String,
+    license_key: String,
+    /// Top level fields of the records, e.g. `city`, without which the web service is queried.
+    required: Vec<String>,
+    queries: Mutex<TokenBucket>,
+    cache: Answers,
+}
+
+impl WebService {
+    /// The web service set in the env, queried with the GEOIP_RS_MAXMIND_ACCOUNT_ID and
+    /// GEOIP_RS_MAXMIND_LICENSE_KEY credentials.
+    pub fn from_env() -> Result<Option<WebService>, String> {
+        let service = match env::var("GEOIP_RS_WEB_SERVICE") {
+            Ok(service) => service,
+            Err(_) => return Ok(None),
+        };
+        if !matches!(service.as_str(), "country" | "city" | "insights") {
+            return Err(format!("GEOIP_RS_WEB_SERVICE must be country, city or insights, got {}", service));
+        }
+        let credential = |name: &str| {
+            env::var(name).map_err(|_| format!("GEOIP_RS_WEB_SERVICE needs the {} env var", name))
+        };
+        let queries_per_minute = match env::var("GEOIP_RS_WEB_SERVICE_RATE") {
+            Ok(rate) => rate
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| *rate > 0.0)
+                .ok_or_else(|| format!("GEOIP_RS_WEB_SERVICE_RATE must be a positive number, got {}", rate))?,
+            Err(_) => DEFAULT_QUERIES_PER_MINUTE,
+        };
+        let cache_size = match env::var("GEOIP_RS_WEB_SERVICE_CACHE_SIZE") {
+            Ok(size) => size
+                .parse()
+                .map_err(|_| format!("GEOIP_RS_WEB_SERVICE_CACHE_SIZE must be a number, got {}", size))?,
+            Err(_) => DEFAULT_CACHE_SIZE,
+        };
+        let base_url = env::var("GEOIP_RS_WEB_SERVICE_URL").unwrap_or_else(|_| String::from(DEFAULT_URL));
+
+        Ok(Some(WebService {
+            url: format!("{}/geoip/v2.1/{}", base_url.trim_end_matches('/'), service),
+            account_id: credential("GEOIP_RS_MAXMIND_ACCOUNT_ID")?,
+            license_key: credential("GEOIP_RS_MAXMIND_LICENSE_KEY")?,
+            required: fallback::required_fields("GEOIP_RS_WEB_SERVICE_FIELDS"),
+            queries: Mutex::new(TokenBucket::new(queries_per_minute, queries_per_minute / 60.0)),
+            cache: Answers::new(cache_size, CACHE_TTL),
+        }))
+    }
+}
+
+impl Upstream for WebService {
+    fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, String> {
+        if let Some(record) = self.cache.get(ip) {
+            return Ok(record);
+        }
+        if !self.queries.lock().unwrap().take() {
+            return Ok(None);
+        }
+
+        let mut request = ureq::get(&format!("{}/{}", self.url, ip));
+        request.timeout(TIMEOUT);
+        request.auth(&self.account_id, &self.license_key);
+        let response = request.call();
+        let record = match response.status() {
+            200 => Some(
+                response
+                    .into_json()
+                    .map_err(|err| format!("Can not parse the web service answer for {}: {}", ip, err))?,
+            ),
+            // IP_ADDRESS_NOT_FOUND and IP_ADDRESS_RESERVED
+            404 => None,
+            status => {
+                return Err(match response.synthetic_error() {
+                    Some(err) => format!("Can not query the web service for {}: {}", ip, err),
+                    None => format!("Can not query the web service for {}: HTTP {}", ip, status),
+                })
+            }
+        };
+
+        self.cache.insert(ip, record.clone());
+        Ok(record)
+    }
+
+    fn required(&self) -> &[String] {
+        &self.required
+    }
+}